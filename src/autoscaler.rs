@@ -0,0 +1,442 @@
+use crate::objects::{Scheduler, World};
+use crate::systems::{Server, ServerLoad, System, WarmUp};
+use crate::traits::{Emmitter, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{tostring, Counter, DelayQueue, Gauge};
+
+use rand_distr::Poisson;
+
+use std::collections::HashMap;
+
+pub enum ScalingMetric {
+    /// Share of the interval servers spent serving requests.
+    Utilization,
+    /// Average number of requests queued per server.
+    QueueLength,
+    /// Average ns from arrival to completion of requests completed in the interval.
+    Latency,
+}
+
+pub struct AutoscalerConfig {
+    pub metric: ScalingMetric,
+    /// Value of the metric the autoscaler tries to keep.
+    pub target: f64,
+    /// No scaling while the metric is within this ratio of the target.
+    pub tolerance: f64,
+    pub interval_ns: i64,
+    pub min_instances: usize,
+    pub max_instances: usize,
+    /// Time between deciding to add a server and it taking requests.
+    pub provisioning_ns: i64,
+    /// No scaling decisions for this long after the last one.
+    pub cooldown_ns: i64,
+}
+
+impl Default for AutoscalerConfig {
+    fn default() -> Self {
+        AutoscalerConfig {
+            metric: ScalingMetric::Utilization,
+            target: 0.7,
+            tolerance: 0.1,
+            interval_ns: 15_000_000_000,
+            min_instances: 1,
+            max_instances: 10,
+            provisioning_ns: 30_000_000_000,
+            cooldown_ns: 60_000_000_000,
+        }
+    }
+}
+
+/// Autoscaler adds and removes servers behind a load balancer.
+/// Every interval it looks at the metric over the servers in rotation and
+/// computes the desired count like the kubernetes hpa does:
+/// `ceil(instances * metric / target)`.
+/// New servers are added to the world and join the rotation after the
+/// provisioning delay. Removed servers leave the rotation straight away,
+/// finish their queue and are then removed from the world.
+pub struct Autoscaler {
+    config: AutoscalerConfig,
+    load_balancer: SystemRef,
+    distribution: Poisson<f32>,
+    warm_up: Option<WarmUp>,
+    sink: SystemRef,
+    servers: Vec<SystemRef>,
+    provisioning: DelayQueue<SystemRef>,
+    // out of rotation, finishing their queue
+    retiring: Vec<SystemRef>,
+    last_loads: HashMap<SystemRef, ServerLoad>,
+    next_evaluation_t: i64,
+    last_scaling_t: Option<i64>,
+    created: usize,
+    scale_ups: Counter,
+    scale_downs: Counter,
+    instances: Gauge,
+    pending: Gauge,
+    metric: Gauge,
+    name: String,
+    sr: Option<SystemRef>,
+}
+
+impl Autoscaler {
+    /// `servers` are already behind the load balancer, new servers are
+    /// created with the distribution and the sink.
+    pub fn new(
+        config: AutoscalerConfig,
+        load_balancer: SystemRef,
+        servers: Vec<SystemRef>,
+        distribution: Poisson<f32>,
+        sink: SystemRef,
+    ) -> Self {
+        assert!(config.min_instances > 0);
+        assert!(config.min_instances <= config.max_instances);
+        Autoscaler {
+            config,
+            load_balancer,
+            distribution,
+            warm_up: None,
+            sink,
+            servers,
+            provisioning: DelayQueue::new(),
+            retiring: Vec::new(),
+            last_loads: HashMap::new(),
+            next_evaluation_t: 0,
+            last_scaling_t: None,
+            created: 0,
+            scale_ups: Counter::new(),
+            scale_downs: Counter::new(),
+            instances: Gauge::new(),
+            pending: Gauge::new(),
+            metric: Gauge::new(),
+            name: String::new(),
+            sr: None,
+        }
+    }
+
+    /// New servers warm up from joining the rotation.
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self
+    }
+
+    pub fn servers(&self) -> &[SystemRef] {
+        &self.servers
+    }
+
+    fn server_load(world: &mut World, server: SystemRef, t: i64) -> ServerLoad {
+        world.with_system(server, |system, _world| match system {
+            System::Server(server) => server.load(t),
+            _ => panic!("autoscaler can only scale servers"),
+        })
+    }
+
+    fn observe(&mut self, world: &mut World, t: i64) -> f64 {
+        let mut delta = ServerLoad::default();
+        let mut loads = HashMap::new();
+        for server in &self.servers {
+            let load = Self::server_load(world, *server, t);
+            let last = self.last_loads.get(server).cloned().unwrap_or_default();
+            delta.busy_ns += load.busy_ns - last.busy_ns;
+            delta.completed += load.completed - last.completed;
+            delta.latency_ns += load.latency_ns - last.latency_ns;
+            delta.queue += load.queue;
+            loads.insert(*server, load);
+        }
+        self.last_loads = loads;
+        let n = self.servers.len() as f64;
+        match self.config.metric {
+            ScalingMetric::Utilization => {
+                delta.busy_ns as f64 / (self.config.interval_ns as f64 * n)
+            }
+            ScalingMetric::QueueLength => delta.queue as f64 / n,
+            ScalingMetric::Latency if delta.completed == 0 => 0.0,
+            ScalingMetric::Latency => delta.latency_ns as f64 / delta.completed as f64,
+        }
+    }
+
+    fn evaluate(&mut self, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let value = self.observe(world, cur_t);
+        self.metric.set(value);
+        if self
+            .last_scaling_t
+            .is_some_and(|t| t + self.config.cooldown_ns > cur_t)
+        {
+            return;
+        }
+        let ratio = value / self.config.target;
+        if (ratio - 1.0).abs() <= self.config.tolerance {
+            return;
+        }
+        let current = self.servers.len() + self.provisioning.len();
+        let desired = ((self.servers.len() as f64 * ratio).ceil() as usize)
+            .clamp(self.config.min_instances, self.config.max_instances);
+        if desired > current {
+            for _ in current..desired {
+                self.provision(world, scheduler);
+            }
+            self.scale_ups.inc();
+        } else if desired < self.servers.len() {
+            let before = self.servers.len();
+            for _ in desired..before {
+                if !self.retire(world) {
+                    break;
+                }
+            }
+            if self.servers.len() == before {
+                return;
+            }
+            self.scale_downs.inc();
+        } else {
+            return;
+        }
+        scheduler.annotate(
+            &self.name,
+            format!("scaling from {} to {} instances", current, desired),
+        );
+        self.last_scaling_t = Some(cur_t);
+    }
+
+    fn provision(&mut self, world: &mut World, scheduler: &mut Scheduler) {
+        self.created += 1;
+        let mut server = Server::new(self.distribution, self.sink);
+        if let Some(warm_up) = self.warm_up {
+            server = server.with_warm_up(warm_up);
+        }
+        let server_ref = world.add(
+            System::Server(server),
+            format!("{}_server{}", self.name, self.created),
+        );
+        let ready_t = scheduler.get_cur_t() + self.config.provisioning_ns;
+        self.provisioning.push(ready_t, server_ref);
+        scheduler.schedule_at(ready_t, self.getref().unwrap());
+    }
+
+    /// Takes the last server out of the rotation, returns false when the
+    /// load balancer keeps it, e.g. as its last sink.
+    fn retire(&mut self, world: &mut World) -> bool {
+        let server = *self.servers.last().unwrap();
+        let removed = world.with_system(self.load_balancer, |system, _world| match system {
+            System::LoadBalancer(lb) => lb.remove_sink(server),
+            _ => panic!("autoscaler needs a load balancer"),
+        });
+        if removed {
+            self.servers.pop();
+            self.last_loads.remove(&server);
+            self.retiring.push(server);
+        }
+        removed
+    }
+
+    fn report(&mut self) {
+        self.instances.set(self.servers.len() as f64);
+        self.pending.set(self.provisioning.len() as f64);
+    }
+}
+
+impl Emmitter for Autoscaler {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        let cur_t = scheduler.get_cur_t();
+        while let Some(server) = self.provisioning.pop_due(cur_t) {
+            world.with_system(self.load_balancer, |system, _world| match system {
+                System::LoadBalancer(lb) => lb.add_sink(server),
+                _ => panic!("autoscaler needs a load balancer"),
+            });
+            world.with_system(server, |system, _world| match system {
+                System::Server(server) => server.start(cur_t),
+                _ => unreachable!(),
+            });
+            self.last_loads
+                .insert(server, Self::server_load(world, server, cur_t));
+            self.servers.push(server);
+        }
+        self.retiring.retain(|server| {
+            let drained = world.queue_size_of(*server) == 0;
+            if drained {
+                world.remove(*server);
+            }
+            !drained
+        });
+        let evaluated = cur_t >= self.next_evaluation_t;
+        if evaluated {
+            self.evaluate(world, scheduler);
+            self.next_evaluation_t = cur_t + self.config.interval_ns;
+        }
+        self.report();
+        if evaluated {
+            Some(self.next_evaluation_t)
+        } else {
+            None
+        }
+    }
+}
+
+impl StatEmitter for Autoscaler {
+    fn stats(&self) -> String {
+        format!(
+            "instances {} provisioning {} scale ups {} scale downs {} metric {}",
+            tostring(self.servers.len()),
+            tostring(self.provisioning.len()),
+            self.scale_ups.stats(),
+            self.scale_downs.stats(),
+            self.metric.stats()
+        )
+    }
+}
+
+impl WorldMember for Autoscaler {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.scale_ups.name = Some(name.clone() + "_scale_ups");
+        self.scale_downs.name = Some(name.clone() + "_scale_downs");
+        self.instances.name = Some(name.clone() + "_instances");
+        self.pending.name = Some(name.clone() + "_provisioning");
+        self.metric.name = Some(name.clone() + "_metric");
+        self.name = name;
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::LoadBalancer;
+    use crate::testing::{end_sink, run, send_at};
+
+    fn config() -> AutoscalerConfig {
+        AutoscalerConfig {
+            interval_ns: 1_000_000,
+            min_instances: 1,
+            max_instances: 4,
+            provisioning_ns: 0,
+            cooldown_ns: 0,
+            ..AutoscalerConfig::default()
+        }
+    }
+
+    /// Idle servers, `behind` of them behind the load balancer, scaled down
+    /// for 10ms. Returns the servers and the autoscaler.
+    fn scale_down(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        servers: usize,
+        behind: usize,
+    ) -> (Vec<SystemRef>, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let servers: Vec<SystemRef> = (0..servers)
+            .map(|i| {
+                let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+                world.add(System::Server(server), format!("server{}", i))
+            })
+            .collect();
+        let lb = LoadBalancer::new(servers[..behind].to_vec());
+        let lb = world.add(System::LoadBalancer(lb), "lb".to_string());
+        let distribution = Poisson::new(1_000.0).unwrap();
+        let autoscaler = Autoscaler::new(config(), lb, servers.clone(), distribution, end_sink);
+        let autoscaler = world.add(System::Autoscaler(autoscaler), "autoscaler".to_string());
+        scheduler.schedule(world, autoscaler);
+        run(world, scheduler, 10_000_000);
+        (servers, autoscaler)
+    }
+
+    fn with_autoscaler<R, F: FnOnce(&Autoscaler) -> R>(
+        world: &mut World,
+        autoscaler: SystemRef,
+        f: F,
+    ) -> R {
+        world.with_system(autoscaler, |system, _world| match system {
+            System::Autoscaler(autoscaler) => f(autoscaler),
+            _ => panic!("not an autoscaler"),
+        })
+    }
+
+    #[test]
+    fn new_servers_warm_up() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let lb = LoadBalancer::new(vec![server]);
+        let lb = world.add(System::LoadBalancer(lb), "lb".to_string());
+        let distribution = Poisson::new(1_000.0).unwrap();
+        let warm_up = WarmUp::Time {
+            factor: 3.0,
+            duration_ns: 1_000_000_000,
+        };
+        let autoscaler = Autoscaler::new(config(), lb, vec![server], distribution, end_sink)
+            .with_warm_up(warm_up);
+        let autoscaler = world.add(System::Autoscaler(autoscaler), "autoscaler".to_string());
+        scheduler.schedule(&mut world, autoscaler);
+        // the server is busy all the time
+        for i in 0..5_000 {
+            send_at(&mut scheduler, i * 1_000 + 1, lb, 0, None);
+        }
+        run(&mut world, &mut scheduler, 5_000_000);
+        let servers = with_autoscaler(&mut world, autoscaler, |autoscaler| {
+            autoscaler.servers().to_vec()
+        });
+        assert!(servers.len() > 1);
+        for (i, server) in servers.into_iter().enumerate() {
+            let factor = world.with_system(server, |system, _world| match system {
+                System::Server(server) => server.warm_up_factor(5_000_000),
+                _ => panic!("not a server"),
+            });
+            // only new servers warm up
+            assert_eq!(factor > 2.9, i > 0, "server {} factor {}", i, factor);
+        }
+    }
+
+    #[test]
+    fn idle_servers_are_retired_and_removed() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (servers, autoscaler) = scale_down(&mut world, &mut scheduler, 3, 3);
+        with_autoscaler(&mut world, autoscaler, |autoscaler| {
+            assert_eq!(autoscaler.servers(), &servers[..1]);
+            assert_eq!(autoscaler.scale_downs.value(), 1);
+        });
+        assert!(world.contains(servers[0]));
+        assert!(!world.contains(servers[1]));
+        assert!(!world.contains(servers[2]));
+    }
+
+    #[test]
+    fn keeps_servers_the_load_balancer_does_not_release() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        // the second server is not behind the load balancer
+        let (servers, autoscaler) = scale_down(&mut world, &mut scheduler, 2, 1);
+        with_autoscaler(&mut world, autoscaler, |autoscaler| {
+            assert_eq!(autoscaler.servers(), &servers[..]);
+            assert_eq!(autoscaler.scale_downs.value(), 0);
+        });
+        assert!(world.contains(servers[1]));
+    }
+
+    #[test]
+    fn requests_in_service_count_as_busy() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let server = Server::new(Poisson::new(5_000_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let lb = LoadBalancer::new(vec![server]);
+        let lb = world.add(System::LoadBalancer(lb), "lb".to_string());
+        let config = AutoscalerConfig {
+            max_instances: 1,
+            ..config()
+        };
+        let distribution = Poisson::new(1_000.0).unwrap();
+        let autoscaler = Autoscaler::new(config, lb, vec![server], distribution, end_sink);
+        let autoscaler = world.add(System::Autoscaler(autoscaler), "autoscaler".to_string());
+        scheduler.schedule(&mut world, autoscaler);
+        // a single request in service over the intervals ending at 1ms and 2ms
+        send_at(&mut scheduler, 0, server, 0, None);
+        run(&mut world, &mut scheduler, 2_500_000);
+        let utilization =
+            with_autoscaler(&mut world, autoscaler, |autoscaler| autoscaler.metric.get());
+        assert_eq!(utilization, 1.0);
+    }
+}
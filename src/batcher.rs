@@ -0,0 +1,193 @@
+use crate::objects::{Request, Scheduler, World};
+use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, Histogram};
+
+use std::collections::HashMap;
+
+/// Batcher collects requests until there are `max_batch` of them or the oldest
+/// waited `max_wait_ns`, then sends them downstream as one batch request.
+/// When the batch comes back its requests are released to the sink with the
+/// status of the batch.
+pub struct Batcher {
+    max_batch: usize,
+    max_wait_ns: i64,
+    downstream: SystemRef,
+    sink: SystemRef,
+    // arrival time and request
+    buffer: Vec<(i64, Request)>,
+    in_flight: HashMap<u64, Vec<Request>>,
+    batches: Counter,
+    batch_size: Histogram,
+    added_latency: Histogram,
+    sr: Option<SystemRef>,
+}
+
+impl Batcher {
+    pub fn new(max_batch: usize, max_wait_ns: i64, downstream: SystemRef, sink: SystemRef) -> Self {
+        assert!(max_batch > 0);
+        Batcher {
+            max_batch,
+            max_wait_ns,
+            downstream,
+            sink,
+            buffer: Vec::new(),
+            in_flight: HashMap::new(),
+            batches: Counter::new(),
+            batch_size: Histogram::new(),
+            added_latency: Histogram::new(),
+            sr: None,
+        }
+    }
+
+    fn flush(&mut self, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let mut batch = scheduler.new_request();
+        batch.batch_size = self.buffer.len();
+        batch.size = self.buffer.iter().map(|(_, request)| request.size).sum();
+        batch.reply_to.push(self.getref().unwrap());
+        batch.location = world.location(self.getref().unwrap());
+        let requests = self
+            .buffer
+            .drain(..)
+            .map(|(arrival_t, request)| {
+                self.added_latency.update(cur_t - arrival_t);
+                request
+            })
+            .collect();
+        self.batches.inc();
+        self.batch_size.update(batch.batch_size as i64);
+        self.in_flight.insert(batch.id, requests);
+        world.with_system(self.downstream, |system, world| {
+            system.next(batch, world, scheduler)
+        });
+    }
+}
+
+impl Sink for Batcher {
+    fn next(&mut self, request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        if let Some(requests) = self.in_flight.remove(&request.id) {
+            // the batch is done
+            for mut released in requests {
+                released.status = request.status;
+                let sink = released.respond_to(self.sink);
+                world.with_system(sink, |system, world| {
+                    system.next(released, world, scheduler)
+                });
+            }
+            return;
+        }
+        let cur_t = scheduler.get_cur_t();
+        if self.buffer.is_empty() {
+            scheduler.schedule_at(cur_t + self.max_wait_ns, self.getref().unwrap());
+        }
+        self.buffer.push((cur_t, request));
+        if self.buffer.len() >= self.max_batch {
+            self.flush(world, scheduler);
+        }
+    }
+}
+
+impl Emmitter for Batcher {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        let oldest = self.buffer.first().map(|(arrival_t, _)| *arrival_t);
+        if oldest.is_some_and(|t| t + self.max_wait_ns <= scheduler.get_cur_t()) {
+            self.flush(world, scheduler);
+        }
+        None
+    }
+}
+
+impl StatEmitter for Batcher {
+    fn stats(&self) -> String {
+        format!(
+            "batches {} batch size {} added latency {}",
+            self.batches.stats(),
+            self.batch_size.stats(),
+            self.added_latency.stats()
+        )
+    }
+}
+
+impl HasQueue for Batcher {
+    fn queue_size(&self) -> i64 {
+        let in_flight: usize = self.in_flight.values().map(|r| r.len()).sum();
+        (self.buffer.len() + in_flight) as i64
+    }
+}
+
+impl WorldMember for Batcher {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.batches.name = Some(name.clone() + "_batches");
+        self.batch_size.name = Some(name.clone() + "_batch_size");
+        self.added_latency.name = Some(name + "_added_latency");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::Fault;
+    use crate::objects::Status;
+    use crate::systems::{Server, System};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    use rand_distr::Poisson;
+
+    /// A batcher of 3 or 1ms in front of a server, the server gets the fault.
+    fn batcher(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        fault: Option<Fault>,
+    ) -> (SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        if let Some(fault) = fault {
+            world.with_system(server, |system, _world| match system {
+                System::Server(server) => server.inject(fault, scheduler),
+                _ => unreachable!(),
+            });
+        }
+        let batcher = Batcher::new(3, 1_000_000, server, end_sink);
+        let batcher = world.add(System::Batcher(batcher), "batcher".to_string());
+        (batcher, end_sink)
+    }
+
+    fn batches(world: &mut World, batcher: SystemRef) -> i64 {
+        world.with_system(batcher, |system, _world| match system {
+            System::Batcher(batcher) => batcher.batches.value(),
+            _ => unreachable!(),
+        })
+    }
+
+    #[test]
+    fn flushes_full_batches_and_after_the_wait() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (batcher, end_sink) = batcher(&mut world, &mut scheduler, None);
+        for i in 0..7 {
+            send_at(&mut scheduler, i, batcher, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(batches(&mut world, batcher), 3);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 7);
+    }
+
+    #[test]
+    fn releases_requests_with_the_batch_status() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let crash = Fault::Crash { fail: true };
+        let (batcher, end_sink) = batcher(&mut world, &mut scheduler, Some(crash));
+        for i in 0..3 {
+            send_at(&mut scheduler, i, batcher, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Error), 3);
+    }
+}
@@ -0,0 +1,875 @@
+use crate::objects::{Request, Scheduler, World};
+use crate::systems::System;
+use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{tostring, Counter, DelayQueue, Gauge, LateIds};
+
+use std::collections::{HashMap, VecDeque};
+
+/// How producers pick the partition of a message.
+pub enum Partitioning {
+    /// Messages with the same key go to the same partition, messages
+    /// without a key are spread round robin.
+    Key,
+    RoundRobin,
+}
+
+/// How a consumer group reassigns partitions when its members change.
+#[derive(Clone, Copy, Debug)]
+pub enum Rebalance {
+    /// No partition of the group is consumed for `pause_ns`.
+    Eager { pause_ns: i64 },
+    /// Only partitions moving to another consumer are not consumed for
+    /// `pause_ns`, the rest stay with their consumer.
+    Cooperative { pause_ns: i64 },
+}
+
+pub struct ConsumerConfig {
+    /// Most messages a consumer takes in one poll.
+    pub fetch_batch: usize,
+    /// How long a consumer waits before polling again after an empty poll.
+    pub poll_interval_ns: i64,
+    /// How often the broker checks the consumers are up.
+    pub heartbeat_ns: i64,
+    /// A consumer down for this long leaves the group.
+    pub session_timeout_ns: i64,
+    /// A fetched message without a response by then is lost, so a consumer
+    /// dropping it still polls again.
+    pub processing_timeout_ns: i64,
+    pub rebalance: Rebalance,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        ConsumerConfig {
+            fetch_batch: 500,
+            poll_interval_ns: 100_000_000,
+            heartbeat_ns: 3_000_000_000,
+            session_timeout_ns: 10_000_000_000,
+            processing_timeout_ns: 30_000_000_000,
+            rebalance: Rebalance::Eager {
+                pause_ns: 1_000_000_000,
+            },
+        }
+    }
+}
+
+struct Partition {
+    // offset of the first message in the log
+    base: u64,
+    // append time and message
+    log: VecDeque<(i64, Request)>,
+}
+
+impl Partition {
+    fn end(&self) -> u64 {
+        self.base + self.log.len() as u64
+    }
+}
+
+struct Consumer {
+    system: SystemRef,
+    partitions: Vec<usize>,
+    // partition to fetch from first in the next poll
+    next_partition: usize,
+    in_flight: usize,
+    member: bool,
+    // left the group because it was down, joins again once up
+    failed: bool,
+    down_since: Option<i64>,
+    // polls scheduled before the consumer last joined are ignored
+    generation: u64,
+}
+
+struct ConsumerGroup {
+    name: String,
+    config: ConsumerConfig,
+    sink: SystemRef,
+    consumers: Vec<Consumer>,
+    // next offset to consume per partition
+    offsets: Vec<u64>,
+    // partitions are not consumed before this time during a rebalance
+    paused_until: Vec<i64>,
+    consumed: Counter,
+    lost: Counter,
+    rebalances: Counter,
+    paused: Gauge,
+    total_lag: Gauge,
+    lag: Vec<Gauge>,
+    lag_ns: Vec<Gauge>,
+}
+
+impl ConsumerGroup {
+    /// Spreads the partitions evenly over the members, keeping as many of
+    /// them as possible with their current consumer.
+    /// Returns the partitions which moved.
+    fn assign(&mut self, partitions: usize) -> Vec<usize> {
+        let mut previous = vec![None; partitions];
+        for (c, consumer) in self.consumers.iter().enumerate() {
+            for p in &consumer.partitions {
+                previous[*p] = Some(c);
+            }
+        }
+        let members: Vec<usize> = (0..self.consumers.len())
+            .filter(|c| self.consumers[*c].member)
+            .collect();
+        let mut assigned = vec![Vec::new(); self.consumers.len()];
+        if !members.is_empty() {
+            let mut quota = vec![0; self.consumers.len()];
+            for (rank, c) in members.iter().enumerate() {
+                quota[*c] =
+                    partitions / members.len() + usize::from(rank < partitions % members.len());
+            }
+            let mut free = Vec::new();
+            for (p, owner) in previous.iter().enumerate() {
+                match owner {
+                    Some(c) if assigned[*c].len() < quota[*c] => assigned[*c].push(p),
+                    _ => free.push(p),
+                }
+            }
+            free.reverse();
+            for c in members {
+                while assigned[c].len() < quota[c] {
+                    assigned[c].push(free.pop().unwrap());
+                }
+                assigned[c].sort();
+            }
+        }
+        let mut moved = Vec::new();
+        for (c, partitions) in assigned.into_iter().enumerate() {
+            for p in &partitions {
+                if previous[*p] != Some(c) {
+                    moved.push(*p);
+                }
+            }
+            let consumer = &mut self.consumers[c];
+            consumer.partitions = partitions;
+            consumer.next_partition = 0;
+        }
+        moved.sort();
+        moved
+    }
+
+    fn find(&self, consumer: SystemRef) -> Option<usize> {
+        self.consumers.iter().position(|c| c.system == consumer)
+    }
+}
+
+enum Event {
+    // group, consumer, generation
+    Poll(usize, usize, u64),
+    Heartbeat(usize),
+    Rebalance(usize),
+    Resume(usize),
+    // id of a delivered message
+    Expire(u64),
+}
+
+struct Delivered {
+    group: usize,
+    consumer: usize,
+    // id the producer gave the message
+    id: u64,
+}
+
+/// Broker keeps messages in partitioned logs, like kafka does.
+/// Requests sent to the broker are appended to a partition, every consumer
+/// group reads all of them. A consumer polls the partitions assigned to it,
+/// takes up to a fetch batch of messages and polls again once it processed
+/// all of them, or after the poll interval when there was nothing to take.
+/// Consumers are servers, processed messages go to the sink of their group.
+/// When consumers join or leave, a consumer is down for the session timeout
+/// or at a rebalance event, the group reassigns its partitions and pauses
+/// consuming them. Messages fetched by a consumer which failed, or not
+/// processed within the processing timeout, are lost, offsets are committed
+/// on fetch.
+/// A producer waiting for a response gets the message back as an ack once it
+/// is appended, the group sink gets processed messages with their original id.
+/// Schedule the broker at the start of the simulation.
+pub struct Broker {
+    partitioning: Partitioning,
+    partitions: Vec<Partition>,
+    groups: Vec<ConsumerGroup>,
+    round_robin: usize,
+    events: DelayQueue<Event>,
+    // id of a delivered message to where it went
+    in_flight: HashMap<u64, Delivered>,
+    // lost messages whose response may still come
+    late: LateIds,
+    produced: Counter,
+    name: String,
+    sr: Option<SystemRef>,
+}
+
+impl Broker {
+    pub fn new(partitions: usize, partitioning: Partitioning) -> Self {
+        assert!(partitions > 0);
+        Broker {
+            partitioning,
+            partitions: (0..partitions)
+                .map(|_| Partition {
+                    base: 0,
+                    log: VecDeque::new(),
+                })
+                .collect(),
+            groups: Vec::new(),
+            round_robin: 0,
+            events: DelayQueue::new(),
+            in_flight: HashMap::new(),
+            late: LateIds::new(),
+            produced: Counter::new(),
+            name: String::new(),
+            sr: None,
+        }
+    }
+
+    /// Adds a group of consumers reading every partition from the start.
+    pub fn with_consumer_group(
+        mut self,
+        name: &str,
+        consumers: Vec<SystemRef>,
+        config: ConsumerConfig,
+        sink: SystemRef,
+    ) -> Self {
+        assert!(!consumers.is_empty());
+        let partitions = self.partitions.len();
+        let mut group = ConsumerGroup {
+            name: name.to_string(),
+            config,
+            sink,
+            consumers: consumers
+                .into_iter()
+                .map(|system| Consumer {
+                    system,
+                    partitions: Vec::new(),
+                    next_partition: 0,
+                    in_flight: 0,
+                    member: true,
+                    failed: false,
+                    down_since: None,
+                    generation: 0,
+                })
+                .collect(),
+            offsets: vec![0; partitions],
+            paused_until: vec![0; partitions],
+            consumed: Counter::new(),
+            lost: Counter::new(),
+            rebalances: Counter::new(),
+            paused: Gauge::new(),
+            total_lag: Gauge::new(),
+            lag: (0..partitions).map(|_| Gauge::new()).collect(),
+            lag_ns: (0..partitions).map(|_| Gauge::new()).collect(),
+        };
+        group.assign(partitions);
+        let index = self.groups.len();
+        for consumer in 0..group.consumers.len() {
+            self.events.push(0, Event::Poll(index, consumer, 0));
+        }
+        self.events.push(0, Event::Heartbeat(index));
+        self.groups.push(group);
+        self
+    }
+
+    /// Rebalances the group at simulation time `t`.
+    pub fn with_rebalance_at(mut self, t: i64, group: &str) -> Self {
+        let group = self.group(group);
+        self.events.push(t, Event::Rebalance(group));
+        self
+    }
+
+    /// Adds the consumer to the group, e.g. when scaling up, and rebalances.
+    pub fn add_consumer(&mut self, group: &str, consumer: SystemRef, scheduler: &mut Scheduler) {
+        let group = self.group(group);
+        let g = &mut self.groups[group];
+        let index = match g.find(consumer) {
+            Some(index) if g.consumers[index].member => return,
+            Some(index) => index,
+            None => {
+                g.consumers.push(Consumer {
+                    system: consumer,
+                    partitions: Vec::new(),
+                    next_partition: 0,
+                    in_flight: 0,
+                    member: false,
+                    failed: false,
+                    down_since: None,
+                    generation: 0,
+                });
+                g.consumers.len() - 1
+            }
+        };
+        self.join(group, index, scheduler);
+        self.rebalance(group, "consumer joined", scheduler);
+    }
+
+    /// Takes the consumer out of the group and rebalances, the consumer
+    /// finishes the messages it already fetched.
+    pub fn remove_consumer(&mut self, group: &str, consumer: SystemRef, scheduler: &mut Scheduler) {
+        let group = self.group(group);
+        let g = &mut self.groups[group];
+        if let Some(index) = g.find(consumer) {
+            if g.consumers[index].member {
+                g.consumers[index].member = false;
+                g.consumers[index].failed = false;
+                self.rebalance(group, "consumer left", scheduler);
+            }
+        }
+    }
+
+    fn group(&self, name: &str) -> usize {
+        self.groups
+            .iter()
+            .position(|g| g.name == name)
+            .unwrap_or_else(|| panic!("no consumer group {}", name))
+    }
+
+    fn join(&mut self, group: usize, consumer: usize, scheduler: &mut Scheduler) {
+        let c = &mut self.groups[group].consumers[consumer];
+        c.member = true;
+        c.failed = false;
+        c.down_since = None;
+        c.generation += 1;
+        if c.in_flight == 0 {
+            let t = scheduler.get_cur_t();
+            self.events
+                .push(t, Event::Poll(group, consumer, c.generation));
+            scheduler.schedule_at(t, self.getref().unwrap());
+        }
+    }
+
+    /// Reassigns the partitions of the group and pauses consuming them.
+    fn rebalance(&mut self, group: usize, reason: &str, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let partitions = self.partitions.len();
+        let g = &mut self.groups[group];
+        let moved = g.assign(partitions);
+        let (pause_ns, paused) = match g.config.rebalance {
+            Rebalance::Eager { pause_ns } => (pause_ns, (0..partitions).collect()),
+            Rebalance::Cooperative { pause_ns } => (pause_ns, moved),
+        };
+        for p in &paused {
+            g.paused_until[*p] = g.paused_until[*p].max(cur_t + pause_ns);
+        }
+        g.rebalances.inc();
+        scheduler.annotate(
+            &self.name,
+            format!(
+                "{} rebalance, {}, partitions {:?} paused",
+                g.name, reason, paused
+            ),
+        );
+        self.report_paused(group, cur_t);
+        self.events.push(cur_t + pause_ns, Event::Resume(group));
+        scheduler.schedule_at(cur_t + pause_ns, self.getref().unwrap());
+    }
+
+    fn report_paused(&mut self, group: usize, cur_t: i64) {
+        let g = &mut self.groups[group];
+        let paused = g.paused_until.iter().filter(|t| **t > cur_t).count();
+        g.paused.set(paused as f64);
+    }
+
+    /// Checks which consumers of the group are up, consumers down for the
+    /// session timeout leave the group, those which failed and are up again
+    /// join it.
+    fn heartbeat(&mut self, group: usize, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let mut reason = None;
+        for consumer in 0..self.groups[group].consumers.len() {
+            let g = &mut self.groups[group];
+            let c = &mut g.consumers[consumer];
+            let up = world.with_system(c.system, |system, _world| match system {
+                System::Server(server) => server.is_up(),
+                _ => true,
+            });
+            if up {
+                c.down_since = None;
+                if c.failed {
+                    self.join(group, consumer, scheduler);
+                    reason = Some("consumer joined");
+                }
+            } else if c.member {
+                let down_since = *c.down_since.get_or_insert(cur_t);
+                if cur_t - down_since >= g.config.session_timeout_ns {
+                    c.member = false;
+                    c.failed = true;
+                    c.in_flight = 0;
+                    let before = self.in_flight.len();
+                    self.in_flight
+                        .retain(|_, d| d.group != group || d.consumer != consumer);
+                    for _ in self.in_flight.len()..before {
+                        g.lost.inc();
+                    }
+                    reason = Some("consumer failed");
+                }
+            }
+        }
+        if let Some(reason) = reason {
+            self.rebalance(group, reason, scheduler);
+        }
+        let t = cur_t + self.groups[group].config.heartbeat_ns;
+        self.events.push(t, Event::Heartbeat(group));
+        scheduler.schedule_at(t, self.getref().unwrap());
+    }
+
+    /// Messages of the partition not yet consumed by the group.
+    pub fn lag(&self, group: usize, partition: usize) -> u64 {
+        self.partitions[partition].end() - self.groups[group].offsets[partition]
+    }
+
+    /// Time the oldest message of the partition not yet consumed by the group
+    /// has been waiting.
+    pub fn lag_ns(&self, group: usize, partition: usize, cur_t: i64) -> i64 {
+        let p = &self.partitions[partition];
+        let offset = self.groups[group].offsets[partition];
+        p.log
+            .get((offset - p.base) as usize)
+            .map_or(0, |(append_t, _)| cur_t - append_t)
+    }
+
+    fn partition(&mut self, request: &Request) -> usize {
+        let n = self.partitions.len();
+        match (&self.partitioning, request.key) {
+            (Partitioning::Key, Some(key)) => (key % n as u64) as usize,
+            _ => {
+                self.round_robin = (self.round_robin + 1) % n;
+                self.round_robin
+            }
+        }
+    }
+
+    fn report_lag(&mut self, partition: usize, cur_t: i64) {
+        for group in 0..self.groups.len() {
+            let lag = self.lag(group, partition);
+            let lag_ns = self.lag_ns(group, partition, cur_t);
+            let total: u64 = (0..self.partitions.len()).map(|p| self.lag(group, p)).sum();
+            let group = &mut self.groups[group];
+            group.lag[partition].set(lag as f64);
+            group.lag_ns[partition].set(lag_ns as f64);
+            group.total_lag.set(total as f64);
+        }
+    }
+
+    /// Drops messages every group consumed.
+    fn trim(&mut self, partition: usize) {
+        let consumed = self.groups.iter().map(|g| g.offsets[partition]).min();
+        if let Some(consumed) = consumed {
+            let p = &mut self.partitions[partition];
+            while p.base < consumed {
+                p.log.pop_front();
+                p.base += 1;
+            }
+        }
+    }
+
+    /// Polls again after the poll interval, or when a pause of one of the
+    /// consumer's partitions ends if that is sooner.
+    fn schedule_poll(&mut self, group: usize, consumer: usize, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let g = &self.groups[group];
+        let c = &g.consumers[consumer];
+        let resumes = c
+            .partitions
+            .iter()
+            .map(|p| g.paused_until[*p])
+            .filter(|t| *t > cur_t)
+            .min();
+        let t = (cur_t + g.config.poll_interval_ns).min(resumes.unwrap_or(i64::MAX));
+        self.events
+            .push(t, Event::Poll(group, consumer, c.generation));
+        scheduler.schedule_at(t, self.getref().unwrap());
+    }
+
+    fn poll(&mut self, group: usize, consumer: usize, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let g = &mut self.groups[group];
+        let c = &mut g.consumers[consumer];
+        let mut fetched = Vec::new();
+        let mut touched = Vec::new();
+        for i in 0..c.partitions.len() {
+            if fetched.len() >= g.config.fetch_batch {
+                break;
+            }
+            let partition = c.partitions[(c.next_partition + i) % c.partitions.len()];
+            if g.paused_until[partition] > cur_t {
+                continue;
+            }
+            let p = &self.partitions[partition];
+            let offset = &mut g.offsets[partition];
+            let start = (*offset - p.base) as usize;
+            let take = (p.log.len() - start).min(g.config.fetch_batch - fetched.len());
+            fetched.extend(p.log.range(start..start + take).map(|(_, m)| m.clone()));
+            *offset += take as u64;
+            if take > 0 {
+                touched.push(partition);
+            }
+        }
+        if !c.partitions.is_empty() {
+            c.next_partition = (c.next_partition + 1) % c.partitions.len();
+        }
+        if fetched.is_empty() {
+            self.schedule_poll(group, consumer, scheduler);
+            return;
+        }
+        c.in_flight = fetched.len();
+        let system = c.system;
+        for partition in touched {
+            self.trim(partition);
+            self.report_lag(partition, cur_t);
+        }
+        let broker = self.getref().unwrap();
+        let expires_t = cur_t + self.groups[group].config.processing_timeout_ns;
+        for mut message in fetched {
+            // every group gets its own copy of the message
+            let id = message.id;
+            message.id = scheduler.next_request_id();
+            message.reply_to.push(broker);
+            self.groups[group].consumed.inc();
+            self.in_flight.insert(
+                message.id,
+                Delivered {
+                    group,
+                    consumer,
+                    id,
+                },
+            );
+            self.events.push(expires_t, Event::Expire(message.id));
+            // the consumer may be the one that called back with its last response
+            scheduler.deliver_at(cur_t, system, message);
+        }
+        scheduler.schedule_at(expires_t, broker);
+    }
+
+    /// Gives up on a message the consumer did not process in time.
+    fn expire(&mut self, id: u64, scheduler: &mut Scheduler) {
+        let delivered = match self.in_flight.remove(&id) {
+            Some(delivered) => delivered,
+            None => return,
+        };
+        let g = &mut self.groups[delivered.group];
+        g.lost.inc();
+        let c = &mut g.consumers[delivered.consumer];
+        c.in_flight -= 1;
+        if c.in_flight == 0 && c.member {
+            self.poll(delivered.group, delivered.consumer, scheduler);
+        }
+        // a late response must not look like a new message
+        self.late.insert(id);
+    }
+}
+
+impl Sink for Broker {
+    fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        if self.late.remove(request.id) {
+            return;
+        }
+        if let Some(Delivered {
+            group,
+            consumer,
+            id,
+        }) = self.in_flight.remove(&request.id)
+        {
+            // a consumer processed the message
+            let g = &mut self.groups[group];
+            request.id = id;
+            let sink = request.respond_to(g.sink);
+            let c = &mut g.consumers[consumer];
+            c.in_flight -= 1;
+            let polls = c.in_flight == 0 && c.member;
+            world.with_system(sink, |system, world| system.next(request, world, scheduler));
+            if polls {
+                self.poll(group, consumer, scheduler);
+            }
+            return;
+        }
+        let cur_t = scheduler.get_cur_t();
+        if let Some(producer) = request.reply_to.pop() {
+            // the producer may be the one calling
+            let ack = request.clone();
+            // processed messages go to the group sinks
+            request.reply_to.clear();
+            scheduler.deliver_at(cur_t, producer, ack);
+        }
+        let partition = self.partition(&request);
+        self.partitions[partition].log.push_back((cur_t, request));
+        self.produced.inc();
+        if self.groups.is_empty() {
+            // nobody reads the partition
+            self.partitions[partition].log.pop_front();
+            self.partitions[partition].base += 1;
+        }
+        self.report_lag(partition, cur_t);
+    }
+}
+
+impl Emmitter for Broker {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        let cur_t = scheduler.get_cur_t();
+        while let Some(event) = self.events.pop_due(cur_t) {
+            match event {
+                Event::Poll(group, consumer, generation) => {
+                    let c = &self.groups[group].consumers[consumer];
+                    if c.member && c.generation == generation {
+                        self.poll(group, consumer, scheduler);
+                    }
+                }
+                Event::Heartbeat(group) => self.heartbeat(group, world, scheduler),
+                Event::Rebalance(group) => self.rebalance(group, "rebalance event", scheduler),
+                Event::Resume(group) => self.report_paused(group, cur_t),
+                Event::Expire(id) => self.expire(id, scheduler),
+            }
+        }
+        None
+    }
+}
+
+impl StatEmitter for Broker {
+    fn stats(&self) -> String {
+        let groups: Vec<String> = (0..self.groups.len())
+            .map(|group| {
+                let lags: Vec<String> = (0..self.partitions.len())
+                    .map(|p| tostring(self.lag(group, p) as usize))
+                    .collect();
+                let g = &self.groups[group];
+                format!(
+                    "{} consumed {} lost {} rebalances {} lag [{}]",
+                    g.name,
+                    g.consumed.stats(),
+                    g.lost.stats(),
+                    g.rebalances.stats(),
+                    lags.join(", ")
+                )
+            })
+            .collect();
+        format!("produced {} {}", self.produced.stats(), groups.join(" "))
+    }
+}
+
+impl HasQueue for Broker {
+    fn queue_size(&self) -> i64 {
+        self.partitions.iter().map(|p| p.log.len() as i64).sum()
+    }
+}
+
+impl WorldMember for Broker {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.produced.name = Some(name.clone() + "_produced");
+        for group in self.groups.iter_mut() {
+            let prefix = format!("{}_{}", name, group.name);
+            group.consumed.name = Some(prefix.clone() + "_consumed");
+            group.lost.name = Some(prefix.clone() + "_lost");
+            group.rebalances.name = Some(prefix.clone() + "_rebalances");
+            group.paused.name = Some(prefix.clone() + "_paused_partitions");
+            group.total_lag.name = Some(prefix.clone() + "_lag");
+            for (p, gauge) in group.lag.iter_mut().enumerate() {
+                gauge.name = Some(format!("{}_p{}_lag", prefix, p));
+            }
+            for (p, gauge) in group.lag_ns.iter_mut().enumerate() {
+                gauge.name = Some(format!("{}_p{}_lag_ns", prefix, p));
+            }
+        }
+        self.name = name;
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::{FailureInjector, Fault};
+    use crate::objects::Status;
+    use crate::systems::{LoadBalancer, OutlierDetection, Server};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    use rand_distr::Poisson;
+
+    fn config() -> ConsumerConfig {
+        ConsumerConfig {
+            fetch_batch: 1,
+            poll_interval_ns: 100_000,
+            heartbeat_ns: 1_000_000,
+            session_timeout_ns: 100_000_000,
+            processing_timeout_ns: 200_000,
+            rebalance: Rebalance::Eager { pause_ns: 0 },
+        }
+    }
+
+    /// A broker with one partition and one consumer writing to `sink`.
+    fn broker(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        sink: SystemRef,
+    ) -> (SystemRef, SystemRef) {
+        let consumer = Server::new(Poisson::new(1_000.0).unwrap(), sink);
+        let consumer = world.add(System::Server(consumer), "consumer".to_string());
+        let broker = Broker::new(1, Partitioning::RoundRobin).with_consumer_group(
+            "group",
+            vec![consumer],
+            config(),
+            sink,
+        );
+        let broker = world.add(System::Broker(broker), "broker".to_string());
+        scheduler.schedule(world, broker);
+        (broker, consumer)
+    }
+
+    fn with_broker<R, F: FnOnce(&mut Broker, &mut Scheduler) -> R>(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        broker: SystemRef,
+        f: F,
+    ) -> R {
+        world.with_system(broker, |system, _world| match system {
+            System::Broker(broker) => f(broker, scheduler),
+            _ => panic!("not a broker"),
+        })
+    }
+
+    fn lost(world: &mut World, broker: SystemRef) -> i64 {
+        world.with_system(broker, |system, _world| match system {
+            System::Broker(broker) => broker.groups[0].lost.value(),
+            _ => panic!("not a broker"),
+        })
+    }
+
+    /// Adds a third consumer to a group of two reading four partitions,
+    /// returns the partitions of each consumer and the paused ones.
+    fn scale_up(rebalance: Rebalance) -> (Vec<Vec<usize>>, Vec<usize>) {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let sink = end_sink(&mut world, "consumed");
+        let consumers: Vec<SystemRef> = (0..3)
+            .map(|i| {
+                let consumer = Server::new(Poisson::new(1_000.0).unwrap(), sink);
+                world.add(System::Server(consumer), format!("consumer{}", i))
+            })
+            .collect();
+        let config = ConsumerConfig {
+            rebalance,
+            ..config()
+        };
+        let broker = Broker::new(4, Partitioning::RoundRobin).with_consumer_group(
+            "group",
+            consumers[..2].to_vec(),
+            config,
+            sink,
+        );
+        let broker = world.add(System::Broker(broker), "broker".to_string());
+        with_broker(&mut world, &mut scheduler, broker, |broker, scheduler| {
+            broker.add_consumer("group", consumers[2], scheduler);
+            let g = &broker.groups[0];
+            let partitions = g.consumers.iter().map(|c| c.partitions.clone()).collect();
+            let paused = (0..4).filter(|p| g.paused_until[*p] > 0).collect();
+            (partitions, paused)
+        })
+    }
+
+    #[test]
+    fn eager_rebalance_pauses_every_partition() {
+        let (partitions, paused) = scale_up(Rebalance::Eager { pause_ns: 1_000 });
+        assert_eq!(partitions, vec![vec![0, 1], vec![2], vec![3]]);
+        assert_eq!(paused, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn cooperative_rebalance_pauses_moved_partitions() {
+        let (partitions, paused) = scale_up(Rebalance::Cooperative { pause_ns: 1_000 });
+        assert_eq!(partitions, vec![vec![0, 1], vec![2], vec![3]]);
+        assert_eq!(paused, vec![3]);
+    }
+
+    #[test]
+    fn failed_consumer_leaves_the_group() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let consumed = end_sink(&mut world, "consumed");
+        let consumers: Vec<SystemRef> = (0..2)
+            .map(|i| {
+                let consumer = Server::new(Poisson::new(1_000.0).unwrap(), consumed);
+                world.add(System::Server(consumer), format!("consumer{}", i))
+            })
+            .collect();
+        let config = ConsumerConfig {
+            session_timeout_ns: 2_000_000,
+            ..config()
+        };
+        let broker = Broker::new(2, Partitioning::RoundRobin).with_consumer_group(
+            "group",
+            consumers.clone(),
+            config,
+            consumed,
+        );
+        let broker = world.add(System::Broker(broker), "broker".to_string());
+        scheduler.schedule(&mut world, broker);
+        let injector =
+            FailureInjector::new().at(1_000_000, consumers[0], Fault::Crash { fail: false });
+        let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+        scheduler.schedule(&mut world, injector);
+        for i in 0..100 {
+            send_at(&mut scheduler, i * 100_000 + 1, broker, 0, None);
+        }
+        run(&mut world, &mut scheduler, 20_000_000);
+        let lost = lost(&mut world, broker);
+        assert_eq!(count(&mut world, consumed, Status::Ok) + lost, 100);
+        with_broker(&mut world, &mut scheduler, broker, |broker, _| {
+            let g = &broker.groups[0];
+            assert_eq!(g.rebalances.value(), 1);
+            assert!(!g.consumers[0].member);
+            assert_eq!(g.consumers[1].partitions, vec![0, 1]);
+            assert_eq!(g.total_lag.get(), 0.0);
+        });
+    }
+
+    #[test]
+    fn acks_the_producer() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let acks = end_sink(&mut world, "acks");
+        let consumed = end_sink(&mut world, "consumed");
+        let (broker, _) = broker(&mut world, &mut scheduler, consumed);
+        let outlier_detection = OutlierDetection {
+            consecutive_errors: 1,
+            max_latency_ns: None,
+            ejection_ns: 100_000_000,
+            timeout_ns: 100_000,
+        };
+        let producer =
+            LoadBalancer::new(vec![broker]).with_outlier_detection(outlier_detection, acks);
+        let producer = world.add(System::LoadBalancer(producer), "producer".to_string());
+        scheduler.schedule(&mut world, producer);
+        for i in 0..10 {
+            send_at(&mut scheduler, i * 10_000 + 1, producer, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, acks, Status::Ok), 10);
+        assert_eq!(count(&mut world, acks, Status::Timeout), 0);
+        assert_eq!(count(&mut world, consumed, Status::Ok), 10);
+    }
+
+    #[test]
+    fn consumer_polls_again_after_dropping_a_message() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let consumed = end_sink(&mut world, "consumed");
+        let (broker, consumer) = broker(&mut world, &mut scheduler, consumed);
+        let restart = Fault::Restart {
+            cold_ns: 0,
+            factor: 1.0,
+        };
+        let injector = FailureInjector::new()
+            .at(300_000, consumer, Fault::Crash { fail: false })
+            .at(400_000, consumer, restart);
+        let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+        scheduler.schedule(&mut world, injector);
+        for i in 0..100 {
+            send_at(&mut scheduler, i * 10_000 + 1, broker, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        let lost = lost(&mut world, broker);
+        assert!(lost >= 1);
+        assert_eq!(count(&mut world, consumed, Status::Ok) + lost, 100);
+    }
+}
@@ -0,0 +1,580 @@
+use crate::objects::{Request, Scheduler, Status, World};
+use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{tostring, tostringfloat, Counter, DelayQueue, Gauge};
+
+use rand_distr::Distribution;
+use rand_distr::Poisson;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Hit ratio gauge is recalculated once per this much simulation time.
+const HIT_RATIO_WINDOW_NS: i64 = 1_000_000_000;
+
+pub enum EvictionPolicy {
+    /// Evicts the least recently used key.
+    Lru,
+    /// Evicts the least frequently used key, least recently used among equals.
+    Lfu,
+    /// Keys expire after the given ns since they were filled,
+    /// when full the oldest filled key is evicted.
+    Ttl(i64),
+}
+
+struct CacheEntry {
+    filled_t: i64,
+    rank: (u64, u64),
+}
+
+enum Lookup {
+    Hit,
+    /// Expired but still within the stale-while-revalidate period.
+    Stale,
+    Miss,
+}
+
+/// Cache sits between a source and a backend.
+/// Hits are served after the hit distribution delay, misses are sent to the
+/// backend and fill the cache when the backend responds successfully.
+/// Requests without a key always go to the backend.
+///
+/// With coalescing, misses for a key which is already being fetched wait for
+/// that fetch instead of going to the backend.
+/// With stale-while-revalidate, expired keys are served as hits for a while
+/// longer and a single background fetch refreshes them.
+pub struct Cache {
+    hit_distribution: Poisson<f32>,
+    backend: SystemRef,
+    sink: SystemRef,
+    policy: EvictionPolicy,
+    capacity: usize,
+    entries: HashMap<u64, CacheEntry>,
+    // (rank, key), first is the next to evict
+    order: BTreeSet<(u64, u64, u64)>,
+    uses: u64,
+    coalescing: bool,
+    stale_ns: Option<i64>,
+    // ids of requests sent to the backend, and of background refreshes
+    fetching: HashSet<u64>,
+    refreshing: HashSet<u64>,
+    // keys being fetched, with requests coalesced onto the fetch
+    keys_in_flight: HashMap<u64, Vec<Request>>,
+    hits_in_flight: DelayQueue<Request>,
+    hits: Counter,
+    stale_hits: Counter,
+    misses: Counter,
+    coalesced: Counter,
+    evictions: Counter,
+    backend_requests: Counter,
+    backend_in_flight: Gauge,
+    hit_ratio: Gauge,
+    window_start_t: i64,
+    window_hits: i64,
+    window_lookups: i64,
+    sr: Option<SystemRef>,
+}
+
+impl Cache {
+    pub fn new(
+        hit_distribution: Poisson<f32>,
+        policy: EvictionPolicy,
+        capacity: usize,
+        backend: SystemRef,
+        sink: SystemRef,
+    ) -> Self {
+        assert!(capacity > 0);
+        Cache {
+            hit_distribution,
+            backend,
+            sink,
+            policy,
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeSet::new(),
+            uses: 0,
+            coalescing: false,
+            stale_ns: None,
+            fetching: HashSet::new(),
+            refreshing: HashSet::new(),
+            keys_in_flight: HashMap::new(),
+            hits_in_flight: DelayQueue::new(),
+            hits: Counter::new(),
+            stale_hits: Counter::new(),
+            misses: Counter::new(),
+            coalesced: Counter::new(),
+            evictions: Counter::new(),
+            backend_requests: Counter::new(),
+            backend_in_flight: Gauge::new(),
+            hit_ratio: Gauge::new(),
+            window_start_t: 0,
+            window_hits: 0,
+            window_lookups: 0,
+            sr: None,
+        }
+    }
+
+    /// Misses for a key already being fetched wait for that fetch.
+    pub fn with_coalescing(mut self) -> Self {
+        self.coalescing = true;
+        self
+    }
+
+    /// Keys expired less than `stale_ns` ago are served and refreshed in the
+    /// background, only makes sense with the ttl policy.
+    pub fn with_stale_while_revalidate(mut self, stale_ns: i64) -> Self {
+        assert!(matches!(self.policy, EvictionPolicy::Ttl(_)));
+        self.stale_ns = Some(stale_ns);
+        self
+    }
+
+    fn rank(&self, entry: Option<&CacheEntry>) -> (u64, u64) {
+        match self.policy {
+            EvictionPolicy::Lru => (self.uses, 0),
+            EvictionPolicy::Lfu => (entry.map_or(1, |e| e.rank.0 + 1), self.uses),
+            EvictionPolicy::Ttl(_) => entry.map_or((self.uses, 0), |e| e.rank),
+        }
+    }
+
+    fn remove(&mut self, key: u64) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.order.remove(&(entry.rank.0, entry.rank.1, key));
+        }
+    }
+
+    /// Looks the key up, updating its rank on a hit.
+    fn lookup(&mut self, key: u64, cur_t: i64) -> Lookup {
+        let lookup = match (&self.policy, self.entries.get(&key)) {
+            (_, None) => return Lookup::Miss,
+            (EvictionPolicy::Ttl(ttl), Some(entry)) if entry.filled_t + ttl <= cur_t => {
+                match self.stale_ns {
+                    Some(stale_ns) if entry.filled_t + ttl + stale_ns > cur_t => Lookup::Stale,
+                    _ => Lookup::Miss,
+                }
+            }
+            _ => Lookup::Hit,
+        };
+        if let Lookup::Miss = lookup {
+            self.remove(key);
+            return lookup;
+        }
+        self.uses += 1;
+        let entry = self.entries.remove(&key).unwrap();
+        self.order.remove(&(entry.rank.0, entry.rank.1, key));
+        let rank = self.rank(Some(&entry));
+        self.order.insert((rank.0, rank.1, key));
+        self.entries.insert(
+            key,
+            CacheEntry {
+                filled_t: entry.filled_t,
+                rank,
+            },
+        );
+        lookup
+    }
+
+    fn fill(&mut self, key: u64, cur_t: i64) {
+        self.remove(key);
+        while self.entries.len() >= self.capacity {
+            let (_, _, evicted) = *self.order.iter().next().unwrap();
+            self.remove(evicted);
+            self.evictions.inc();
+        }
+        self.uses += 1;
+        let rank = self.rank(None);
+        self.order.insert((rank.0, rank.1, key));
+        self.entries.insert(
+            key,
+            CacheEntry {
+                filled_t: cur_t,
+                rank,
+            },
+        );
+    }
+
+    fn record_lookup(&mut self, lookup: &Lookup, cur_t: i64) {
+        match lookup {
+            Lookup::Hit => {
+                self.hits.inc();
+                self.window_hits += 1;
+            }
+            Lookup::Stale => {
+                self.hits.inc();
+                self.stale_hits.inc();
+                self.window_hits += 1;
+            }
+            Lookup::Miss => self.misses.inc(),
+        }
+        self.window_lookups += 1;
+        if cur_t >= self.window_start_t + HIT_RATIO_WINDOW_NS {
+            self.hit_ratio
+                .set(self.window_hits as f64 / self.window_lookups as f64);
+            self.window_start_t = cur_t;
+            self.window_hits = 0;
+            self.window_lookups = 0;
+        }
+    }
+}
+
+impl Cache {
+    fn fetch(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        if let Some(key) = request.key {
+            self.keys_in_flight.entry(key).or_default();
+        }
+        self.backend_requests.inc();
+        self.backend_in_flight
+            .set((self.fetching.len() + self.refreshing.len()) as f64);
+        request.reply_to.push(self.getref().unwrap());
+        world.with_system(self.backend, |system, world| {
+            system.next(request, world, scheduler)
+        });
+    }
+
+    fn respond(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        let sink = request.respond_to(self.sink);
+        world.with_system(sink, |system, world| system.next(request, world, scheduler));
+    }
+
+    fn on_backend_response(
+        &mut self,
+        request: Request,
+        world: &mut World,
+        scheduler: &mut Scheduler,
+    ) {
+        let refresh = self.refreshing.remove(&request.id);
+        self.fetching.remove(&request.id);
+        self.backend_in_flight
+            .set((self.fetching.len() + self.refreshing.len()) as f64);
+        let waiting = match request.key {
+            Some(key) => {
+                if request.status == Status::Ok {
+                    self.fill(key, scheduler.get_cur_t());
+                }
+                self.keys_in_flight.remove(&key).unwrap_or_default()
+            }
+            None => vec![],
+        };
+        let status = request.status;
+        if !refresh {
+            self.respond(request, world, scheduler);
+        }
+        for mut request in waiting {
+            // coalesced requests get what the fetch got
+            request.status = status;
+            self.respond(request, world, scheduler);
+        }
+    }
+}
+
+impl Sink for Cache {
+    fn next(&mut self, request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        if self.fetching.contains(&request.id) || self.refreshing.contains(&request.id) {
+            self.on_backend_response(request, world, scheduler);
+            return;
+        }
+        let key = match request.key {
+            Some(key) => key,
+            None => {
+                self.fetching.insert(request.id);
+                self.fetch(request, world, scheduler);
+                return;
+            }
+        };
+        let lookup = self.lookup(key, cur_t);
+        self.record_lookup(&lookup, cur_t);
+        match lookup {
+            Lookup::Hit | Lookup::Stale => {
+                if let Lookup::Stale = lookup {
+                    if !self.keys_in_flight.contains_key(&key) {
+                        let mut refresh = scheduler.new_request();
+                        refresh.key = Some(key);
+                        refresh.location = world.location(self.getref().unwrap());
+                        self.refreshing.insert(refresh.id);
+                        self.fetch(refresh, world, scheduler);
+                    }
+                }
+                let delay = self.hit_distribution.sample(&mut rand::thread_rng()) as i64;
+                self.hits_in_flight.push(cur_t + delay, request);
+                scheduler.schedule_at(cur_t + delay, self.getref().unwrap());
+            }
+            Lookup::Miss => {
+                if self.coalescing {
+                    if let Some(waiting) = self.keys_in_flight.get_mut(&key) {
+                        waiting.push(request);
+                        self.coalesced.inc();
+                        return;
+                    }
+                }
+                self.fetching.insert(request.id);
+                self.fetch(request, world, scheduler);
+            }
+        }
+    }
+}
+
+impl Emmitter for Cache {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        while let Some(request) = self.hits_in_flight.pop_due(scheduler.get_cur_t()) {
+            self.respond(request, world, scheduler);
+        }
+        None
+    }
+}
+
+impl StatEmitter for Cache {
+    fn stats(&self) -> String {
+        let lookups = self.hits.value() + self.misses.value();
+        let ratio = if lookups == 0 {
+            0.0
+        } else {
+            self.hits.value() as f64 / lookups as f64
+        };
+        format!(
+            "hits {} stale {} misses {} coalesced {} hit ratio {} evictions {} size {} backend {}",
+            self.hits.stats(),
+            self.stale_hits.stats(),
+            self.misses.stats(),
+            self.coalesced.stats(),
+            tostringfloat(ratio),
+            self.evictions.stats(),
+            tostring(self.entries.len()),
+            self.backend_requests.stats()
+        )
+    }
+}
+
+impl HasQueue for Cache {
+    fn queue_size(&self) -> i64 {
+        let coalesced: usize = self.keys_in_flight.values().map(|w| w.len()).sum();
+        (self.hits_in_flight.len() + coalesced) as i64
+    }
+}
+
+impl WorldMember for Cache {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.hits.name = Some(name.clone() + "_hits");
+        self.stale_hits.name = Some(name.clone() + "_stale_hits");
+        self.misses.name = Some(name.clone() + "_misses");
+        self.coalesced.name = Some(name.clone() + "_coalesced");
+        self.evictions.name = Some(name.clone() + "_evictions");
+        self.backend_requests.name = Some(name.clone() + "_backend_requests");
+        self.backend_in_flight.name = Some(name.clone() + "_backend_in_flight");
+        self.hit_ratio.name = Some(name + "_hit_ratio");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::{ErrorModel, Server, System};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    /// A cache on its own, filled and looked up directly.
+    fn cache(policy: EvictionPolicy, capacity: usize) -> Cache {
+        let mut world = World::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        Cache::new(
+            Poisson::new(100.0).unwrap(),
+            policy,
+            capacity,
+            end_sink,
+            end_sink,
+        )
+    }
+
+    fn keys(cache: &Cache) -> Vec<u64> {
+        let mut keys: Vec<u64> = cache.entries.keys().copied().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_key() {
+        let mut cache = cache(EvictionPolicy::Lru, 2);
+        cache.fill(1, 0);
+        cache.fill(2, 0);
+        assert!(matches!(cache.lookup(1, 0), Lookup::Hit));
+        cache.fill(3, 0);
+        assert_eq!(keys(&cache), vec![1, 3]);
+        assert!(matches!(cache.lookup(2, 0), Lookup::Miss));
+        assert_eq!(cache.evictions.value(), 1);
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used_key() {
+        let mut cache = cache(EvictionPolicy::Lfu, 2);
+        cache.fill(1, 0);
+        cache.fill(2, 0);
+        for _ in 0..2 {
+            cache.lookup(1, 0);
+        }
+        // used more recently, but less often
+        cache.lookup(2, 0);
+        cache.fill(3, 0);
+        assert_eq!(keys(&cache), vec![1, 3]);
+        // equally used keys go least recently used first
+        cache.lookup(3, 0);
+        cache.lookup(3, 0);
+        cache.fill(4, 0);
+        assert_eq!(keys(&cache), vec![3, 4]);
+    }
+
+    #[test]
+    fn ttl_expires_keys_and_evicts_the_oldest_fill() {
+        let mut cache = cache(EvictionPolicy::Ttl(100), 2);
+        cache.fill(1, 0);
+        cache.fill(2, 10);
+        // using a key does not keep it longer
+        assert!(matches!(cache.lookup(1, 50), Lookup::Hit));
+        cache.fill(3, 60);
+        assert_eq!(keys(&cache), vec![2, 3]);
+        assert!(matches!(cache.lookup(2, 109), Lookup::Hit));
+        assert!(matches!(cache.lookup(2, 110), Lookup::Miss));
+        assert_eq!(keys(&cache), vec![3]);
+    }
+
+    #[test]
+    fn never_holds_more_than_its_capacity() {
+        for policy in [
+            EvictionPolicy::Lru,
+            EvictionPolicy::Lfu,
+            EvictionPolicy::Ttl(1_000),
+        ] {
+            let mut cache = cache(policy, 10);
+            for key in 0..100 {
+                cache.fill(key % 30, key as i64);
+                cache.lookup(key % 7, key as i64);
+                assert!(cache.entries.len() <= 10);
+                assert_eq!(cache.order.len(), cache.entries.len());
+            }
+            assert_eq!(cache.entries.len(), 10);
+        }
+    }
+
+    /// A cache in front of a server taking 100us per request.
+    fn cached_server(
+        world: &mut World,
+        policy: EvictionPolicy,
+        configure: impl FnOnce(Cache) -> Cache,
+    ) -> (SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let server = Server::new(Poisson::new(100_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let cache = Cache::new(Poisson::new(100.0).unwrap(), policy, 10, server, end_sink);
+        let cache = world.add(System::Cache(configure(cache)), "cache".to_string());
+        (cache, end_sink)
+    }
+
+    fn with_cache<R>(world: &mut World, cache: SystemRef, f: impl FnOnce(&Cache) -> R) -> R {
+        world.with_system(cache, |system, _world| match system {
+            System::Cache(cache) => f(cache),
+            _ => unreachable!(),
+        })
+    }
+
+    #[test]
+    fn hit_ratio_is_reported_per_window() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (cache, end_sink) = cached_server(&mut world, EvictionPolicy::Lru, |cache| cache);
+        // a miss and three hits, then a miss closing the window
+        for t in 0..4 {
+            send_at(&mut scheduler, t * 1_000_000, cache, 0, Some(1));
+        }
+        send_at(&mut scheduler, HIT_RATIO_WINDOW_NS, cache, 0, Some(2));
+        run(&mut world, &mut scheduler, HIT_RATIO_WINDOW_NS + 1_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 5);
+        with_cache(&mut world, cache, |cache| {
+            assert!((cache.hit_ratio.get() - 0.6).abs() < 1e-9);
+            assert_eq!(cache.backend_requests.value(), 2);
+        });
+    }
+
+    /// Backend requests for ten concurrent misses on one key.
+    fn backend_requests_for_concurrent_misses(coalescing: bool) -> i64 {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (cache, end_sink) = cached_server(&mut world, EvictionPolicy::Lru, |cache| {
+            if coalescing {
+                cache.with_coalescing()
+            } else {
+                cache
+            }
+        });
+        for t in 0..10 {
+            send_at(&mut scheduler, t * 1_000, cache, 0, Some(1));
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 10);
+        with_cache(&mut world, cache, |cache| {
+            assert!(cache.keys_in_flight.is_empty());
+            cache.backend_requests.value()
+        })
+    }
+
+    #[test]
+    fn coalescing_sends_one_fetch_per_key() {
+        assert_eq!(backend_requests_for_concurrent_misses(false), 10);
+        assert_eq!(backend_requests_for_concurrent_misses(true), 1);
+    }
+
+    #[test]
+    fn stale_hits_are_served_while_one_refresh_runs() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (cache, end_sink) =
+            cached_server(&mut world, EvictionPolicy::Ttl(1_000_000), |cache| {
+                cache.with_stale_while_revalidate(1_000_000)
+            });
+        // filled at about 100us, expired at 1.1ms
+        send_at(&mut scheduler, 0, cache, 0, Some(1));
+        for t in 0..5 {
+            send_at(&mut scheduler, 1_500_000 + t * 1_000, cache, 0, Some(1));
+        }
+        // well before the refresh can be back
+        run(&mut world, &mut scheduler, 1_520_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 6);
+        with_cache(&mut world, cache, |cache| {
+            assert_eq!(cache.stale_hits.value(), 5);
+            assert_eq!(cache.refreshing.len(), 1);
+            assert_eq!(cache.backend_requests.value(), 2);
+        });
+    }
+
+    #[test]
+    fn failed_fetches_do_not_fill() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let error_model = ErrorModel {
+            probability: 1.0,
+            ..ErrorModel::default()
+        };
+        let server =
+            Server::new(Poisson::new(100_000.0).unwrap(), end_sink).with_error_model(error_model);
+        let server = world.add(System::Server(server), "server".to_string());
+        let cache = Cache::new(
+            Poisson::new(100.0).unwrap(),
+            EvictionPolicy::Lru,
+            10,
+            server,
+            end_sink,
+        )
+        .with_coalescing();
+        let cache = world.add(System::Cache(cache), "cache".to_string());
+        for t in 0..3 {
+            send_at(&mut scheduler, t, cache, 0, Some(1));
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        // the fetch and the two requests coalesced onto it
+        assert_eq!(count(&mut world, end_sink, Status::Error), 3);
+        with_cache(&mut world, cache, |cache| {
+            assert_eq!(cache.backend_requests.value(), 1);
+            assert!(cache.entries.is_empty());
+        });
+    }
+}
@@ -0,0 +1,414 @@
+use crate::objects::{Request, Scheduler, Status, World};
+use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, DelayQueue, Histogram, LateIds};
+
+use rand::Rng;
+use rand_distr::{Distribution, Poisson};
+
+use std::collections::HashMap;
+
+/// Decides which steps of a branch run.
+pub enum Condition {
+    /// True with the probability.
+    Probability(f64),
+    /// True for requests of the kind.
+    Kind(usize),
+    /// True when a call of the request failed so far.
+    Failed,
+}
+
+/// One step of a CallGraphService program.
+pub enum Step {
+    /// Local work taking a time in ns sampled from the distribution.
+    Compute(Poisson<f32>),
+    /// Calls the system and waits for its response.
+    Call(SystemRef),
+    /// Calls all the systems at once and waits for every response.
+    Parallel(Vec<SystemRef>),
+    /// Runs `then` when the condition holds, otherwise `otherwise`.
+    Branch {
+        condition: Condition,
+        then: Vec<Step>,
+        otherwise: Vec<Step>,
+    },
+}
+
+/// Steps flattened into instructions, branches become jumps.
+enum Op {
+    Compute {
+        time: Poisson<f32>,
+        step: usize,
+    },
+    Call {
+        systems: Vec<SystemRef>,
+        step: usize,
+    },
+    // continues with the next op when the condition holds, else at `otherwise`
+    Branch {
+        condition: Condition,
+        otherwise: usize,
+    },
+    Jump(usize),
+}
+
+fn compile(steps: Vec<Step>, ops: &mut Vec<Op>, names: &mut Vec<String>) {
+    for step in steps {
+        match step {
+            Step::Compute(time) => {
+                ops.push(Op::Compute {
+                    time,
+                    step: names.len(),
+                });
+                names.push("compute".to_string());
+            }
+            Step::Call(system) => {
+                ops.push(Op::Call {
+                    systems: vec![system],
+                    step: names.len(),
+                });
+                names.push(format!("call {}", system));
+            }
+            Step::Parallel(systems) => {
+                assert!(!systems.is_empty());
+                names.push(format!("parallel {:?}", systems));
+                ops.push(Op::Call {
+                    systems,
+                    step: names.len() - 1,
+                });
+            }
+            Step::Branch {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let branch = ops.len();
+                ops.push(Op::Jump(0));
+                compile(then, ops, names);
+                let jump = ops.len();
+                ops.push(Op::Jump(0));
+                ops[branch] = Op::Branch {
+                    condition,
+                    otherwise: ops.len(),
+                };
+                compile(otherwise, ops, names);
+                ops[jump] = Op::Jump(ops.len());
+            }
+        }
+    }
+}
+
+/// A request going through the program.
+struct Execution {
+    request: Request,
+    pc: usize,
+    // calls of the current step not answered yet
+    waiting: usize,
+    failed: bool,
+    start_t: i64,
+    step_start_t: i64,
+}
+
+/// CallGraphService serves each request by running a program of steps:
+/// local compute, calls to other systems waiting for their response,
+/// parallel calls waiting for all of them and branches.
+/// Requests run their programs concurrently, calls are new requests with
+/// the key, kind and size of the request. A request with a failed call
+/// completes with an error once its program ends.
+/// With a call timeout, a call without a response by then fails. Its
+/// response is dropped whenever it comes back.
+/// Time spent in each step is recorded to break down the critical path.
+pub struct CallGraphService {
+    ops: Vec<Op>,
+    sink: SystemRef,
+    executions: HashMap<u64, Execution>,
+    // id of a call to the id of the request making it
+    calls: HashMap<u64, u64>,
+    computing: DelayQueue<u64>,
+    call_timeout_ns: Option<i64>,
+    // ids of calls which time out then
+    timeouts: DelayQueue<u64>,
+    // calls which timed out and may still be answered
+    late: LateIds,
+    step_names: Vec<String>,
+    steps: Vec<Histogram>,
+    latency: Histogram,
+    failed_calls: Counter,
+    timed_out_calls: Counter,
+    failed: Counter,
+    sr: Option<SystemRef>,
+}
+
+impl CallGraphService {
+    pub fn new(program: Vec<Step>, sink: SystemRef) -> Self {
+        let mut ops = Vec::new();
+        let mut step_names = Vec::new();
+        compile(program, &mut ops, &mut step_names);
+        CallGraphService {
+            ops,
+            sink,
+            executions: HashMap::new(),
+            calls: HashMap::new(),
+            computing: DelayQueue::new(),
+            call_timeout_ns: None,
+            timeouts: DelayQueue::new(),
+            late: LateIds::new(),
+            steps: step_names.iter().map(|_| Histogram::new()).collect(),
+            step_names,
+            latency: Histogram::new(),
+            failed_calls: Counter::new(),
+            timed_out_calls: Counter::new(),
+            failed: Counter::new(),
+            sr: None,
+        }
+    }
+
+    /// Calls without a response for `timeout_ns` fail.
+    pub fn with_call_timeout(mut self, timeout_ns: i64) -> Self {
+        assert!(timeout_ns > 0);
+        self.call_timeout_ns = Some(timeout_ns);
+        self
+    }
+
+    /// Runs the program of the request until it waits or ends.
+    fn run(&mut self, id: u64, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let execution = self.executions.get_mut(&id).unwrap();
+        loop {
+            match self.ops.get(execution.pc) {
+                None => break,
+                Some(Op::Compute { time, .. }) => {
+                    let t = cur_t + time.sample(&mut rand::thread_rng()) as i64;
+                    execution.step_start_t = cur_t;
+                    self.computing.push(t, id);
+                    scheduler.schedule_at(t, self.sr.unwrap());
+                    return;
+                }
+                Some(Op::Call { systems, .. }) => {
+                    execution.step_start_t = cur_t;
+                    execution.waiting = systems.len();
+                    for system in systems {
+                        let mut call = scheduler.new_request();
+                        call.created_t = execution.request.created_t;
+                        call.key = execution.request.key;
+                        call.kind = execution.request.kind;
+                        call.size = execution.request.size;
+                        call.location = execution.request.location;
+                        call.reply_to.push(self.sr.unwrap());
+                        self.calls.insert(call.id, id);
+                        if let Some(timeout_ns) = self.call_timeout_ns {
+                            self.timeouts.push(cur_t + timeout_ns, call.id);
+                            scheduler.schedule_at(cur_t + timeout_ns, self.sr.unwrap());
+                        }
+                        // the system may be the one whose response is being handled
+                        scheduler.deliver_at(cur_t, *system, call);
+                    }
+                    return;
+                }
+                Some(Op::Branch {
+                    condition,
+                    otherwise,
+                }) => {
+                    let holds = match condition {
+                        Condition::Probability(p) => rand::thread_rng().gen::<f64>() < *p,
+                        Condition::Kind(kind) => execution.request.kind == *kind,
+                        Condition::Failed => execution.failed,
+                    };
+                    execution.pc = if holds { execution.pc + 1 } else { *otherwise };
+                }
+                Some(Op::Jump(pc)) => execution.pc = *pc,
+            }
+        }
+        let mut execution = self.executions.remove(&id).unwrap();
+        self.latency.update(cur_t - execution.start_t);
+        if execution.failed {
+            self.failed.inc();
+            execution.request.status = Status::Error;
+        }
+        let mut request = execution.request;
+        let sink = request.respond_to(self.sink);
+        // the sink may be the one whose response is being handled
+        scheduler.deliver_at(cur_t, sink, request);
+    }
+
+    /// Records the answer to a call, runs on once the step has all of them.
+    fn call_answered(&mut self, id: u64, ok: bool, scheduler: &mut Scheduler) {
+        let execution = self.executions.get_mut(&id).unwrap();
+        if !ok {
+            self.failed_calls.inc();
+            execution.failed = true;
+        }
+        execution.waiting -= 1;
+        if execution.waiting == 0 {
+            self.step_done(id, scheduler.get_cur_t());
+            self.run(id, scheduler);
+        }
+    }
+
+    /// Records the time spent in the current step and moves to the next one.
+    fn step_done(&mut self, id: u64, cur_t: i64) {
+        let execution = self.executions.get_mut(&id).unwrap();
+        let step = match &self.ops[execution.pc] {
+            Op::Compute { step, .. } | Op::Call { step, .. } => *step,
+            _ => unreachable!(),
+        };
+        self.steps[step].update(cur_t - execution.step_start_t);
+        execution.pc += 1;
+    }
+}
+
+impl Sink for CallGraphService {
+    fn next(&mut self, request: Request, _world: &mut World, scheduler: &mut Scheduler) {
+        if self.late.remove(request.id) {
+            return;
+        }
+        if let Some(id) = self.calls.remove(&request.id) {
+            let execution = self.executions.get_mut(&id).unwrap();
+            execution.request.cross_region_ns += request.cross_region_ns;
+            self.call_answered(id, request.status == Status::Ok, scheduler);
+            return;
+        }
+        let id = request.id;
+        self.executions.insert(
+            id,
+            Execution {
+                request,
+                pc: 0,
+                waiting: 0,
+                failed: false,
+                start_t: scheduler.get_cur_t(),
+                step_start_t: 0,
+            },
+        );
+        self.run(id, scheduler);
+    }
+}
+
+impl Emmitter for CallGraphService {
+    fn tick(&mut self, _world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        let cur_t = scheduler.get_cur_t();
+        while let Some(id) = self.computing.pop_due(cur_t) {
+            self.step_done(id, cur_t);
+            self.run(id, scheduler);
+        }
+        while let Some(call) = self.timeouts.pop_due(cur_t) {
+            if let Some(id) = self.calls.remove(&call) {
+                self.timed_out_calls.inc();
+                self.late.insert(call);
+                self.call_answered(id, false, scheduler);
+            }
+        }
+        None
+    }
+}
+
+impl StatEmitter for CallGraphService {
+    fn stats(&self) -> String {
+        let steps: Vec<String> = self
+            .step_names
+            .iter()
+            .zip(&self.steps)
+            .map(|(name, h)| format!("{} {}", name, h.stats()))
+            .collect();
+        format!(
+            "latency {} steps [{}] failed calls {} timed out calls {} failed {}",
+            self.latency.stats(),
+            steps.join(", "),
+            self.failed_calls.stats(),
+            self.timed_out_calls.stats(),
+            self.failed.stats()
+        )
+    }
+}
+
+impl HasQueue for CallGraphService {
+    fn queue_size(&self) -> i64 {
+        self.executions.len() as i64
+    }
+}
+
+impl WorldMember for CallGraphService {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        for (i, h) in self.steps.iter_mut().enumerate() {
+            h.name = Some(format!("{}_step{}", name, i + 1));
+        }
+        self.latency.name = Some(name.clone() + "_latency");
+        self.failed_calls.name = Some(name.clone() + "_failed_calls");
+        self.timed_out_calls.name = Some(name.clone() + "_timed_out_calls");
+        self.failed.name = Some(name + "_failed");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::{FailureInjector, Fault};
+    use crate::systems::{Server, System};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    /// Sends `n` requests to a service calling a server with the mean
+    /// service time, returns the outcomes.
+    fn outcomes(
+        n: i64,
+        service_ns: f32,
+        fault: Option<Fault>,
+        with: impl FnOnce(CallGraphService) -> CallGraphService,
+    ) -> Vec<i64> {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let server = Server::new(Poisson::new(service_ns).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        if let Some(fault) = fault {
+            let injector = FailureInjector::new().at(0, server, fault);
+            let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+            scheduler.schedule(&mut world, injector);
+        }
+        let program = vec![
+            Step::Compute(Poisson::new(1_000.0).unwrap()),
+            Step::Call(server),
+        ];
+        let service = with(CallGraphService::new(program, end_sink));
+        let service = world.add(System::CallGraphService(service), "service".to_string());
+        for i in 0..n {
+            send_at(&mut scheduler, i * 10_000 + 1, service, 0, None);
+        }
+        run(&mut world, &mut scheduler, 100_000_000);
+        world.with_system(service, |system, _world| match system {
+            System::CallGraphService(service) => {
+                assert!(service.executions.is_empty());
+                assert!(service.calls.is_empty());
+            }
+            _ => panic!("not a call graph service"),
+        });
+        Status::ALL
+            .iter()
+            .map(|status| count(&mut world, end_sink, *status))
+            .collect()
+    }
+
+    #[test]
+    fn runs_the_program() {
+        assert_eq!(outcomes(10, 1_000.0, None, |s| s), vec![10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn failed_call_fails_the_request() {
+        let crash = Some(Fault::Crash { fail: true });
+        assert_eq!(outcomes(10, 1_000.0, crash, |s| s), vec![0, 10, 0, 0]);
+    }
+
+    #[test]
+    fn call_timeout_fails_the_request() {
+        let crash = Some(Fault::Crash { fail: false });
+        let timeout = |s: CallGraphService| s.with_call_timeout(600_000);
+        assert_eq!(outcomes(10, 1_000.0, crash, timeout), vec![0, 10, 0, 0]);
+        // late responses are dropped, not taken for new requests
+        assert_eq!(outcomes(1, 1_000_000.0, None, timeout), vec![0, 1, 0, 0]);
+        assert_eq!(outcomes(3, 10_000_000.0, None, timeout), vec![0, 3, 0, 0]);
+    }
+}
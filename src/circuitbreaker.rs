@@ -0,0 +1,456 @@
+use crate::objects::{Request, Scheduler, Status, World};
+use crate::traits::{Emmitter, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, DelayQueue, Gauge, LateIds};
+
+use std::collections::{HashMap, VecDeque};
+
+pub struct CircuitBreakerConfig {
+    /// Outcomes older than this are forgotten.
+    pub window_ns: i64,
+    /// The circuit does not open with fewer outcomes in the window.
+    pub min_requests: usize,
+    /// Opens when this share of outcomes in the window are bad.
+    pub failure_ratio: f64,
+    /// Responses slower than this count as bad.
+    pub slow_call_ns: Option<i64>,
+    /// Requests without a response for this long time out and count as bad.
+    /// Without a timeout, requests unanswered for the window are no longer
+    /// watched, their responses pass through without being recorded.
+    pub timeout_ns: Option<i64>,
+    /// How long the circuit stays open before probing. Without a timeout, also
+    /// how long probes may go unanswered before the circuit opens again.
+    pub open_ns: i64,
+    /// Good probes needed in half open state to close the circuit.
+    pub half_open_probes: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            window_ns: 10_000_000_000,
+            min_requests: 20,
+            failure_ratio: 0.5,
+            slow_call_ns: None,
+            timeout_ns: None,
+            open_ns: 5_000_000_000,
+            half_open_probes: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// CircuitBreaker wraps a downstream system.
+/// While closed it passes requests through and watches the outcomes, when too
+/// many of them fail, time out or are slow it opens and rejects requests
+/// straight away. After a while it lets a few probes through, closing again
+/// if they succeed.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    downstream: SystemRef,
+    sink: SystemRef,
+    state: CircuitState,
+    opened_t: i64,
+    half_opened_t: i64,
+    probes_sent: usize,
+    probes_ok: usize,
+    // (t, bad) outcomes within the window
+    outcomes: VecDeque<(i64, bool)>,
+    bad: usize,
+    // sent at, a copy to respond with on timeout
+    in_flight: HashMap<u64, (i64, Option<Request>)>,
+    // timeouts, or the end of the window without a timeout
+    timeouts: DelayQueue<u64>,
+    late: LateIds,
+    unwatched: LateIds,
+    passed: Counter,
+    rejected: Counter,
+    failed: Counter,
+    timed_out: Counter,
+    transitions: Counter,
+    state_gauge: Gauge,
+    name: String,
+    sr: Option<SystemRef>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig, downstream: SystemRef, sink: SystemRef) -> Self {
+        CircuitBreaker {
+            config,
+            downstream,
+            sink,
+            state: CircuitState::Closed,
+            opened_t: 0,
+            half_opened_t: 0,
+            probes_sent: 0,
+            probes_ok: 0,
+            outcomes: VecDeque::new(),
+            bad: 0,
+            in_flight: HashMap::new(),
+            timeouts: DelayQueue::new(),
+            late: LateIds::new(),
+            unwatched: LateIds::new(),
+            passed: Counter::new(),
+            rejected: Counter::new(),
+            failed: Counter::new(),
+            timed_out: Counter::new(),
+            transitions: Counter::new(),
+            state_gauge: Gauge::new(),
+            name: String::new(),
+            sr: None,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    fn transition(&mut self, state: CircuitState, scheduler: &mut Scheduler) {
+        scheduler.annotate(
+            &self.name,
+            format!("circuit {:?} -> {:?}", self.state, state),
+        );
+        self.state = state;
+        self.transitions.inc();
+        self.state_gauge.set(match state {
+            CircuitState::Closed => 0.0,
+            CircuitState::Open => 1.0,
+            CircuitState::HalfOpen => 2.0,
+        });
+        match state {
+            CircuitState::Open => self.opened_t = scheduler.get_cur_t(),
+            CircuitState::HalfOpen => {
+                self.half_opened_t = scheduler.get_cur_t();
+                self.probes_sent = 0;
+                self.probes_ok = 0;
+            }
+            CircuitState::Closed => {
+                self.outcomes.clear();
+                self.bad = 0;
+            }
+        }
+    }
+
+    fn record(&mut self, bad: bool, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        match self.state {
+            CircuitState::Closed => {
+                self.outcomes.push_back((cur_t, bad));
+                if bad {
+                    self.bad += 1;
+                }
+                while let Some((t, old_bad)) = self.outcomes.front().cloned() {
+                    if t + self.config.window_ns > cur_t {
+                        break;
+                    }
+                    self.outcomes.pop_front();
+                    if old_bad {
+                        self.bad -= 1;
+                    }
+                }
+                if self.outcomes.len() >= self.config.min_requests
+                    && self.bad as f64 >= self.config.failure_ratio * self.outcomes.len() as f64
+                {
+                    self.transition(CircuitState::Open, scheduler);
+                }
+            }
+            CircuitState::HalfOpen => {
+                if bad {
+                    self.transition(CircuitState::Open, scheduler);
+                } else {
+                    self.probes_ok += 1;
+                    if self.probes_ok >= self.config.half_open_probes {
+                        self.transition(CircuitState::Closed, scheduler);
+                    }
+                }
+            }
+            // late responses of requests sent before opening
+            CircuitState::Open => {}
+        }
+    }
+
+    fn allow(&mut self, scheduler: &mut Scheduler) -> bool {
+        let cur_t = scheduler.get_cur_t();
+        if self.state == CircuitState::HalfOpen
+            && self.probes_sent >= self.config.half_open_probes
+            && self.half_opened_t + self.config.open_ns <= cur_t
+        {
+            // probes lost downstream, only timeouts would have answered them
+            self.transition(CircuitState::Open, scheduler);
+        }
+        if self.state == CircuitState::Open && self.opened_t + self.config.open_ns <= cur_t {
+            self.transition(CircuitState::HalfOpen, scheduler);
+        }
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                if self.probes_sent < self.config.half_open_probes {
+                    self.probes_sent += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn respond(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        let sink = request.respond_to(self.sink);
+        world.with_system(sink, |system, world| system.next(request, world, scheduler));
+    }
+}
+
+impl Sink for CircuitBreaker {
+    fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        if let Some((sent_t, _)) = self.in_flight.remove(&request.id) {
+            // response from downstream
+            let slow = self
+                .config
+                .slow_call_ns
+                .is_some_and(|slow_call_ns| cur_t - sent_t > slow_call_ns);
+            let bad = request.status != Status::Ok || slow;
+            if request.status != Status::Ok {
+                self.failed.inc();
+            }
+            self.record(bad, scheduler);
+            self.respond(request, world, scheduler);
+            return;
+        }
+        if self.late.remove(request.id) {
+            // response after the request already timed out
+            return;
+        }
+        if self.unwatched.remove(request.id) {
+            // response after the window, passed on without recording it
+            self.respond(request, world, scheduler);
+            return;
+        }
+        if !self.allow(scheduler) {
+            self.rejected.inc();
+            request.status = Status::Rejected;
+            let sink = request.respond_to(self.sink);
+            scheduler.deliver_at(cur_t, sink, request);
+            return;
+        }
+        self.passed.inc();
+        let copy = self.config.timeout_ns.map(|_| request.clone());
+        let expire_t = cur_t + self.config.timeout_ns.unwrap_or(self.config.window_ns);
+        self.timeouts.push(expire_t, request.id);
+        scheduler.schedule_at(expire_t, self.getref().unwrap());
+        self.in_flight.insert(request.id, (cur_t, copy));
+        request.reply_to.push(self.getref().unwrap());
+        world.with_system(self.downstream, |system, world| {
+            system.next(request, world, scheduler)
+        });
+    }
+}
+
+impl Emmitter for CircuitBreaker {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        while let Some(id) = self.timeouts.pop_due(scheduler.get_cur_t()) {
+            match self.in_flight.remove(&id) {
+                Some((_, Some(mut request))) => {
+                    self.timed_out.inc();
+                    self.late.insert(id);
+                    self.record(true, scheduler);
+                    request.status = Status::Timeout;
+                    self.respond(request, world, scheduler);
+                }
+                Some((_, None)) => self.unwatched.insert(id),
+                None => {}
+            }
+        }
+        None
+    }
+}
+
+impl StatEmitter for CircuitBreaker {
+    fn stats(&self) -> String {
+        format!(
+            "state {:?} passed {} rejected {} failed {} timed out {} transitions {}",
+            self.state,
+            self.passed.stats(),
+            self.rejected.stats(),
+            self.failed.stats(),
+            self.timed_out.stats(),
+            self.transitions.stats()
+        )
+    }
+}
+
+impl WorldMember for CircuitBreaker {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.passed.name = Some(name.clone() + "_passed");
+        self.rejected.name = Some(name.clone() + "_rejected");
+        self.failed.name = Some(name.clone() + "_failed");
+        self.timed_out.name = Some(name.clone() + "_timed_out");
+        self.transitions.name = Some(name.clone() + "_transitions");
+        self.state_gauge.name = Some(name.clone() + "_state");
+        self.name = name;
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::{FailureInjector, Fault};
+    use crate::systems::{Server, System};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    use rand_distr::Poisson;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            min_requests: 5,
+            open_ns: 1_000_000,
+            half_open_probes: 2,
+            ..CircuitBreakerConfig::default()
+        }
+    }
+
+    fn state(world: &mut World, breaker: SystemRef) -> CircuitState {
+        world.with_system(breaker, |system, _world| match system {
+            System::CircuitBreaker(breaker) => breaker.state(),
+            _ => unreachable!(),
+        })
+    }
+
+    /// A breaker in front of a server failing requests, with the faults
+    /// applied to the server after that.
+    fn breaker(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        config: CircuitBreakerConfig,
+        faults: Vec<(i64, Fault)>,
+    ) -> (SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let breaker = CircuitBreaker::new(config, server, end_sink);
+        let breaker = world.add(System::CircuitBreaker(breaker), "breaker".to_string());
+        let mut injector = FailureInjector::new().at(0, server, Fault::Crash { fail: true });
+        for (t, fault) in faults {
+            injector = injector.at(t, server, fault);
+        }
+        let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+        scheduler.schedule(world, injector);
+        for t in 0..5 {
+            send_at(scheduler, t, breaker, 0, None);
+        }
+        (breaker, end_sink)
+    }
+
+    #[test]
+    fn opens_probes_and_closes() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let restart = Fault::Restart {
+            cold_ns: 0,
+            factor: 1.0,
+        };
+        let (breaker, end_sink) = breaker(
+            &mut world,
+            &mut scheduler,
+            config(),
+            vec![(500_000, restart)],
+        );
+        // open, rejected straight away
+        send_at(&mut scheduler, 100_000, breaker, 0, None);
+        // half open, both probes pass
+        send_at(&mut scheduler, 1_100_000, breaker, 0, None);
+        send_at(&mut scheduler, 1_100_001, breaker, 0, None);
+        run(&mut world, &mut scheduler, 1_500_000);
+        assert_eq!(count(&mut world, end_sink, Status::Error), 5);
+        assert_eq!(count(&mut world, end_sink, Status::Rejected), 1);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 2);
+        assert_eq!(state(&mut world, breaker), CircuitState::Closed);
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (breaker, end_sink) = breaker(&mut world, &mut scheduler, config(), vec![]);
+        send_at(&mut scheduler, 1_100_000, breaker, 0, None);
+        run(&mut world, &mut scheduler, 1_500_000);
+        assert_eq!(count(&mut world, end_sink, Status::Error), 6);
+        assert_eq!(state(&mut world, breaker), CircuitState::Open);
+    }
+
+    #[test]
+    fn lost_probes_open_again_without_timeout() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let drop = Fault::Crash { fail: false };
+        let (breaker, end_sink) =
+            breaker(&mut world, &mut scheduler, config(), vec![(500_000, drop)]);
+        // half open, both probes are lost
+        send_at(&mut scheduler, 1_100_000, breaker, 0, None);
+        send_at(&mut scheduler, 1_100_001, breaker, 0, None);
+        // no probes left
+        send_at(&mut scheduler, 1_500_000, breaker, 0, None);
+        // open again once the probes went unanswered for the open time
+        send_at(&mut scheduler, 2_200_000, breaker, 0, None);
+        run(&mut world, &mut scheduler, 2_500_000);
+        assert_eq!(count(&mut world, end_sink, Status::Rejected), 2);
+        assert_eq!(state(&mut world, breaker), CircuitState::Open);
+    }
+
+    /// Requests through a breaker without a timeout and a window of 1ms, to a
+    /// server taking 2ms per request or dropping them.
+    fn unwatched(dropping: bool, check: impl FnOnce(&CircuitBreaker)) {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let mut server = Server::new(Poisson::new(2_000_000.0).unwrap(), end_sink);
+        if dropping {
+            server.inject(Fault::Crash { fail: false }, &mut scheduler);
+        }
+        let server = world.add(System::Server(server), "server".to_string());
+        let config = CircuitBreakerConfig {
+            window_ns: 1_000_000,
+            ..config()
+        };
+        let breaker = CircuitBreaker::new(config, server, end_sink);
+        let breaker = world.add(System::CircuitBreaker(breaker), "breaker".to_string());
+        for t in 0..5 {
+            send_at(&mut scheduler, t, breaker, 0, None);
+        }
+        run(&mut world, &mut scheduler, 20_000_000);
+        let ok = if dropping { 0 } else { 5 };
+        assert_eq!(count(&mut world, end_sink, Status::Ok), ok);
+        world.with_system(breaker, |system, _world| match system {
+            System::CircuitBreaker(breaker) => check(breaker),
+            _ => unreachable!(),
+        });
+    }
+
+    #[test]
+    fn responses_after_the_window_pass_unrecorded() {
+        unwatched(false, |breaker| {
+            assert!(breaker.in_flight.is_empty());
+            assert!(breaker.unwatched.is_empty());
+            assert!(breaker.outcomes.is_empty());
+        });
+    }
+
+    #[test]
+    fn lost_requests_are_not_watched_forever() {
+        unwatched(true, |breaker| {
+            assert!(breaker.in_flight.is_empty());
+            assert_eq!(breaker.unwatched.len(), 5);
+        });
+    }
+}
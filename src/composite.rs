@@ -0,0 +1,240 @@
+use crate::objects::{Request, Scheduler, World};
+use crate::systems::System;
+use crate::traits::{Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, Gauge};
+
+use std::collections::HashMap;
+
+/// Adds the members of a composite to the world, see `World::add_composite`.
+/// Members are named `composite/member`.
+pub struct CompositeBuilder<'a> {
+    world: &'a mut World,
+    name: String,
+    outputs: HashMap<String, SystemRef>,
+    pub(crate) members: Vec<(String, SystemRef)>,
+}
+
+impl<'a> CompositeBuilder<'a> {
+    pub(crate) fn new(world: &'a mut World, name: &str, outputs: &[(&str, SystemRef)]) -> Self {
+        CompositeBuilder {
+            world,
+            name: name.to_string(),
+            outputs: outputs
+                .iter()
+                .map(|(output, sink)| (output.to_string(), *sink))
+                .collect(),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, system: System, name: &str) -> SystemRef {
+        let sr = self.world.add(system, format!("{}/{}", self.name, name));
+        self.members.push((name.to_string(), sr));
+        sr
+    }
+
+    /// Adds a composite nested in this one.
+    pub fn add_composite<F: FnOnce(&mut CompositeBuilder) -> SystemRef>(
+        &mut self,
+        name: &str,
+        outputs: &[(&str, SystemRef)],
+        build: F,
+    ) -> SystemRef {
+        let sr = self
+            .world
+            .add_composite(&format!("{}/{}", self.name, name), outputs, build);
+        self.members.push((name.to_string(), sr));
+        sr
+    }
+
+    /// The system outside the composite the named output goes to.
+    pub fn output(&self, name: &str) -> SystemRef {
+        *self
+            .outputs
+            .get(name)
+            .unwrap_or_else(|| panic!("composite {} has no output {}", self.name, name))
+    }
+
+    /// The world, e.g. to place members in regions.
+    pub fn world(&mut self) -> &mut World {
+        self.world
+    }
+}
+
+/// Composite is a group of systems acting as one, e.g. a load balancer with
+/// its servers and their database. Requests sent to it go to its input member,
+/// members send on to the outputs the composite was added with.
+/// Its queue is the queue of its members, `World::stats` reports it as one
+/// system and `World::drill_down` lists the members.
+pub struct Composite {
+    input: SystemRef,
+    members: Vec<(String, SystemRef)>,
+    requests: Counter,
+    in_system: Gauge,
+    sr: Option<SystemRef>,
+}
+
+impl Composite {
+    pub(crate) fn new(input: SystemRef, members: Vec<(String, SystemRef)>) -> Self {
+        assert!(
+            members.iter().any(|(_, member)| *member == input),
+            "the input of a composite must be one of its members"
+        );
+        Composite {
+            input,
+            members,
+            requests: Counter::new(),
+            in_system: Gauge::new(),
+            sr: None,
+        }
+    }
+
+    pub fn input(&self) -> SystemRef {
+        self.input
+    }
+
+    /// Members by their name within the composite.
+    pub fn members(&self) -> &[(String, SystemRef)] {
+        &self.members
+    }
+
+    pub(crate) fn remove_member(&mut self, member: SystemRef) {
+        self.members.retain(|(_, sr)| *sr != member);
+    }
+}
+
+impl Sink for Composite {
+    fn next(&mut self, request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        self.requests.inc();
+        // members removed while the composite was being called
+        self.members.retain(|(_, member)| world.contains(*member));
+        let in_system: i64 = self
+            .members
+            .iter()
+            .map(|(_, member)| world.queue_size_of(*member))
+            .sum();
+        self.in_system.set(in_system as f64);
+        world.with_system(self.input, |system, world| {
+            system.next(request, world, scheduler)
+        });
+    }
+}
+
+impl StatEmitter for Composite {
+    fn stats(&self) -> String {
+        format!("requests {}", self.requests.stats())
+    }
+}
+
+impl WorldMember for Composite {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.requests.name = Some(name.clone() + "_requests");
+        self.in_system.name = Some(name + "_in_system");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::Status;
+    use crate::systems::{LoadBalancer, Server};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    use rand_distr::Poisson;
+
+    /// Adds a load balancer with two servers sending to the `out` output.
+    fn service(builder: &mut CompositeBuilder) -> SystemRef {
+        let servers = (1..=2)
+            .map(|i| {
+                let server = Server::new(Poisson::new(1_000.0).unwrap(), builder.output("out"));
+                builder.add(System::Server(server), &format!("server{}", i))
+            })
+            .collect();
+        builder.add(System::LoadBalancer(LoadBalancer::new(servers)), "lb")
+    }
+
+    #[test]
+    fn requests_go_through_the_input_to_the_outputs() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let composite = world.add_composite("service", &[("out", end_sink)], service);
+        for i in 0..10 {
+            send_at(&mut scheduler, i * 10_000 + 1, composite, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 10);
+        assert_eq!(world.queue_size_of(composite), 0);
+        world.with_system(composite, |system, _world| match system {
+            System::Composite(composite) => assert_eq!(composite.requests.value(), 10),
+            _ => panic!("not a composite"),
+        });
+    }
+
+    #[test]
+    fn drill_down_lists_nested_members() {
+        let mut world = World::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let region = world.add_composite("region", &[("out", end_sink)], |builder| {
+            let out = builder.output("out");
+            let db = Server::new(Poisson::new(1_000.0).unwrap(), out);
+            let db = builder.add(System::Server(db), "db");
+            builder.add_composite("service", &[("out", db)], service)
+        });
+        assert_eq!(world.members(region).len(), 2);
+        assert!(world.lookup("region/service/lb").is_some());
+        let names: Vec<(usize, String)> = world
+            .drill_down(region)
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let name = line.trim_start().split(' ').next().unwrap().to_string();
+                (line.len() - line.trim_start().len(), name)
+            })
+            .collect();
+        let expected = [
+            (2, "db"),
+            (2, "service"),
+            (4, "server1"),
+            (4, "server2"),
+            (4, "lb"),
+        ];
+        let expected: Vec<(usize, String)> = expected
+            .iter()
+            .map(|(indent, name)| (*indent, name.to_string()))
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn removed_members_leave_the_composite() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let composite = world.add_composite("service", &[("out", end_sink)], service);
+        let lb = world.lookup("service/lb").unwrap();
+        let server = world.lookup("service/server2").unwrap();
+        world.with_system(lb, |system, _world| match system {
+            System::LoadBalancer(lb) => assert!(lb.remove_sink(server)),
+            _ => panic!("not a load balancer"),
+        });
+        world.remove(server);
+        for i in 0..10 {
+            send_at(&mut scheduler, i * 10_000 + 1, composite, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 10);
+        let members: Vec<&str> = world
+            .members(composite)
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(members, vec!["server1", "lb"]);
+        assert_eq!(world.drill_down(composite).lines().count(), 3);
+    }
+}
@@ -0,0 +1,283 @@
+use crate::objects::{Request, Scheduler, Status, World};
+use crate::systems::ErrorModel;
+use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{tostring, Counter, DelayQueue, Gauge, Histogram, Meter};
+
+use rand_distr::Distribution;
+use rand_distr::Poisson;
+
+use std::collections::{HashMap, VecDeque};
+
+/// Database serves requests on a fixed pool of connections, requests wait
+/// for a free connection in fifo order.
+/// Service time is sampled from the distribution of the request kind.
+/// With row locks, requests with the same key are serialized: a request
+/// holds its connection while waiting for the lock on its key.
+/// Requests of a kind without a distribution fail with `Status::Error`.
+/// With an error model, requests time out waiting for a connection, are
+/// rejected when the pool queue is full and fail when served.
+pub struct Database {
+    distributions: Vec<Poisson<f32>>,
+    connections: usize,
+    row_locks: bool,
+    error_model: Option<ErrorModel>,
+    sink: SystemRef,
+    in_use: usize,
+    // waiting for a connection since
+    pool_queue: VecDeque<(i64, Request)>,
+    // locked keys with requests waiting for the lock since
+    locks: HashMap<u64, VecDeque<(i64, Request)>>,
+    in_service: DelayQueue<Request>,
+    meter: Meter,
+    counter: Counter,
+    unknown_kinds: Counter,
+    errors: Counter,
+    pool_wait: Histogram,
+    lock_wait: Histogram,
+    in_use_gauge: Gauge,
+    sr: Option<SystemRef>,
+}
+
+impl Database {
+    pub fn new(distributions: Vec<Poisson<f32>>, connections: usize, sink: SystemRef) -> Self {
+        assert!(!distributions.is_empty());
+        assert!(connections > 0);
+        Database {
+            distributions,
+            connections,
+            row_locks: false,
+            error_model: None,
+            sink,
+            in_use: 0,
+            pool_queue: VecDeque::new(),
+            locks: HashMap::new(),
+            in_service: DelayQueue::new(),
+            meter: Meter::new(),
+            counter: Counter::new(),
+            unknown_kinds: Counter::new(),
+            errors: Counter::new(),
+            pool_wait: Histogram::new(),
+            lock_wait: Histogram::new(),
+            in_use_gauge: Gauge::new(),
+            sr: None,
+        }
+    }
+
+    /// Requests with the same key are served one at a time.
+    pub fn with_row_locks(mut self) -> Self {
+        self.row_locks = true;
+        self
+    }
+
+    pub fn with_error_model(mut self, error_model: ErrorModel) -> Self {
+        self.error_model = Some(error_model);
+        self
+    }
+
+    /// Responds with the status without serving the request.
+    fn answer(&mut self, mut request: Request, status: Status, scheduler: &mut Scheduler) {
+        self.errors.inc();
+        request.status = status;
+        let sink = request.respond_to(self.sink);
+        // the caller may still be on the call stack
+        scheduler.deliver_at(scheduler.get_cur_t(), sink, request);
+    }
+
+    /// Request got a connection, it still might need to wait for its row lock.
+    fn connected(&mut self, request: Request, scheduler: &mut Scheduler) {
+        match request.key {
+            Some(key) if self.row_locks => match self.locks.get_mut(&key) {
+                Some(waiting) => waiting.push_back((scheduler.get_cur_t(), request)),
+                None => {
+                    self.locks.insert(key, VecDeque::new());
+                    self.lock_wait.update(0);
+                    self.serve(request, scheduler);
+                }
+            },
+            _ => self.serve(request, scheduler),
+        }
+    }
+
+    fn serve(&mut self, request: Request, scheduler: &mut Scheduler) {
+        let distribution = &self.distributions[request.kind];
+        let service_time = distribution.sample(&mut rand::thread_rng()) as i64;
+        let nt = scheduler.get_cur_t() + service_time;
+        self.meter.inc(service_time);
+        self.in_service.push(nt, request);
+        scheduler.schedule_at(nt, self.getref().unwrap());
+    }
+
+    fn release(&mut self, request: &Request, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        if let Some(key) = request.key.filter(|_| self.row_locks) {
+            let next = self.locks.get_mut(&key).unwrap().pop_front();
+            match next {
+                Some((since, next)) => {
+                    self.lock_wait.update(cur_t - since);
+                    self.serve(next, scheduler);
+                }
+                None => {
+                    self.locks.remove(&key);
+                }
+            }
+        }
+        while let Some((since, next)) = self.pool_queue.pop_front() {
+            self.pool_wait.update(cur_t - since);
+            if self
+                .error_model
+                .as_ref()
+                .is_some_and(|m| m.timed_out(since, cur_t))
+            {
+                self.answer(next, Status::Timeout, scheduler);
+                continue;
+            }
+            self.connected(next, scheduler);
+            return;
+        }
+        self.in_use -= 1;
+        self.in_use_gauge.set(self.in_use as f64);
+    }
+}
+
+impl Sink for Database {
+    fn next(&mut self, request: Request, _world: &mut World, scheduler: &mut Scheduler) {
+        self.counter.inc();
+        if request.kind >= self.distributions.len() {
+            self.unknown_kinds.inc();
+            self.answer(request, Status::Error, scheduler);
+            return;
+        }
+        if self.in_use < self.connections {
+            self.in_use += 1;
+            self.in_use_gauge.set(self.in_use as f64);
+            self.pool_wait.update(0);
+            self.connected(request, scheduler);
+        } else if self
+            .error_model
+            .as_ref()
+            .is_some_and(|m| m.rejects(self.pool_queue.len()))
+        {
+            self.answer(request, Status::Rejected, scheduler);
+        } else {
+            self.pool_queue.push_back((scheduler.get_cur_t(), request));
+        }
+    }
+}
+
+impl Emmitter for Database {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        while let Some(mut request) = self.in_service.pop_due(scheduler.get_cur_t()) {
+            self.release(&request, scheduler);
+            let queued = self.pool_queue.len();
+            if self.error_model.as_ref().is_some_and(|m| m.fails(queued)) {
+                self.errors.inc();
+                request.status = Status::Error;
+            }
+            let sink = request.respond_to(self.sink);
+            world.with_system(sink, |system, world| system.next(request, world, scheduler));
+        }
+        None
+    }
+}
+
+impl StatEmitter for Database {
+    fn stats(&self) -> String {
+        format!(
+            "meter {} in use {} pool queue {} pool wait {} lock wait {} counter {} unknown kinds {} errors {}",
+            self.meter.stats(),
+            tostring(self.in_use),
+            tostring(self.pool_queue.len()),
+            self.pool_wait.stats(),
+            self.lock_wait.stats(),
+            self.counter.stats(),
+            self.unknown_kinds.stats(),
+            self.errors.stats()
+        )
+    }
+}
+
+impl HasQueue for Database {
+    fn queue_size(&self) -> i64 {
+        // every request in use holds a connection
+        (self.pool_queue.len() + self.in_use) as i64
+    }
+}
+
+impl WorldMember for Database {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.meter.name = Some(name.clone() + "_meter");
+        self.counter.name = Some(name.clone() + "_counter");
+        self.unknown_kinds.name = Some(name.clone() + "_unknown_kinds");
+        self.errors.name = Some(name.clone() + "_errors");
+        self.pool_wait.name = Some(name.clone() + "_pool_wait");
+        self.lock_wait.name = Some(name.clone() + "_lock_wait");
+        self.in_use_gauge.name = Some(name + "_connections_in_use");
+        self.sr = Some(system_ref);
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::System;
+    use crate::testing::{count, end_sink, run, send_at};
+
+    #[test]
+    fn unknown_kind_fails() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let database = Database::new(vec![Poisson::new(1_000.0).unwrap()], 1, end_sink);
+        let database = world.add(System::Database(database), "db".to_string());
+        send_at(&mut scheduler, 0, database, 0, None);
+        send_at(&mut scheduler, 0, database, 1, None);
+        run(&mut world, &mut scheduler, 1_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 1);
+        assert_eq!(count(&mut world, end_sink, Status::Error), 1);
+    }
+
+    #[test]
+    fn error_model_times_out_and_rejects() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let error_model = ErrorModel {
+            timeout_ns: Some(1_500_000),
+            max_queue: Some(2),
+            ..ErrorModel::default()
+        };
+        let database = Database::new(vec![Poisson::new(1_000_000.0).unwrap()], 1, end_sink)
+            .with_error_model(error_model);
+        let database = world.add(System::Database(database), "db".to_string());
+        // served, served after 1ms, times out after 2ms, rejected
+        for _ in 0..4 {
+            send_at(&mut scheduler, 0, database, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 2);
+        assert_eq!(count(&mut world, end_sink, Status::Timeout), 1);
+        assert_eq!(count(&mut world, end_sink, Status::Rejected), 1);
+    }
+
+    #[test]
+    fn row_locks_serialize_a_key() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let database =
+            Database::new(vec![Poisson::new(1_000_000.0).unwrap()], 4, end_sink).with_row_locks();
+        let database = world.add(System::Database(database), "db".to_string());
+        for _ in 0..3 {
+            send_at(&mut scheduler, 0, database, 0, Some(7));
+        }
+        send_at(&mut scheduler, 0, database, 0, Some(8));
+        run(&mut world, &mut scheduler, 2_500_000);
+        // the other key ran alongside the first request of the locked one,
+        // the third request of the locked key is still waiting
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 3);
+    }
+}
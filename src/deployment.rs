@@ -0,0 +1,550 @@
+use crate::objects::{Scheduler, World};
+use crate::systems::{Server, ServerLoad, System, WarmUp};
+use crate::traits::{Emmitter, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{tostring, tostringfloat, Counter, DelayQueue, Gauge};
+
+use rand_distr::Poisson;
+
+use std::collections::HashMap;
+
+pub struct DeploymentConfig {
+    /// Servers added on top of the desired count during a rollout.
+    pub max_surge: usize,
+    /// Servers the rotation may be short of the desired count during a rollout.
+    pub max_unavailable: usize,
+    /// Time between starting a new server and it taking requests.
+    pub startup_ns: i64,
+    /// Warm-up of new servers from joining the rotation. A rollout ends once
+    /// its servers warmed up, with `WarmUp::Requests` that takes traffic.
+    pub warm_up: WarmUp,
+    /// How often drains and the rollout progress are checked.
+    pub check_interval_ns: i64,
+}
+
+impl Default for DeploymentConfig {
+    fn default() -> Self {
+        DeploymentConfig {
+            max_surge: 1,
+            max_unavailable: 0,
+            startup_ns: 10_000_000_000,
+            warm_up: WarmUp::Time {
+                factor: 2.0,
+                duration_ns: 30_000_000_000,
+            },
+            check_interval_ns: 100_000_000,
+        }
+    }
+}
+
+/// Requests completed by the servers over a period.
+#[derive(Default)]
+struct Window {
+    start_t: i64,
+    completed: i64,
+    latency_ns: i64,
+}
+
+impl Window {
+    fn mean_latency(&self) -> f64 {
+        if self.completed == 0 {
+            0.0
+        } else {
+            self.latency_ns as f64 / self.completed as f64
+        }
+    }
+}
+
+struct Rollout {
+    version: usize,
+    distribution: Poisson<f32>,
+    // servers of the previous version still in rotation
+    old: Vec<SystemRef>,
+    created: usize,
+    window: Window,
+    min_capacity: f64,
+}
+
+/// Outcome of a finished rollout.
+struct RolloutReport {
+    version: usize,
+    start_t: i64,
+    end_t: i64,
+    min_capacity: f64,
+    // mean latency since the previous rollout and during this one
+    baseline_latency: f64,
+    latency: f64,
+}
+
+/// Deployment replaces the servers behind a load balancer with servers of a
+/// new version in rolling batches, like a kubernetes deployment does.
+/// New servers start up, join the rotation and warm up with slower service
+/// times, old servers leave the rotation, drain their queue and are removed
+/// from the world. At most `max_surge` servers run beyond the desired count
+/// and at most `max_unavailable` are missing from the rotation.
+/// Capacity is the desired count worth of full speed servers in rotation,
+/// counting warming up servers by their speed. A rollout ends once every
+/// new server warmed up, its capacity dip and latency against the time
+/// before it are reported and annotated.
+/// Schedule the deployment at the start of the simulation.
+pub struct Deployment {
+    config: DeploymentConfig,
+    load_balancer: SystemRef,
+    sink: SystemRef,
+    desired: usize,
+    // in rotation
+    servers: Vec<SystemRef>,
+    starting: DelayQueue<SystemRef>,
+    draining: Vec<SystemRef>,
+    rollouts: DelayQueue<(usize, Poisson<f32>)>,
+    // version of the next rollout added, the servers it starts with are 1
+    next_version: usize,
+    active: Option<Rollout>,
+    last_loads: HashMap<SystemRef, ServerLoad>,
+    baseline: Window,
+    reports: Vec<RolloutReport>,
+    next_check_t: i64,
+    replaced: Counter,
+    capacity: Gauge,
+    latency: Gauge,
+    name: String,
+    sr: Option<SystemRef>,
+}
+
+impl Deployment {
+    /// `servers` are behind the load balancer, new servers send to the sink.
+    pub fn new(
+        config: DeploymentConfig,
+        load_balancer: SystemRef,
+        servers: Vec<SystemRef>,
+        sink: SystemRef,
+    ) -> Self {
+        assert!(!servers.is_empty());
+        assert!(config.max_surge + config.max_unavailable > 0);
+        Deployment {
+            config,
+            load_balancer,
+            sink,
+            desired: servers.len(),
+            servers,
+            starting: DelayQueue::new(),
+            draining: Vec::new(),
+            rollouts: DelayQueue::new(),
+            next_version: 2,
+            active: None,
+            last_loads: HashMap::new(),
+            baseline: Window::default(),
+            reports: Vec::new(),
+            next_check_t: 0,
+            replaced: Counter::new(),
+            capacity: Gauge::new(),
+            latency: Gauge::new(),
+            name: String::new(),
+            sr: None,
+        }
+    }
+
+    /// Rolls out servers with the service time distribution from `t`, after
+    /// the rollouts before it finished.
+    pub fn with_rollout_at(mut self, t: i64, distribution: Poisson<f32>) -> Self {
+        self.rollouts.push(t, (self.next_version, distribution));
+        self.next_version += 1;
+        self
+    }
+
+    /// Adds what the servers completed since the last check to the windows.
+    fn observe(&mut self, world: &mut World, t: i64) -> Window {
+        let mut delta = Window::default();
+        for server in self.servers.iter().chain(&self.draining) {
+            let load = world.with_system(*server, |system, _world| match system {
+                System::Server(server) => server.load(t),
+                _ => panic!("deployment can only replace servers"),
+            });
+            let last = self.last_loads.get(server).cloned().unwrap_or_default();
+            delta.completed += load.completed - last.completed;
+            delta.latency_ns += load.latency_ns - last.latency_ns;
+            self.last_loads.insert(*server, load);
+        }
+        let window = match self.active.as_mut() {
+            Some(rollout) => &mut rollout.window,
+            None => &mut self.baseline,
+        };
+        window.completed += delta.completed;
+        window.latency_ns += delta.latency_ns;
+        delta
+    }
+
+    fn warm_up_factor(world: &mut World, server: SystemRef, cur_t: i64) -> f64 {
+        world.with_system(server, |system, _world| match system {
+            System::Server(server) => server.warm_up_factor(cur_t),
+            _ => panic!("deployment can only replace servers"),
+        })
+    }
+
+    /// Whether every server in rotation runs at full speed.
+    fn warm(&self, world: &mut World, cur_t: i64) -> bool {
+        self.servers
+            .iter()
+            .all(|server| Self::warm_up_factor(world, *server, cur_t) <= 1.0)
+    }
+
+    /// Full speed servers in rotation over the desired count.
+    fn effective_capacity(&self, world: &mut World, cur_t: i64) -> f64 {
+        let servers: f64 = self
+            .servers
+            .iter()
+            .map(|server| 1.0 / Self::warm_up_factor(world, *server, cur_t))
+            .sum();
+        servers / self.desired as f64
+    }
+
+    fn start(
+        &mut self,
+        version: usize,
+        distribution: Poisson<f32>,
+        world: &mut World,
+        scheduler: &mut Scheduler,
+    ) {
+        let cur_t = scheduler.get_cur_t();
+        scheduler.annotate(
+            &self.name,
+            format!(
+                "rolling out version {} to {} servers",
+                version, self.desired
+            ),
+        );
+        self.active = Some(Rollout {
+            version,
+            distribution,
+            old: self.servers.clone(),
+            created: 0,
+            window: Window {
+                start_t: cur_t,
+                ..Window::default()
+            },
+            min_capacity: self.effective_capacity(world, cur_t),
+        });
+    }
+
+    /// Starts new servers and takes old ones out of rotation as far as the
+    /// surge and unavailability limits allow.
+    fn step(&mut self, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let rollout = self.active.as_mut().unwrap();
+        let mut running = self.servers.len() + self.starting.len() + self.draining.len();
+        while rollout.created < self.desired && running < self.desired + self.config.max_surge {
+            rollout.created += 1;
+            running += 1;
+            let server =
+                Server::new(rollout.distribution, self.sink).with_warm_up(self.config.warm_up);
+            let server_ref = world.add(
+                System::Server(server),
+                format!(
+                    "{}_v{}_server{}",
+                    self.name, rollout.version, rollout.created
+                ),
+            );
+            let ready_t = cur_t + self.config.startup_ns;
+            self.starting.push(ready_t, server_ref);
+            scheduler.schedule_at(ready_t, self.sr.unwrap());
+        }
+        let min_available = self.desired.saturating_sub(self.config.max_unavailable);
+        while !rollout.old.is_empty() && self.servers.len() > min_available {
+            let server = rollout.old[0];
+            let removed = world.with_system(self.load_balancer, |system, _world| match system {
+                System::LoadBalancer(lb) => lb.remove_sink(server),
+                _ => panic!("deployment needs a load balancer"),
+            });
+            if !removed {
+                break;
+            }
+            rollout.old.remove(0);
+            self.servers.retain(|s| *s != server);
+            self.draining.push(server);
+        }
+    }
+
+    /// Removes old servers which finished their queue from the world.
+    fn drain(&mut self, world: &mut World) {
+        let mut drained = Vec::new();
+        self.draining.retain(|server| {
+            let done = world.queue_size_of(*server) == 0;
+            if done {
+                drained.push(*server);
+            }
+            !done
+        });
+        for server in drained {
+            self.last_loads.remove(&server);
+            world.remove(server);
+            self.replaced.inc();
+        }
+    }
+
+    fn finish(&mut self, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let rollout = self.active.take().unwrap();
+        let report = RolloutReport {
+            version: rollout.version,
+            start_t: rollout.window.start_t,
+            end_t: cur_t,
+            min_capacity: rollout.min_capacity,
+            baseline_latency: self.baseline.mean_latency(),
+            latency: rollout.window.mean_latency(),
+        };
+        scheduler.annotate(
+            &self.name,
+            format!("version {} rolled out, {}", report.version, report.stats()),
+        );
+        self.reports.push(report);
+        self.baseline = Window {
+            start_t: cur_t,
+            ..Window::default()
+        };
+    }
+}
+
+impl RolloutReport {
+    fn stats(&self) -> String {
+        let impact = if self.baseline_latency > 0.0 {
+            self.latency / self.baseline_latency - 1.0
+        } else {
+            0.0
+        };
+        format!(
+            "took {} min capacity {} latency {} baseline {} impact {}%",
+            tostring(self.end_t - self.start_t),
+            tostringfloat(self.min_capacity),
+            tostringfloat(self.latency),
+            tostringfloat(self.baseline_latency),
+            tostringfloat(impact * 100.0)
+        )
+    }
+}
+
+impl Emmitter for Deployment {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        let cur_t = scheduler.get_cur_t();
+        while let Some(server) = self.starting.pop_due(cur_t) {
+            world.with_system(self.load_balancer, |system, _world| match system {
+                System::LoadBalancer(lb) => lb.add_sink(server),
+                _ => panic!("deployment needs a load balancer"),
+            });
+            world.with_system(server, |system, _world| match system {
+                System::Server(server) => server.start(cur_t),
+                _ => unreachable!(),
+            });
+            self.servers.push(server);
+        }
+        let checked = cur_t >= self.next_check_t;
+        if checked {
+            let delta = self.observe(world, cur_t);
+            self.latency.set(delta.mean_latency());
+            self.drain(world);
+            if self.active.is_none() {
+                if let Some((version, distribution)) = self.rollouts.pop_due(cur_t) {
+                    self.start(version, distribution, world, scheduler);
+                }
+            }
+        }
+        if self.active.is_some() {
+            self.step(world, scheduler);
+        }
+        let capacity = self.effective_capacity(world, cur_t);
+        self.capacity.set(capacity);
+        if let Some(rollout) = self.active.as_mut() {
+            rollout.min_capacity = rollout.min_capacity.min(capacity);
+            let replaced = rollout.old.is_empty()
+                && rollout.created == self.desired
+                && self.starting.is_empty()
+                && self.draining.is_empty();
+            if replaced && self.warm(world, cur_t) {
+                self.finish(scheduler);
+            }
+        }
+        if !checked {
+            return None;
+        }
+        self.next_check_t = cur_t + self.config.check_interval_ns;
+        if self.active.is_none() {
+            if let Some(t) = self.rollouts.peek_t() {
+                self.next_check_t = self.next_check_t.min(t.max(cur_t + 1));
+            }
+        }
+        Some(self.next_check_t)
+    }
+}
+
+impl StatEmitter for Deployment {
+    fn stats(&self) -> String {
+        let reports: Vec<String> = self
+            .reports
+            .iter()
+            .map(|r| format!("v{} {}", r.version, r.stats()))
+            .collect();
+        format!(
+            "servers {} starting {} draining {} replaced {} capacity {} rollouts [{}]",
+            tostring(self.servers.len()),
+            tostring(self.starting.len()),
+            tostring(self.draining.len()),
+            self.replaced.stats(),
+            self.capacity.stats(),
+            reports.join(", ")
+        )
+    }
+}
+
+impl WorldMember for Deployment {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.replaced.name = Some(name.clone() + "_replaced");
+        self.capacity.name = Some(name.clone() + "_capacity");
+        self.latency.name = Some(name.clone() + "_latency");
+        self.name = name;
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::Status;
+    use crate::systems::LoadBalancer;
+    use crate::testing::{count, end_sink, run, send_at};
+
+    fn config(warm_up: WarmUp) -> DeploymentConfig {
+        DeploymentConfig {
+            startup_ns: 100_000,
+            warm_up,
+            check_interval_ns: 10_000,
+            ..DeploymentConfig::default()
+        }
+    }
+
+    /// Two servers behind a load balancer taking a request every 10us for
+    /// 5ms, rolled out at 0 and to servers twice as slow at 2ms. Returns the
+    /// deployment and the end sink.
+    fn deploy(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        config: DeploymentConfig,
+    ) -> (SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let servers: Vec<SystemRef> = (1..=2)
+            .map(|i| {
+                let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+                world.add(System::Server(server), format!("server{}", i))
+            })
+            .collect();
+        let lb = world.add(
+            System::LoadBalancer(LoadBalancer::new(servers.clone())),
+            "lb".to_string(),
+        );
+        let deployment = Deployment::new(config, lb, servers, end_sink)
+            .with_rollout_at(0, Poisson::new(1_000.0).unwrap())
+            .with_rollout_at(2_000_000, Poisson::new(2_000.0).unwrap());
+        let deployment = world.add(System::Deployment(deployment), "deployment".to_string());
+        scheduler.schedule(world, deployment);
+        for i in 0..500 {
+            send_at(scheduler, i * 10_000 + 1, lb, 0, None);
+        }
+        run(world, scheduler, 10_000_000);
+        (deployment, end_sink)
+    }
+
+    fn with_deployment<R, F: FnOnce(&Deployment) -> R>(
+        world: &mut World,
+        deployment: SystemRef,
+        f: F,
+    ) -> R {
+        world.with_system(deployment, |system, _world| match system {
+            System::Deployment(deployment) => f(deployment),
+            _ => panic!("not a deployment"),
+        })
+    }
+
+    #[test]
+    fn rollouts_replace_every_server_in_turn() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let warm_up = WarmUp::Time {
+            factor: 2.0,
+            duration_ns: 100_000,
+        };
+        let (deployment, end_sink) = deploy(&mut world, &mut scheduler, config(warm_up));
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 500);
+        let servers = with_deployment(&mut world, deployment, |deployment| {
+            let versions: Vec<usize> = deployment.reports.iter().map(|r| r.version).collect();
+            assert_eq!(versions, vec![2, 3]);
+            // warming up servers take the place of warm ones
+            assert!(deployment.reports[0].min_capacity < 1.0);
+            // the slower version shows up in the latency of its rollout
+            let report = &deployment.reports[1];
+            assert!(
+                report.latency > 1.2 * report.baseline_latency,
+                "{}",
+                report.stats()
+            );
+            assert!(deployment.active.is_none());
+            assert_eq!(deployment.replaced.value(), 4);
+            deployment.servers.clone()
+        });
+        let names: Vec<&str> = servers.iter().map(|s| world.name(*s)).collect();
+        assert_eq!(
+            names,
+            vec!["deployment_v3_server1", "deployment_v3_server2"]
+        );
+        assert!(world.lookup("server1").is_none());
+    }
+
+    #[test]
+    fn servers_warm_up_by_requests() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let warm_up = WarmUp::Requests {
+            factor: 2.0,
+            requests: 20,
+        };
+        let (deployment, end_sink) = deploy(&mut world, &mut scheduler, config(warm_up));
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 500);
+        with_deployment(&mut world, deployment, |deployment| {
+            let versions: Vec<usize> = deployment.reports.iter().map(|r| r.version).collect();
+            assert_eq!(versions, vec![2, 3]);
+            // a new server gets a request every 20us, so 400us to warm up
+            let report = &deployment.reports[1];
+            assert!(
+                report.end_t - report.start_t >= 400_000,
+                "{}",
+                report.stats()
+            );
+        });
+    }
+
+    /// Minimum capacity of the first rollout of servers without a warm-up.
+    fn min_capacity(max_surge: usize, max_unavailable: usize) -> f64 {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let warm_up = WarmUp::Time {
+            factor: 1.0,
+            duration_ns: 1,
+        };
+        let config = DeploymentConfig {
+            max_surge,
+            max_unavailable,
+            ..config(warm_up)
+        };
+        let (deployment, _) = deploy(&mut world, &mut scheduler, config);
+        with_deployment(&mut world, deployment, |deployment| {
+            deployment.reports[0].min_capacity
+        })
+    }
+
+    #[test]
+    fn unavailable_servers_dip_the_capacity() {
+        assert_eq!(min_capacity(1, 0), 1.0);
+        // one of the two servers is out of the rotation at a time
+        assert_eq!(min_capacity(0, 1), 0.5);
+    }
+}
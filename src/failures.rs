@@ -0,0 +1,269 @@
+use crate::objects::{Scheduler, World};
+use crate::systems::System;
+use crate::traits::{Emmitter, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, DelayQueue};
+
+use rand_distr::Distribution;
+use rand_distr::Exp;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Fault {
+    /// Server stops, requests queued or arriving while it is down are
+    /// dropped, or failed back to the sender when `fail` is set.
+    /// Dropped requests are never answered, systems waiting for them need a
+    /// timeout of their own.
+    Crash { fail: bool },
+    /// Service time is multiplied by the factor, 1.0 ends the brownout.
+    Brownout(f64),
+    /// Requests to the server or over the link and their responses are
+    /// lost, and like dropped requests never answered.
+    Partition,
+    /// Ends a partition.
+    Heal,
+    /// Server starts again after a crash and warms up again. With `cold_ns`
+    /// it warms up from `factor` over that time like `WarmUp::Time`, instead
+    /// of with its own warm-up, if any.
+    Restart { cold_ns: i64, factor: f64 },
+}
+
+/// Crashes at random with exponentially distributed time between failures
+/// and time to recover.
+struct RandomCrashes {
+    target: SystemRef,
+    mtbf: Exp<f64>,
+    mttr: Exp<f64>,
+    fail: bool,
+    cold_ns: i64,
+    cold_factor: f64,
+}
+
+struct FaultEvent {
+    target: SystemRef,
+    fault: Fault,
+    // index of the random crashes which caused the event
+    random: Option<usize>,
+}
+
+/// FailureInjector applies a timeline of faults to servers, links can be
+/// partitioned and healed.
+/// Schedule it at the start of the simulation, every fault is reported as
+/// an annotation and counted.
+pub struct FailureInjector {
+    timeline: DelayQueue<FaultEvent>,
+    random: Vec<RandomCrashes>,
+    faults: Counter,
+    name: String,
+    sr: Option<SystemRef>,
+}
+
+impl Default for FailureInjector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FailureInjector {
+    pub fn new() -> Self {
+        FailureInjector {
+            timeline: DelayQueue::new(),
+            random: Vec::new(),
+            faults: Counter::new(),
+            name: String::new(),
+            sr: None,
+        }
+    }
+
+    /// Applies the fault to the target at simulation time `t`.
+    pub fn at(mut self, t: i64, target: SystemRef, fault: Fault) -> Self {
+        self.timeline.push(
+            t,
+            FaultEvent {
+                target,
+                fault,
+                random: None,
+            },
+        );
+        self
+    }
+
+    /// Crashes the target after mean `mtbf_ns` of uptime and restarts it after
+    /// mean `mttr_ns`, with a cold start as in `Fault::Restart`.
+    pub fn random_crashes(
+        mut self,
+        target: SystemRef,
+        mtbf_ns: f64,
+        mttr_ns: f64,
+        fail: bool,
+        cold_ns: i64,
+        cold_factor: f64,
+    ) -> Self {
+        let random = RandomCrashes {
+            target,
+            mtbf: Exp::new(1.0 / mtbf_ns).unwrap(),
+            mttr: Exp::new(1.0 / mttr_ns).unwrap(),
+            fail,
+            cold_ns,
+            cold_factor,
+        };
+        let t = random.mtbf.sample(&mut rand::thread_rng()) as i64;
+        self.timeline.push(
+            t,
+            FaultEvent {
+                target,
+                fault: Fault::Crash { fail },
+                random: Some(self.random.len()),
+            },
+        );
+        self.random.push(random);
+        self
+    }
+
+    /// Schedules the next event of random crashes after `fault` happened.
+    fn next_random(&mut self, index: usize, fault: Fault, cur_t: i64) {
+        let random = &self.random[index];
+        let mut rng = rand::thread_rng();
+        let (after, fault) = match fault {
+            Fault::Crash { .. } => (
+                random.mttr.sample(&mut rng),
+                Fault::Restart {
+                    cold_ns: random.cold_ns,
+                    factor: random.cold_factor,
+                },
+            ),
+            _ => (
+                random.mtbf.sample(&mut rng),
+                Fault::Crash { fail: random.fail },
+            ),
+        };
+        let target = random.target;
+        self.timeline.push(
+            cur_t + after as i64,
+            FaultEvent {
+                target,
+                fault,
+                random: Some(index),
+            },
+        );
+    }
+}
+
+impl Emmitter for FailureInjector {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        let cur_t = scheduler.get_cur_t();
+        while let Some(event) = self.timeline.pop_due(cur_t) {
+            world.with_system(event.target, |system, _world| match system {
+                System::Server(server) => server.inject(event.fault, scheduler),
+                System::Link(link) => link.inject(event.fault),
+                _ => panic!("faults can only be injected into servers and links"),
+            });
+            self.faults.inc();
+            scheduler.annotate(
+                &self.name,
+                format!("{:?} system {}", event.fault, event.target),
+            );
+            if let Some(index) = event.random {
+                self.next_random(index, event.fault, cur_t);
+            }
+        }
+        self.timeline.peek_t()
+    }
+}
+
+impl StatEmitter for FailureInjector {
+    fn stats(&self) -> String {
+        format!("faults {}", self.faults.stats())
+    }
+}
+
+impl WorldMember for FailureInjector {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.faults.name = Some(name.clone() + "_faults");
+        self.name = name;
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::Status;
+    use crate::systems::{Server, WarmUp};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    use rand_distr::Poisson;
+
+    /// Sends a request to a server every 10us for 1ms with the faults
+    /// applied, returns the outcomes.
+    fn outcomes(faults: Vec<(i64, Fault)>) -> Vec<i64> {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let mut injector = FailureInjector::new();
+        for (t, fault) in faults {
+            injector = injector.at(t, server, fault);
+        }
+        let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+        scheduler.schedule(&mut world, injector);
+        for i in 0..100 {
+            send_at(&mut scheduler, i * 10_000 + 1, server, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        Status::ALL
+            .iter()
+            .map(|status| count(&mut world, end_sink, *status))
+            .collect()
+    }
+
+    #[test]
+    fn crash_fails_or_drops_until_restart() {
+        let restart = Fault::Restart {
+            cold_ns: 0,
+            factor: 1.0,
+        };
+        let faults = vec![(500_000, Fault::Crash { fail: true }), (800_000, restart)];
+        assert_eq!(outcomes(faults), vec![70, 30, 0, 0]);
+        let faults = vec![(500_000, Fault::Crash { fail: false }), (800_000, restart)];
+        assert_eq!(outcomes(faults), vec![70, 0, 0, 0]);
+    }
+
+    #[test]
+    fn partition_loses_requests_until_healed() {
+        let faults = vec![(200_000, Fault::Partition), (300_000, Fault::Heal)];
+        assert_eq!(outcomes(faults), vec![90, 0, 0, 0]);
+    }
+
+    #[test]
+    fn restart_warms_up_from_the_cold_factor() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let warm_up = WarmUp::Time {
+            factor: 2.0,
+            duration_ns: 1_000_000,
+        };
+        let mut server =
+            Server::new(Poisson::new(1_000.0).unwrap(), end_sink).with_warm_up(warm_up);
+        let cold = Fault::Restart {
+            cold_ns: 100_000,
+            factor: 4.0,
+        };
+        server.inject(cold, &mut scheduler);
+        // the cold start replaces the warm-up rather than adding to it
+        assert_eq!(server.warm_up_factor(0), 4.0);
+        assert_eq!(server.warm_up_factor(50_000), 2.5);
+        assert_eq!(server.warm_up_factor(100_000), 1.0);
+        let restart = Fault::Restart {
+            cold_ns: 0,
+            factor: 1.0,
+        };
+        server.inject(restart, &mut scheduler);
+        assert_eq!(server.warm_up_factor(0), 2.0);
+        assert_eq!(server.warm_up_factor(1_000_000), 1.0);
+    }
+}
@@ -23,6 +23,16 @@ https://github.com/sunng87/metriki/blob/master/metriki-influxdb-reporter/src/lib
 pub struct SimulationReachedTimeEvent {
     pub time_ns: i64,
     pub stop: bool,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Something that happened to a system at a point of simulation time,
+/// reported to the events measurement so dashboards can annotate it.
+#[derive(Debug)]
+pub struct Annotation {
+    pub time_ns: i64,
+    pub system: String,
+    pub text: String,
 }
 
 #[derive(Debug)]
@@ -64,11 +74,11 @@ impl InfluxDbReporter {
             password: None,
             auth_token: env::var("AUTH_TOKEN").ok(),
             measurement_prefix: "sdm_".to_string(),
-            tags: tags,
+            tags,
             batch_size: 60,
-            event_rx: event_rx,
+            event_rx,
             last_send_time_ns: None,
-            test_start_time: test_start_time,
+            test_start_time,
             send_interval_ns: interval_secs * 1_000_000_000,
         }
     }
@@ -85,15 +95,14 @@ impl InfluxDbReporter {
     }
 
     pub fn start(mut self) {
-        let test_start_time_string: String = self.test_start_time
+        let test_start_time_string: String = self
+            .test_start_time
             .duration_since(UNIX_EPOCH)
             .ok()
             .unwrap()
             .as_secs()
             .to_string();
-        println!(
-            "influxdb: test_start_time {}", test_start_time_string
-        );
+        println!("influxdb: test_start_time {}", test_start_time_string);
         let looper = move || {
             let runtime = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
@@ -106,6 +115,15 @@ impl InfluxDbReporter {
                         if event.stop {
                             need_stop = true;
                         }
+                        if !event.annotations.is_empty() {
+                            let client = self.new_client();
+                            let queries: Vec<WriteQuery> = event
+                                .annotations
+                                .iter()
+                                .map(|annotation| self.report_annotation(annotation))
+                                .collect();
+                            self.do_query(&client, queries).await;
+                        }
                         // TODO: fix, check if interval from last reporting is larger than send interval
                         if self.last_send_time_ns.is_none()
                             || self.last_send_time_ns.unwrap() + self.send_interval_ns
@@ -141,15 +159,14 @@ impl InfluxDbReporter {
                         }
                     }
                 }
-                let test_start_time_string: String = self.test_start_time
+                let test_start_time_string: String = self
+                    .test_start_time
                     .duration_since(UNIX_EPOCH)
                     .ok()
                     .unwrap()
                     .as_secs()
                     .to_string();
-                println!(
-                    "influxdb: test_start_time {}", test_start_time_string
-                );
+                println!("influxdb: test_start_time {}", test_start_time_string);
             })
         };
         std::thread::spawn(looper);
@@ -214,6 +231,12 @@ impl InfluxDbReporter {
             .add_field("mean", snapshot.mean())
     }
 
+    fn report_annotation(&self, annotation: &Annotation) -> WriteQuery {
+        self.with_query("events", annotation.time_ns)
+            .add_tag("system", annotation.system.clone())
+            .add_field("text", annotation.text.clone())
+    }
+
     fn report_counter(&self, name: &str, c: &Counter, since_start: i64) -> WriteQuery {
         self.with_query(name, since_start)
             .add_field("value", c.value())
@@ -0,0 +1,27 @@
+pub mod autoscaler;
+pub mod batcher;
+pub mod broker;
+pub mod cache;
+pub mod callgraph;
+pub mod circuitbreaker;
+pub mod composite;
+pub mod database;
+pub mod deployment;
+pub mod failures;
+pub mod influxdbreporter;
+pub mod link;
+pub mod objects;
+pub mod quorum;
+pub mod ratelimiter;
+pub mod regions;
+pub mod replication;
+pub mod sharding;
+pub mod systems;
+pub mod traits;
+pub mod utils;
+
+#[cfg(test)]
+mod testing;
+
+extern crate derive_builder;
+extern crate rand;
@@ -0,0 +1,285 @@
+use crate::failures::Fault;
+use crate::objects::{Request, Scheduler, World};
+use crate::traits::{Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, Gauge, Histogram};
+
+use rand::Rng;
+use rand_distr::{Distribution, Poisson};
+
+use std::collections::HashSet;
+
+const UTILIZATION_WINDOW_NS: i64 = 1_000_000_000;
+
+/// One direction of a link, sends one request at a time.
+struct Direction {
+    // the link is busy serialising requests until then
+    busy_until_t: i64,
+    window_start_t: i64,
+    window_busy_ns: i64,
+    utilization: Gauge,
+}
+
+impl Direction {
+    fn new() -> Self {
+        Direction {
+            busy_until_t: 0,
+            window_start_t: 0,
+            window_busy_ns: 0,
+            utilization: Gauge::new(),
+        }
+    }
+
+    /// Reserves the link to send `serialization_ns` worth of bytes from `t`,
+    /// returns when the last byte is sent.
+    fn send(&mut self, t: i64, serialization_ns: i64) -> i64 {
+        let start_t = t.max(self.busy_until_t);
+        self.busy_until_t = start_t + serialization_ns;
+        self.window_busy_ns += serialization_ns;
+        if t >= self.window_start_t + UTILIZATION_WINDOW_NS {
+            self.utilization
+                .set(self.window_busy_ns as f64 / (t - self.window_start_t) as f64);
+            self.window_start_t = t;
+            self.window_busy_ns = 0;
+        }
+        self.busy_until_t
+    }
+}
+
+/// Link is the network between the systems sending to it and the downstream.
+/// Requests take the serialisation delay of their size at the link bandwidth,
+/// waiting for the requests sent before them, plus the propagation delay.
+/// A lost request is sent again after the retransmission timeout.
+/// Responses come back the same way and go to the sink.
+/// A partitioned link drops requests and responses sent over it, they are
+/// never answered.
+pub struct Link {
+    latency: Poisson<f32>,
+    // bytes per second
+    bandwidth: Option<f64>,
+    loss: f64,
+    retransmit_ns: i64,
+    downstream: SystemRef,
+    sink: SystemRef,
+    in_flight: HashSet<u64>,
+    partitioned: bool,
+    forward: Direction,
+    reverse: Direction,
+    sent: Counter,
+    retransmissions: Counter,
+    lost: Counter,
+    delay: Histogram,
+    sr: Option<SystemRef>,
+}
+
+impl Link {
+    /// `latency` is the one way propagation delay in ns.
+    pub fn new(latency: Poisson<f32>, downstream: SystemRef, sink: SystemRef) -> Self {
+        Link {
+            latency,
+            bandwidth: None,
+            loss: 0.0,
+            retransmit_ns: 0,
+            downstream,
+            sink,
+            in_flight: HashSet::new(),
+            partitioned: false,
+            forward: Direction::new(),
+            reverse: Direction::new(),
+            sent: Counter::new(),
+            retransmissions: Counter::new(),
+            lost: Counter::new(),
+            delay: Histogram::new(),
+            sr: None,
+        }
+    }
+
+    /// Each direction sends at most `bytes_per_second`.
+    pub fn with_bandwidth(mut self, bytes_per_second: f64) -> Self {
+        assert!(bytes_per_second > 0.0);
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    /// Requests and responses are lost with the probability and sent again
+    /// after `retransmit_ns`.
+    pub fn with_loss(mut self, probability: f64, retransmit_ns: i64) -> Self {
+        assert!((0.0..1.0).contains(&probability));
+        self.loss = probability;
+        self.retransmit_ns = retransmit_ns;
+        self
+    }
+
+    /// Links can be partitioned and healed, other faults are for servers.
+    pub fn inject(&mut self, fault: Fault) {
+        match fault {
+            Fault::Partition => self.partitioned = true,
+            Fault::Heal => self.partitioned = false,
+            _ => panic!("links can only be partitioned and healed, not {:?}", fault),
+        }
+    }
+
+    /// When the request sent now in the direction arrives at the other end.
+    fn arrival_t(&mut self, request: &Request, cur_t: i64, forward: bool) -> i64 {
+        let serialization_ns = self.bandwidth.map_or(0, |bandwidth| {
+            (request.size as f64 * 1_000_000_000.0 / bandwidth) as i64
+        });
+        let direction = if forward {
+            &mut self.forward
+        } else {
+            &mut self.reverse
+        };
+        let mut rng = rand::thread_rng();
+        let mut sent_t = direction.send(cur_t, serialization_ns);
+        while rng.gen::<f64>() < self.loss {
+            // resends use the bandwidth but do not hold up later requests
+            self.retransmissions.inc();
+            direction.window_busy_ns += serialization_ns;
+            sent_t += self.retransmit_ns + serialization_ns;
+        }
+        sent_t + self.latency.sample(&mut rng) as i64
+    }
+}
+
+impl Sink for Link {
+    fn next(&mut self, mut request: Request, _world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let forward = !self.in_flight.remove(&request.id);
+        if self.partitioned {
+            self.lost.inc();
+            return;
+        }
+        let arrival_t = self.arrival_t(&request, cur_t, forward);
+        self.sent.inc();
+        self.delay.update(arrival_t - cur_t);
+        let sink = if forward {
+            self.in_flight.insert(request.id);
+            request.reply_to.push(self.getref().unwrap());
+            self.downstream
+        } else {
+            request.respond_to(self.sink)
+        };
+        scheduler.deliver_at(arrival_t, sink, request);
+    }
+}
+
+impl StatEmitter for Link {
+    fn stats(&self) -> String {
+        format!(
+            "sent {} retransmissions {} lost {} delay {} utilization {} reverse {}",
+            self.sent.stats(),
+            self.retransmissions.stats(),
+            self.lost.stats(),
+            self.delay.stats(),
+            self.forward.utilization.stats(),
+            self.reverse.utilization.stats()
+        )
+    }
+}
+
+impl WorldMember for Link {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.sent.name = Some(name.clone() + "_sent");
+        self.retransmissions.name = Some(name.clone() + "_retransmissions");
+        self.lost.name = Some(name.clone() + "_lost");
+        self.delay.name = Some(name.clone() + "_delay");
+        self.forward.utilization.name = Some(name.clone() + "_utilization");
+        self.reverse.utilization.name = Some(name + "_reverse_utilization");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::FailureInjector;
+    use crate::objects::Status;
+    use crate::systems::{Server, System};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    #[test]
+    fn requests_wait_for_the_ones_sent_before() {
+        let mut direction = Direction::new();
+        assert_eq!(direction.send(0, 100), 100);
+        assert_eq!(direction.send(0, 100), 200);
+        assert_eq!(direction.send(500, 100), 600);
+    }
+
+    #[test]
+    fn lost_requests_are_sent_again() {
+        let mut world = World::new();
+        let sink = end_sink(&mut world, "endsink");
+        let mut link =
+            Link::new(Poisson::new(1_000.0).unwrap(), sink, sink).with_loss(0.5, 1_000_000);
+        let request = Request::new(1, 0);
+        // the propagation delay is far below the retransmission timeout
+        let resends: i64 = (0..1_000)
+            .map(|_| link.arrival_t(&request, 0, true) / 1_000_000)
+            .sum();
+        let retransmissions = link.retransmissions.value();
+        assert_eq!(resends, retransmissions);
+        // one resend per request on average
+        assert!(
+            (800..1_200).contains(&retransmissions),
+            "{}",
+            retransmissions
+        );
+    }
+
+    #[test]
+    fn responses_come_back_over_the_link() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let link = Link::new(Poisson::new(10_000.0).unwrap(), server, end_sink)
+            .with_bandwidth(1_000_000_000.0);
+        let link = world.add(System::Link(link), "link".to_string());
+        for i in 0..10 {
+            send_at(&mut scheduler, i * 100_000 + 1, link, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 10);
+        world.with_system(link, |system, _world| match system {
+            System::Link(link) => {
+                assert_eq!(link.sent.value(), 20);
+                assert!(link.in_flight.is_empty());
+            }
+            _ => panic!("not a link"),
+        });
+    }
+
+    #[test]
+    fn partitioned_link_drops_both_ways() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let link = Link::new(Poisson::new(10_000.0).unwrap(), server, end_sink);
+        let link = world.add(System::Link(link), "link".to_string());
+        let injector = FailureInjector::new()
+            .at(300_000, link, Fault::Partition)
+            .at(600_000, link, Fault::Heal);
+        let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+        scheduler.schedule(&mut world, injector);
+        for i in 0..10 {
+            send_at(&mut scheduler, i * 100_000 + 1, link, 0, None);
+        }
+        // reaches the server before the partition, its response is lost
+        send_at(&mut scheduler, 295_000, link, 0, None);
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 7);
+        world.with_system(link, |system, _world| match system {
+            System::Link(link) => {
+                assert_eq!(link.lost.value(), 4);
+                assert!(link.in_flight.is_empty());
+            }
+            _ => panic!("not a link"),
+        });
+    }
+}
@@ -1,16 +1,7 @@
-pub mod influxdbreporter;
-pub mod objects;
-pub mod systems;
-pub mod traits;
-pub mod utils;
-
-extern crate derive_builder;
-extern crate rand;
-
-use crate::objects::{Scheduler, World};
-use crate::systems::{ArrivalSource, EndSink, LoadBalancer, Server, System};
-use crate::traits::{HasQueue, StatEmitter};
-use crate::utils::tostring;
+use system_design_model_rust::objects::{Scheduler, World};
+use system_design_model_rust::systems::{ArrivalSource, EndSink, LoadBalancer, Server, System};
+use system_design_model_rust::traits::{HasQueue, StatEmitter};
+use system_design_model_rust::utils::tostring;
 
 use rand_distr::Poisson;
 
@@ -1,43 +1,430 @@
+use crate::composite::{Composite, CompositeBuilder};
+use crate::regions::{Location, Topology};
 use crate::systems::System;
 use crate::traits::{HasQueue, StatEmitter, SystemRef, WorldMember};
 
 use crate::influxdbreporter::InfluxDbReporter;
+use crate::utils::tostring;
+
+use rand::Rng;
+use rand_distr::{Distribution, Zipf};
+
+/// Outcome of a request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Ok,
+    Error,
+    Timeout,
+    Rejected,
+}
+
+impl Status {
+    pub const ALL: [Status; 4] = [Status::Ok, Status::Error, Status::Timeout, Status::Rejected];
+
+    /// Position of the status in `ALL`.
+    pub fn index(&self) -> usize {
+        match self {
+            Status::Ok => 0,
+            Status::Error => 1,
+            Status::Timeout => 2,
+            Status::Rejected => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Error => "error",
+            Status::Timeout => "timeout",
+            Status::Rejected => "rejected",
+        }
+    }
+}
+
+/// Request travelling between systems.
+/// `reply_to` is a stack of systems waiting for the response: the system
+/// serving the request pops the top and responds there instead of its sink.
+#[derive(Clone)]
+pub struct Request {
+    pub id: u64,
+    pub created_t: i64,
+    pub key: Option<u64>,
+    /// Request type, e.g. query type, systems may pick behaviour by it.
+    pub kind: usize,
+    pub status: Status,
+    /// Number of requests this request carries when it is a batch.
+    pub batch_size: usize,
+    /// Bytes sent over network links, for the request and for its response.
+    pub size: u64,
+    /// Where the request is, set once it reaches a system placed in a region.
+    pub location: Option<Location>,
+    /// Time spent on hops between regions.
+    pub cross_region_ns: i64,
+    pub reply_to: Vec<SystemRef>,
+}
+
+impl Request {
+    pub fn new(id: u64, created_t: i64) -> Self {
+        Request {
+            id,
+            created_t,
+            key: None,
+            kind: 0,
+            status: Status::Ok,
+            batch_size: 1,
+            size: 0,
+            location: None,
+            cross_region_ns: 0,
+            reply_to: Vec::new(),
+        }
+    }
+
+    /// Where the response goes: the last system waiting for it or the default sink.
+    pub fn respond_to(&mut self, sink: SystemRef) -> SystemRef {
+        self.reply_to.pop().unwrap_or(sink)
+    }
+}
+
+/// Key space requests draw their keys from, keys are in 1..=n.
+pub enum KeySpace {
+    Uniform(u64),
+    Zipf(Zipf<f64>),
+    /// One of the hot keys with probability `share`, otherwise a key of the rest.
+    Hot {
+        keys: Vec<u64>,
+        share: f64,
+        rest: Box<KeySpace>,
+    },
+}
+
+impl KeySpace {
+    pub fn uniform(n: u64) -> Self {
+        assert!(n > 0);
+        KeySpace::Uniform(n)
+    }
+
+    /// Zipf distributed keys, key 1 is the most popular, `s` is the skew.
+    pub fn zipf(n: u64, s: f64) -> Self {
+        KeySpace::Zipf(Zipf::new(n, s).unwrap())
+    }
+
+    /// Makes `share` of the keys one of the hot keys, picked uniformly.
+    pub fn with_hot_keys(self, keys: Vec<u64>, share: f64) -> Self {
+        assert!(!keys.is_empty());
+        assert!((0.0..=1.0).contains(&share));
+        KeySpace::Hot {
+            keys,
+            share,
+            rest: Box::new(self),
+        }
+    }
+
+    pub fn sample(&self) -> u64 {
+        let mut rng = rand::thread_rng();
+        match self {
+            KeySpace::Uniform(n) => rng.gen_range(1..=*n),
+            KeySpace::Zipf(zipf) => zipf.sample(&mut rng) as u64,
+            KeySpace::Hot { keys, share, rest } => {
+                if rng.gen::<f64>() < *share {
+                    keys[rng.gen_range(0..keys.len())]
+                } else {
+                    rest.sample()
+                }
+            }
+        }
+    }
+}
 
 pub struct World {
     systems: Vec<System>,
+    // generation of the system in each place, see `SystemRef`
+    generations: Vec<u32>,
+    // places of removed systems, reused by the next systems added
+    free: Vec<usize>,
+    // name of each system, e.g. `region1/api/server3` for members of
+    // composites, None for removed systems
+    names: Vec<Option<String>>,
+    by_name: HashMap<String, SystemRef>,
+    // members of each composite, known even while it is being called
+    composites: HashMap<SystemRef, Vec<SystemRef>>,
+    topology: Option<Topology>,
+    locations: HashMap<SystemRef, Location>,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl World {
     pub fn new() -> Self {
         World {
             systems: Vec::new(),
+            generations: Vec::new(),
+            free: Vec::new(),
+            names: Vec::new(),
+            by_name: HashMap::new(),
+            composites: HashMap::new(),
+            topology: None,
+            locations: HashMap::new(),
+        }
+    }
+
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = Some(topology);
+    }
+
+    pub fn topology(&self) -> Option<&Topology> {
+        self.topology.as_ref()
+    }
+
+    /// Places the system in a zone of a region of the topology.
+    pub fn place(&mut self, system_ref: SystemRef, region: &str, zone: usize) {
+        let region = self
+            .topology
+            .as_ref()
+            .expect("set the topology before placing systems")
+            .region(region);
+        self.locations.insert(system_ref, Location { region, zone });
+    }
+
+    pub fn location(&self, system_ref: SystemRef) -> Option<Location> {
+        self.locations.get(&system_ref).copied()
+    }
+
+    /// Moves the request to the location of the system, returns the latency
+    /// of the hop when the request leaves its zone.
+    pub fn hop(&mut self, system_ref: SystemRef, request: &mut Request) -> Option<i64> {
+        let to = self.location(system_ref)?;
+        match self.topology.as_mut() {
+            Some(topology) => topology.hop(request, to),
+            None => None,
         }
     }
+
+    /// Adds the system under the name, made unique with a `_2`, `_3`...
+    /// suffix when another system has it, see `name` for the one it got.
+    /// Names of members of composites start with the name of the composite
+    /// and a `/`. Systems may be added while the simulation runs.
     pub fn add(&mut self, system: System, name: String) -> SystemRef {
-        let sr = self.systems.len();
-        self.systems.push(system);
+        let name = self.unique_name(&name);
+        self.insert(system, name)
+    }
+
+    /// Adds the system under the name, returns None without adding it when
+    /// another system has the name.
+    pub fn try_add(&mut self, system: System, name: String) -> Option<SystemRef> {
+        if self.by_name.contains_key(&name) {
+            return None;
+        }
+        Some(self.insert(system, name))
+    }
+
+    fn unique_name(&self, name: &str) -> String {
+        let mut unique = name.to_string();
+        let mut n = 1;
+        while self.by_name.contains_key(&unique) {
+            n += 1;
+            unique = format!("{}_{}", name, n);
+        }
+        unique
+    }
+
+    fn insert(&mut self, system: System, name: String) -> SystemRef {
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.systems[index] = system;
+                self.names[index] = Some(name.clone());
+                index
+            }
+            None => {
+                self.systems.push(system);
+                self.generations.push(0);
+                self.names.push(Some(name.clone()));
+                self.systems.len() - 1
+            }
+        };
+        let sr = SystemRef::new(index, self.generations[index]);
+        self.by_name.insert(name.clone(), sr);
         self.with_system(sr, |system, _world| system.add(sr, name));
         sr
     }
+
+    /// Removes the system, with the members of a composite, from the world.
+    /// A member of a composite leaves the composite. References to it are
+    /// stale from now on, events the scheduler still has
+    /// for it are handled by its `RemovedPolicy`. A system may remove itself,
+    /// it is dropped once its call returns. Returns false for stale references.
+    pub fn remove(&mut self, system_ref: SystemRef) -> bool {
+        if !self.contains(system_ref) {
+            return false;
+        }
+        let index = system_ref.index();
+        self.systems[index] = System::Unset;
+        for member in self.composites.remove(&system_ref).unwrap_or_default() {
+            self.remove(member);
+        }
+        let parent = self.composites.iter_mut().find_map(|(composite, members)| {
+            let len = members.len();
+            members.retain(|member| *member != system_ref);
+            (members.len() < len).then_some(*composite)
+        });
+        // a composite being called drops it on its next call
+        if let Some(System::Composite(composite)) = parent.map(|p| &mut self.systems[p.index()]) {
+            composite.remove_member(system_ref);
+        }
+        let name = self.names[index].take().unwrap();
+        self.by_name.remove(&name);
+        self.locations.remove(&system_ref);
+        self.generations[index] += 1;
+        self.free.push(index);
+        true
+    }
+
+    /// Whether the system is in the world, false once it has been removed.
+    pub fn contains(&self, system_ref: SystemRef) -> bool {
+        let index = system_ref.index();
+        index < self.systems.len()
+            && self.generations[index] == system_ref.generation()
+            && self.names[index].is_some()
+    }
+
+    fn get(&self, system_ref: SystemRef) -> &System {
+        assert!(self.contains(system_ref), "stale system {}", system_ref);
+        &self.systems[system_ref.index()]
+    }
+
+    pub fn name(&self, system_ref: SystemRef) -> &str {
+        assert!(self.contains(system_ref), "stale system {}", system_ref);
+        self.names[system_ref.index()].as_deref().unwrap()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<SystemRef> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Systems whose name matches the pattern, in the order they were added.
+    /// `*` matches within one level of the name, `**` across levels and `?`
+    /// matches one character, e.g. `region1/*/server?` or `**/db`.
+    pub fn glob(&self, pattern: &str) -> Vec<SystemRef> {
+        let pattern = pattern.as_bytes();
+        self.systems()
+            .filter(|(_, name, _)| glob_match(pattern, name.as_bytes()))
+            .map(|(sr, _, _)| sr)
+            .collect()
+    }
+
+    /// Every system with its name and type, in the order of their places.
+    pub fn systems(&self) -> impl Iterator<Item = (SystemRef, &str, &'static str)> {
+        self.systems
+            .iter()
+            .zip(&self.names)
+            .enumerate()
+            .filter_map(|(index, (system, name))| {
+                let sr = SystemRef::new(index, self.generations[index]);
+                Some((sr, name.as_deref()?, system.type_name()))
+            })
+    }
+
+    /// Adds a composite: `build` adds its members and returns the one
+    /// requests to the composite go to. `outputs` name the systems outside
+    /// the composite its members send to. Returns the composite.
+    /// Like `add`, the name is made unique, members are named after it.
+    pub fn add_composite<F: FnOnce(&mut CompositeBuilder) -> SystemRef>(
+        &mut self,
+        name: &str,
+        outputs: &[(&str, SystemRef)],
+        build: F,
+    ) -> SystemRef {
+        let name = self.unique_name(name);
+        let mut builder = CompositeBuilder::new(self, &name, outputs);
+        let input = build(&mut builder);
+        let members = builder.members;
+        let refs = members.iter().map(|(_, member)| *member).collect();
+        let sr = self.insert(System::Composite(Composite::new(input, members)), name);
+        self.composites.insert(sr, refs);
+        sr
+    }
+
+    /// Members of the composite by their name within it, none for other systems.
+    pub fn members(&self, system_ref: SystemRef) -> &[(String, SystemRef)] {
+        match self.get(system_ref) {
+            System::Composite(composite) => composite.members(),
+            _ => &[],
+        }
+    }
+
+    /// Queue of the system, for a composite the queues of all its members.
+    pub fn queue_size_of(&self, system_ref: SystemRef) -> i64 {
+        match self.get(system_ref) {
+            System::Composite(composite) => composite
+                .members()
+                .iter()
+                .filter(|(_, member)| self.contains(*member))
+                .map(|(_, member)| self.queue_size_of(*member))
+                .sum(),
+            system => system.queue_size(),
+        }
+    }
+
+    /// Stats of the system, a composite is reported as one system.
+    pub fn stats(&self, system_ref: SystemRef) -> String {
+        match self.get(system_ref) {
+            System::Composite(composite) => format!(
+                "{} in system {}",
+                composite.stats(),
+                tostring(self.queue_size_of(system_ref))
+            ),
+            system => system.stats(),
+        }
+    }
+
+    /// Stats of the system followed by a line per member of a composite,
+    /// nested composites indented below their members.
+    pub fn drill_down(&self, system_ref: SystemRef) -> String {
+        let mut lines = vec![self.stats(system_ref)];
+        for (name, member) in self.members(system_ref) {
+            let member = self.drill_down(*member).replace('\n', "\n  ");
+            lines.push(format!("  {} {}", name, member));
+        }
+        lines.join("\n")
+    }
+
+    /// Calls `f` with the system taken out of the world, panics for a stale
+    /// reference. The system may remove itself in the call.
     pub fn with_system<R, F: FnOnce(&mut System, &mut World) -> R>(
         &mut self,
         system_ref: SystemRef,
         f: F,
     ) -> R {
-        let (mut s, mut nw) = self.split(system_ref);
-        let r = f(&mut s, &mut nw);
-        std::mem::swap(self, &mut nw);
-        self.systems[system_ref] = s;
+        assert!(self.contains(system_ref), "stale system {}", system_ref);
+        let index = system_ref.index();
+        let mut s = std::mem::replace(&mut self.systems[index], System::Unset);
+        let r = f(&mut s, self);
+        if self.contains(system_ref) {
+            self.systems[index] = s;
+        }
         r
     }
+}
 
-    fn split(&mut self, system_ref: SystemRef) -> (System, World) {
-        let mut unset = System::Unset;
-        std::mem::swap(&mut unset, self.systems.get_mut(system_ref).unwrap());
-        let mut nsystems = vec![];
-        std::mem::swap(&mut nsystems, &mut self.systems);
-        let nw = World { systems: nsystems };
-        (unset, nw)
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        [b'*', b'*', rest @ ..] => match rest.strip_prefix(b"/") {
+            // `**/` matches any number of whole levels, none included
+            Some(rest) => {
+                glob_match(rest, name)
+                    || (0..name.len()).any(|i| name[i] == b'/' && glob_match(rest, &name[i + 1..]))
+            }
+            None => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        },
+        [b'*', rest @ ..] => (0..=name.len())
+            .take_while(|i| *i == 0 || name[i - 1] != b'/')
+            .any(|i| glob_match(rest, &name[i..])),
+        [b'?', rest @ ..] => !name.is_empty() && name[0] != b'/' && glob_match(rest, &name[1..]),
+        [c, rest @ ..] => name.first() == Some(c) && glob_match(rest, &name[1..]),
     }
 }
 
@@ -58,6 +445,10 @@ struct EmitterRef {
 struct SchedulerElement {
     t: i64,
     e: EmitterRef,
+    // delivered to the system instead of ticking it
+    request: Option<Request>,
+    // set for ticks of `Scheduler::reschedule`, only the latest one fires
+    timer: Option<u64>,
 }
 impl PartialEq for SchedulerElement {
     fn eq(&self, o: &Self) -> bool {
@@ -67,7 +458,7 @@ impl PartialEq for SchedulerElement {
 impl Eq for SchedulerElement {}
 impl PartialOrd for SchedulerElement {
     fn partial_cmp(&self, o: &Self) -> Option<std::cmp::Ordering> {
-        self.t.partial_cmp(&o.t).map(|e| e.reverse())
+        Some(self.cmp(o))
     }
 }
 impl Ord for SchedulerElement {
@@ -76,16 +467,41 @@ impl Ord for SchedulerElement {
     }
 }
 
-use crate::influxdbreporter::SimulationReachedTimeEvent;
+/// What the scheduler does with events of systems removed from the world.
+pub enum RemovedPolicy {
+    /// Ticks and requests are dropped.
+    Drop,
+    /// Requests are answered with `Status::Rejected` to the system waiting for
+    /// them, ticks and requests nobody waits for are dropped.
+    Reject,
+    /// Panics, for models which never remove systems with events pending.
+    Panic,
+}
+
+use crate::influxdbreporter::{Annotation, SimulationReachedTimeEvent};
 use crate::utils::Counter;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap};
 use tokio::sync::mpsc;
 pub struct Scheduler {
     heap: BinaryHeap<SchedulerElement>,
     cur_t_ns: i64,
     executed: Counter,
+    removed_policy: RemovedPolicy,
+    // events of removed systems
+    stale: Counter,
     event_tx: mpsc::Sender<SimulationReachedTimeEvent>,
     reported_cur_t_ns: Option<i64>,
+    last_request_id: u64,
+    // latest timer of each system using reschedule
+    timers: HashMap<SystemRef, u64>,
+    last_timer: u64,
+    annotations: Vec<Annotation>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scheduler {
@@ -98,11 +514,24 @@ impl Scheduler {
             heap: binary_heap,
             cur_t_ns: 0,
             executed: Counter::new(),
+            removed_policy: RemovedPolicy::Reject,
+            stale: Counter::new(),
             event_tx: tx,
             reported_cur_t_ns: None,
+            last_request_id: 0,
+            timers: HashMap::new(),
+            last_timer: 0,
+            annotations: Vec::new(),
         }
     }
 
+    /// How events of systems removed from the world are handled, defaults
+    /// to rejecting their requests.
+    pub fn with_removed_policy(mut self, policy: RemovedPolicy) -> Self {
+        self.removed_policy = policy;
+        self
+    }
+
     pub fn schedule(&mut self, world: &mut World, emitter: SystemRef) {
         world.with_system(emitter, |system, world| {
             let nt = system.tick(self, world);
@@ -116,12 +545,53 @@ impl Scheduler {
         self.heap.push(SchedulerElement {
             t,
             e: EmitterRef { aref: emitter },
+            request: None,
+            timer: None,
+        });
+    }
+
+    /// Delivers the request to the sink at time `t`. Systems answering a caller
+    /// which is still on the call stack, e.g. failing a request as soon as it
+    /// arrives, deliver the response at the current time instead of calling
+    /// the caller back.
+    pub fn deliver_at(&mut self, t: i64, sink: SystemRef, request: Request) {
+        self.heap.push(SchedulerElement {
+            t,
+            e: EmitterRef { aref: sink },
+            request: Some(request),
+            timer: None,
+        });
+    }
+
+    /// Ticks the emitter at time `t` instead of the time of its previous
+    /// reschedule, e.g. when a completion time changes. Ticks scheduled
+    /// otherwise are not affected.
+    pub fn reschedule(&mut self, t: i64, emitter: SystemRef) {
+        self.last_timer += 1;
+        self.timers.insert(emitter, self.last_timer);
+        self.heap.push(SchedulerElement {
+            t,
+            e: EmitterRef { aref: emitter },
+            request: None,
+            timer: Some(self.last_timer),
         });
     }
 
+    /// Drops the tick of the last reschedule of the emitter.
+    pub fn cancel(&mut self, emitter: SystemRef) {
+        self.timers.remove(&emitter);
+    }
+
     pub fn execute_next(&mut self, world: &mut World, up_to_nano: i64) -> bool {
         let top = self.heap.pop();
         if let Some(top) = top {
+            if let Some(timer) = top.timer {
+                if self.timers.get(&top.e.aref) != Some(&timer) {
+                    // rescheduled or cancelled since
+                    return !self.heap.is_empty();
+                }
+                self.timers.remove(&top.e.aref);
+            }
             self.executed.inc();
             let ee = top.e;
             self.cur_t_ns = top.t;
@@ -130,14 +600,31 @@ impl Scheduler {
                 false
             } else {
                 self.reportmetrics(false);
-                let nt = world.with_system(ee.aref, |system, world| -> Option<i64> {
-                    system.tick(self, world)
-                });
+                if !world.contains(ee.aref) {
+                    self.removed(ee.aref, top.request);
+                    return !self.heap.is_empty();
+                }
+                let nt = match top.request {
+                    Some(request) => {
+                        world.with_system(ee.aref, |system, world| {
+                            system.next(request, world, self)
+                        });
+                        None
+                    }
+                    None => world.with_system(ee.aref, |system, world| -> Option<i64> {
+                        system.tick(self, world)
+                    }),
+                };
                 if let Some(nt) = nt {
-                    self.heap.push(SchedulerElement { t: nt, e: ee });
+                    self.heap.push(SchedulerElement {
+                        t: nt,
+                        e: ee,
+                        request: None,
+                        timer: None,
+                    });
                     true
                 } else {
-                    self.heap.len() > 0
+                    !self.heap.is_empty()
                 }
             }
         } else {
@@ -145,16 +632,57 @@ impl Scheduler {
         }
     }
 
+    /// Handles an event of a removed system by the removed policy.
+    fn removed(&mut self, system_ref: SystemRef, request: Option<Request>) {
+        self.stale.inc();
+        match (&self.removed_policy, request) {
+            (RemovedPolicy::Panic, _) => panic!("event for removed system {}", system_ref),
+            (RemovedPolicy::Reject, Some(mut request)) => {
+                if let Some(sink) = request.reply_to.pop() {
+                    request.status = Status::Rejected;
+                    self.deliver_at(self.cur_t_ns, sink, request);
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub fn get_cur_t(&self) -> i64 {
         self.cur_t_ns
     }
 
+    /// Creates a new request at the current simulation time.
+    pub fn new_request(&mut self) -> Request {
+        let id = self.next_request_id();
+        Request::new(id, self.cur_t_ns)
+    }
+
+    /// Hands out a request id not used before.
+    pub fn next_request_id(&mut self) -> u64 {
+        self.last_request_id += 1;
+        self.last_request_id
+    }
+
+    /// Records an event happening to a system now, reported with the next metrics.
+    pub fn annotate(&mut self, system: &str, text: String) {
+        self.annotations.push(Annotation {
+            time_ns: self.cur_t_ns,
+            system: system.to_string(),
+            text,
+        });
+    }
+
+    /// Reports the time reached, at most every 500ms of simulation time and
+    /// on stop, which flushes the annotations not reported yet.
     fn reportmetrics(&mut self, stop: bool) {
-        if self.reported_cur_t_ns.is_none() ||
-            self.cur_t_ns > self.reported_cur_t_ns.unwrap() + 5_000_000_00 {
+        if stop
+            || self.reported_cur_t_ns.is_none()
+            || self.cur_t_ns > self.reported_cur_t_ns.unwrap() + 500_000_000
+        {
             futures::executor::block_on(self.event_tx.send(SimulationReachedTimeEvent {
                 time_ns: self.cur_t_ns,
-                stop: stop,
+                stop,
+                annotations: std::mem::take(&mut self.annotations),
             }))
             .unwrap();
             self.reported_cur_t_ns = Some(self.cur_t_ns);
@@ -164,6 +692,151 @@ impl Scheduler {
 
 impl StatEmitter for Scheduler {
     fn stats(&self) -> String {
-        format!("executed {}", self.executed.stats())
+        format!(
+            "executed {} stale {}",
+            self.executed.stats(),
+            self.stale.stats()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::{FailureInjector, Fault};
+    use crate::systems::{EndSink, Server};
+
+    use rand_distr::Poisson;
+
+    #[test]
+    fn status_index_is_its_position() {
+        for (i, status) in Status::ALL.iter().enumerate() {
+            assert_eq!(status.index(), i);
+        }
+    }
+
+    fn end_sink() -> System {
+        System::EndSink(EndSink::new())
+    }
+
+    #[test]
+    fn duplicate_names_get_a_suffix() {
+        let mut world = World::new();
+        let first = world.add(end_sink(), "sink".to_string());
+        let second = world.add(end_sink(), "sink".to_string());
+        let third = world.add(end_sink(), "sink".to_string());
+        assert_eq!(world.name(second), "sink_2");
+        assert_eq!(world.name(third), "sink_3");
+        assert_eq!(world.lookup("sink"), Some(first));
+        assert!(world.try_add(end_sink(), "sink_2".to_string()).is_none());
+        let added = world.try_add(end_sink(), "other".to_string());
+        assert_eq!(added, world.lookup("other"));
+    }
+
+    #[test]
+    fn duplicate_composites_name_their_members_after_them() {
+        let mut world = World::new();
+        let build = |builder: &mut CompositeBuilder| builder.add(end_sink(), "sink");
+        world.add_composite("service", &[], build);
+        let second = world.add_composite("service", &[], build);
+        assert_eq!(world.name(second), "service_2");
+        assert_eq!(
+            world.members(second)[0].1,
+            world.lookup("service_2/sink").unwrap()
+        );
+    }
+
+    #[test]
+    fn removing_a_composite_removes_its_members() {
+        let mut world = World::new();
+        let composite = world.add_composite("region", &[], |builder| {
+            builder.add_composite("service", &[], |builder| builder.add(end_sink(), "sink"))
+        });
+        let sink = world.lookup("region/service/sink").unwrap();
+        // the composite is taken out of the world while it is called
+        let removed = world.with_system(composite, |_system, world| world.remove(composite));
+        assert!(removed);
+        assert!(!world.contains(composite));
+        assert!(!world.contains(sink));
+        assert_eq!(world.systems().count(), 0);
+    }
+
+    /// A processor sharing server, only its ticks are rescheduled.
+    fn sharing_server(world: &mut World, sink: SystemRef) -> SystemRef {
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), sink).with_processor_sharing(1);
+        world.add(System::Server(server), "server".to_string())
+    }
+
+    #[test]
+    fn superseded_timer_does_not_tick() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let sink = world.add(end_sink(), "sink".to_string());
+        let server = sharing_server(&mut world, sink);
+        scheduler.reschedule(100, server);
+        scheduler.reschedule(200, server);
+        while scheduler.execute_next(&mut world, 1_000) {}
+        assert_eq!(scheduler.executed.value(), 1);
+        assert!(scheduler.timers.is_empty());
+    }
+
+    #[test]
+    fn cancel_after_a_crash_drops_the_timer() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let sink = world.add(end_sink(), "sink".to_string());
+        let server = sharing_server(&mut world, sink);
+        let injector = FailureInjector::new().at(10, server, Fault::Crash { fail: false });
+        let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+        scheduler.schedule_at(10, injector);
+        scheduler.deliver_at(1, server, Request::new(1, 1));
+        while scheduler.execute_next(&mut world, 1_000_000) {}
+        // the request and the crash, the completion of the lost request is dropped
+        assert_eq!(scheduler.executed.value(), 2);
+        assert!(scheduler.timers.is_empty());
+        assert_eq!(world.queue_size_of(server), 0);
+    }
+
+    #[test]
+    fn stop_flushes_annotations() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let sink = world.add(end_sink(), "endsink".to_string());
+        for t in [0, 1_000] {
+            let request = scheduler.new_request();
+            scheduler.deliver_at(t, sink, request);
+        }
+        // reports the start
+        assert!(scheduler.execute_next(&mut world, 1_000));
+        scheduler.annotate("endsink", "annotated".to_string());
+        assert!(!scheduler.execute_next(&mut world, 1_000));
+        assert!(scheduler.annotations.is_empty());
+    }
+
+    #[test]
+    fn glob_matches_levels_of_names() {
+        let mut world = World::new();
+        for name in [
+            "region1/lb",
+            "region1/pool/server1",
+            "region1/pool/server2",
+            "region2/db",
+        ] {
+            world.add(end_sink(), name.to_string());
+        }
+        let names = |world: &World, pattern: &str| -> Vec<String> {
+            world
+                .glob(pattern)
+                .iter()
+                .map(|sr| world.name(*sr).to_string())
+                .collect()
+        };
+        assert_eq!(names(&world, "region1/*"), vec!["region1/lb"]);
+        assert_eq!(
+            names(&world, "region1/*/server?"),
+            vec!["region1/pool/server1", "region1/pool/server2"]
+        );
+        assert_eq!(names(&world, "**/db"), vec!["region2/db"]);
+        assert_eq!(names(&world, "**/server*").len(), 2);
     }
 }
@@ -0,0 +1,506 @@
+use crate::objects::{Request, Scheduler, Status, World};
+use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, DelayQueue, Histogram, LateIds};
+
+use std::collections::HashMap;
+
+pub struct QuorumConfig {
+    /// Replicas answering a read before it completes.
+    pub read_quorum: usize,
+    /// Replicas acknowledging a write before it completes.
+    pub write_quorum: usize,
+    /// Requests of this kind are writes, the rest are reads.
+    pub write_kind: usize,
+    /// Reads seeing replicas behind the newest version write it to them.
+    pub read_repair: bool,
+    /// Requests without a quorum for this long time out.
+    pub timeout_ns: i64,
+}
+
+impl Default for QuorumConfig {
+    fn default() -> Self {
+        QuorumConfig {
+            read_quorum: 2,
+            write_quorum: 2,
+            write_kind: 1,
+            read_repair: true,
+            timeout_ns: 1_000_000_000,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum OpKind {
+    Read,
+    Write,
+    Repair,
+}
+
+struct Op {
+    kind: OpKind,
+    key: u64,
+    version: u64,
+    start_t: i64,
+    // the request to respond to once the quorum is reached
+    request: Option<Request>,
+    // ids of the copies sent to the replicas
+    copies: Vec<u64>,
+    oks: usize,
+    errors: usize,
+    // version each replica answered a read with
+    versions: Vec<Option<u64>>,
+}
+
+/// QuorumStore is a replicated datastore in front of replica servers.
+/// Every read and write goes to all of them and completes after the read or
+/// write quorum answered, failing once the quorum can not be reached.
+/// Replicas which miss writes fall behind, reads answered by them are
+/// counted as stale and, with read repair, bring them up to date.
+pub struct QuorumStore {
+    config: QuorumConfig,
+    replicas: Vec<SystemRef>,
+    sink: SystemRef,
+    ops: HashMap<u64, Op>,
+    // id of a copy sent to a replica to (op id, replica index)
+    in_flight: HashMap<u64, (u64, usize)>,
+    timeouts: DelayQueue<u64>,
+    // copies not waited for any more, their answers are dropped
+    late: LateIds,
+    // version of every key held by each replica
+    versions: Vec<HashMap<u64, u64>>,
+    latest: HashMap<u64, u64>,
+    committed: HashMap<u64, u64>,
+    // latency until the k-th replica answered, the last one is the slowest replica
+    read_acks: Vec<Histogram>,
+    write_acks: Vec<Histogram>,
+    failed: Counter,
+    timed_out: Counter,
+    stale_reads: Counter,
+    read_repairs: Counter,
+    sr: Option<SystemRef>,
+}
+
+impl QuorumStore {
+    pub fn new(config: QuorumConfig, replicas: Vec<SystemRef>, sink: SystemRef) -> Self {
+        let n = replicas.len();
+        assert!(config.read_quorum > 0 && config.read_quorum <= n);
+        assert!(config.write_quorum > 0 && config.write_quorum <= n);
+        QuorumStore {
+            config,
+            sink,
+            ops: HashMap::new(),
+            in_flight: HashMap::new(),
+            timeouts: DelayQueue::new(),
+            late: LateIds::new(),
+            versions: vec![HashMap::new(); n],
+            latest: HashMap::new(),
+            committed: HashMap::new(),
+            read_acks: (0..n).map(|_| Histogram::new()).collect(),
+            write_acks: (0..n).map(|_| Histogram::new()).collect(),
+            failed: Counter::new(),
+            timed_out: Counter::new(),
+            stale_reads: Counter::new(),
+            read_repairs: Counter::new(),
+            replicas,
+            sr: None,
+        }
+    }
+
+    fn version(&self, replica: usize, key: u64) -> u64 {
+        self.versions[replica].get(&key).copied().unwrap_or(0)
+    }
+
+    /// Sends a copy of the op to each of the replicas.
+    fn send(&mut self, id: u64, mut op: Op, replicas: Vec<usize>, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        for replica in replicas {
+            let mut copy = scheduler.new_request();
+            copy.key = Some(op.key);
+            if let Some(request) = &op.request {
+                copy.created_t = request.created_t;
+                copy.kind = request.kind;
+                copy.size = request.size;
+                copy.location = request.location;
+            }
+            copy.reply_to.push(self.getref().unwrap());
+            op.copies.push(copy.id);
+            self.in_flight.insert(copy.id, (id, replica));
+            // a replica may be the one whose response is being handled
+            scheduler.deliver_at(cur_t, self.replicas[replica], copy);
+        }
+        self.ops.insert(id, op);
+        self.timeouts.push(cur_t + self.config.timeout_ns, id);
+        scheduler.schedule_at(cur_t + self.config.timeout_ns, self.getref().unwrap());
+    }
+
+    fn respond(
+        &mut self,
+        mut request: Request,
+        status: Status,
+        world: &mut World,
+        scheduler: &mut Scheduler,
+    ) {
+        request.status = status;
+        let sink = request.respond_to(self.sink);
+        world.with_system(sink, |system, world| system.next(request, world, scheduler));
+    }
+
+    fn replica_answered(
+        &mut self,
+        id: u64,
+        replica: usize,
+        status: Status,
+        world: &mut World,
+        scheduler: &mut Scheduler,
+    ) {
+        let cur_t = scheduler.get_cur_t();
+        let op = self.ops.get_mut(&id).unwrap();
+        let n = self.replicas.len();
+        if status == Status::Ok {
+            op.oks += 1;
+            match op.kind {
+                OpKind::Read => {
+                    op.versions[replica] =
+                        Some(self.versions[replica].get(&op.key).copied().unwrap_or(0));
+                    self.read_acks[op.oks - 1].update(cur_t - op.start_t);
+                }
+                OpKind::Write | OpKind::Repair => {
+                    let version = self.versions[replica].entry(op.key).or_insert(0);
+                    *version = (*version).max(op.version);
+                    if op.kind == OpKind::Write {
+                        self.write_acks[op.oks - 1].update(cur_t - op.start_t);
+                    }
+                }
+            }
+        } else {
+            op.errors += 1;
+        }
+        let quorum = match op.kind {
+            OpKind::Read => self.config.read_quorum,
+            _ => self.config.write_quorum,
+        };
+        if op.request.is_some() {
+            if op.oks >= quorum {
+                if op.kind == OpKind::Write {
+                    let committed = self.committed.entry(op.key).or_insert(0);
+                    *committed = (*committed).max(op.version);
+                } else if op.versions.iter().flatten().max().copied().unwrap_or(0)
+                    < self.committed.get(&op.key).copied().unwrap_or(0)
+                {
+                    self.stale_reads.inc();
+                }
+                let request = op.request.take().unwrap();
+                self.respond(request, Status::Ok, world, scheduler);
+            } else if op.errors > n - quorum {
+                self.failed.inc();
+                let request = op.request.take().unwrap();
+                self.respond(request, Status::Error, world, scheduler);
+            }
+        }
+        let op = &self.ops[&id];
+        if op.oks + op.errors == op.copies.len() {
+            let op = self.ops.remove(&id).unwrap();
+            if op.kind == OpKind::Read && self.config.read_repair {
+                self.repair(op, scheduler);
+            }
+        }
+    }
+
+    /// Writes the newest version a read saw to the replicas behind it.
+    fn repair(&mut self, read: Op, scheduler: &mut Scheduler) {
+        let newest = match read.versions.iter().flatten().max() {
+            Some(newest) => *newest,
+            None => return,
+        };
+        let behind: Vec<usize> = (0..self.replicas.len())
+            .filter(|r| self.version(*r, read.key) < newest)
+            .collect();
+        if behind.is_empty() {
+            return;
+        }
+        self.read_repairs.inc();
+        let id = scheduler.next_request_id();
+        let op = Op {
+            kind: OpKind::Repair,
+            key: read.key,
+            version: newest,
+            start_t: scheduler.get_cur_t(),
+            request: None,
+            copies: Vec::new(),
+            oks: 0,
+            errors: 0,
+            versions: vec![None; self.replicas.len()],
+        };
+        self.send(id, op, behind, scheduler);
+    }
+}
+
+impl Sink for QuorumStore {
+    fn next(&mut self, request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        if let Some((id, replica)) = self.in_flight.remove(&request.id) {
+            self.replica_answered(id, replica, request.status, world, scheduler);
+            return;
+        }
+        if self.late.remove(request.id) {
+            // answer of a replica after the op timed out
+            return;
+        }
+        let key = request.key.unwrap_or(0);
+        let (kind, version) = if request.kind == self.config.write_kind {
+            let latest = self.latest.entry(key).or_insert(0);
+            *latest += 1;
+            (OpKind::Write, *latest)
+        } else {
+            (OpKind::Read, 0)
+        };
+        let op = Op {
+            kind,
+            key,
+            version,
+            start_t: scheduler.get_cur_t(),
+            request: Some(request),
+            copies: Vec::new(),
+            oks: 0,
+            errors: 0,
+            versions: vec![None; self.replicas.len()],
+        };
+        let id = scheduler.next_request_id();
+        self.send(id, op, (0..self.replicas.len()).collect(), scheduler);
+    }
+}
+
+impl Emmitter for QuorumStore {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        while let Some(id) = self.timeouts.pop_due(scheduler.get_cur_t()) {
+            // replicas which did not answer by now are not waited for
+            if let Some(mut op) = self.ops.remove(&id) {
+                for copy in &op.copies {
+                    if self.in_flight.remove(copy).is_some() {
+                        self.late.insert(*copy);
+                    }
+                }
+                if let Some(request) = op.request.take() {
+                    self.timed_out.inc();
+                    self.respond(request, Status::Timeout, world, scheduler);
+                }
+            }
+        }
+        None
+    }
+}
+
+fn acks_stats(acks: &[Histogram]) -> String {
+    let acks: Vec<String> = acks.iter().map(|h| h.stats()).collect();
+    acks.join(", ")
+}
+
+impl StatEmitter for QuorumStore {
+    fn stats(&self) -> String {
+        format!(
+            "read acks [{}] write acks [{}] failed {} timed out {} stale reads {} read repairs {}",
+            acks_stats(&self.read_acks),
+            acks_stats(&self.write_acks),
+            self.failed.stats(),
+            self.timed_out.stats(),
+            self.stale_reads.stats(),
+            self.read_repairs.stats()
+        )
+    }
+}
+
+impl HasQueue for QuorumStore {
+    fn queue_size(&self) -> i64 {
+        self.ops.len() as i64
+    }
+}
+
+impl WorldMember for QuorumStore {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        for (k, h) in self.read_acks.iter_mut().enumerate() {
+            h.name = Some(format!("{}_read_ack{}", name, k + 1));
+        }
+        for (k, h) in self.write_acks.iter_mut().enumerate() {
+            h.name = Some(format!("{}_write_ack{}", name, k + 1));
+        }
+        self.failed.name = Some(name.clone() + "_failed");
+        self.timed_out.name = Some(name.clone() + "_timed_out");
+        self.stale_reads.name = Some(name.clone() + "_stale_reads");
+        self.read_repairs.name = Some(name + "_read_repairs");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::{FailureInjector, Fault};
+    use crate::systems::{Server, System};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    use rand_distr::Poisson;
+
+    const RESTART: Fault = Fault::Restart {
+        cold_ns: 0,
+        factor: 1.0,
+    };
+
+    /// A store over three replicas with the faults applied to them.
+    fn quorum_store(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        config: QuorumConfig,
+        faults: Vec<(i64, usize, Fault)>,
+    ) -> (SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let replicas: Vec<SystemRef> = (0..3)
+            .map(|i| {
+                let replica = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+                world.add(System::Server(replica), format!("replica{}", i))
+            })
+            .collect();
+        let mut injector = FailureInjector::new();
+        for (t, replica, fault) in faults {
+            injector = injector.at(t, replicas[replica], fault);
+        }
+        let injector = world.add(System::FailureInjector(injector), "injector".to_string());
+        scheduler.schedule(world, injector);
+        let store = QuorumStore::new(config, replicas, end_sink);
+        let store = world.add(System::QuorumStore(store), "store".to_string());
+        scheduler.schedule(world, store);
+        (store, end_sink)
+    }
+
+    fn with_store<R, F: FnOnce(&QuorumStore) -> R>(world: &mut World, store: SystemRef, f: F) -> R {
+        world.with_system(store, |system, _world| match system {
+            System::QuorumStore(store) => f(store),
+            _ => panic!("not a quorum store"),
+        })
+    }
+
+    /// Sends a write then a read of the key every 10us for 100us.
+    fn send(scheduler: &mut Scheduler, store: SystemRef) {
+        for i in 0..10 {
+            send_at(scheduler, i * 10_000 + 1, store, 1, Some(i as u64));
+            send_at(scheduler, i * 10_000 + 5_001, store, 0, Some(i as u64));
+        }
+    }
+
+    #[test]
+    fn reaches_the_quorum_without_a_replica() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let faults = vec![(0, 2, Fault::Crash { fail: true })];
+        let (store, end_sink) =
+            quorum_store(&mut world, &mut scheduler, QuorumConfig::default(), faults);
+        send(&mut scheduler, store);
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 20);
+    }
+
+    #[test]
+    fn fails_or_times_out_without_a_quorum() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let config = QuorumConfig {
+            timeout_ns: 100_000,
+            ..QuorumConfig::default()
+        };
+        let faults = vec![
+            (0, 1, Fault::Crash { fail: true }),
+            (0, 2, Fault::Crash { fail: false }),
+        ];
+        let (store, end_sink) = quorum_store(&mut world, &mut scheduler, config, faults);
+        send(&mut scheduler, store);
+        run(&mut world, &mut scheduler, 10_000_000);
+        // one answer is missing after the failure, the dropped one never comes
+        assert_eq!(count(&mut world, end_sink, Status::Timeout), 20);
+
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let faults = vec![
+            (0, 1, Fault::Crash { fail: true }),
+            (0, 2, Fault::Crash { fail: true }),
+        ];
+        let (store, end_sink) =
+            quorum_store(&mut world, &mut scheduler, QuorumConfig::default(), faults);
+        send(&mut scheduler, store);
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Error), 20);
+        assert_eq!(
+            with_store(&mut world, store, |store| store.failed.value()),
+            20
+        );
+    }
+
+    #[test]
+    fn counts_stale_reads() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let config = QuorumConfig {
+            read_quorum: 1,
+            ..QuorumConfig::default()
+        };
+        // replica 2 misses the write, then it is the only one to read from
+        let faults = vec![
+            (0, 2, Fault::Crash { fail: false }),
+            (500_000, 2, RESTART),
+            (600_000, 0, Fault::Crash { fail: true }),
+            (600_000, 1, Fault::Crash { fail: true }),
+        ];
+        let (store, end_sink) = quorum_store(&mut world, &mut scheduler, config, faults);
+        send_at(&mut scheduler, 1, store, 1, Some(7));
+        send_at(&mut scheduler, 700_000, store, 0, Some(7));
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 2);
+        with_store(&mut world, store, |store| {
+            assert_eq!(store.stale_reads.value(), 1);
+            assert_eq!(store.read_repairs.value(), 0);
+        });
+    }
+
+    #[test]
+    fn read_repair_brings_a_replica_up_to_date() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let faults = vec![(0, 2, Fault::Crash { fail: false }), (500_000, 2, RESTART)];
+        let (store, end_sink) =
+            quorum_store(&mut world, &mut scheduler, QuorumConfig::default(), faults);
+        send_at(&mut scheduler, 1, store, 1, Some(7));
+        send_at(&mut scheduler, 700_000, store, 0, Some(7));
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 2);
+        with_store(&mut world, store, |store| {
+            assert_eq!(store.stale_reads.value(), 0);
+            assert_eq!(store.read_repairs.value(), 1);
+            assert_eq!(store.version(2, 7), 1);
+        });
+    }
+
+    #[test]
+    fn answers_of_a_slow_replica_after_the_timeout_are_dropped() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let config = QuorumConfig {
+            timeout_ns: 100_000,
+            ..QuorumConfig::default()
+        };
+        // 1ms per request, answering long after the ops timed out
+        let faults = vec![(0, 2, Fault::Brownout(1_000.0))];
+        let (store, end_sink) = quorum_store(&mut world, &mut scheduler, config, faults);
+        send(&mut scheduler, store);
+        run(&mut world, &mut scheduler, 100_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 20);
+        for status in [Status::Error, Status::Timeout] {
+            assert_eq!(count(&mut world, end_sink, status), 0);
+        }
+        with_store(&mut world, store, |store| {
+            assert!(store.latest.values().all(|latest| *latest == 1));
+            assert!(store.ops.is_empty());
+            assert!(store.late.is_empty());
+        });
+    }
+}
@@ -0,0 +1,239 @@
+use crate::objects::{Request, Scheduler, Status, World};
+use crate::traits::{Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::Counter;
+
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Clone, Copy)]
+pub enum RateLimit {
+    /// Refills `rate` tokens per simulated second up to `burst` tokens,
+    /// each request takes a token.
+    TokenBucket { rate: f64, burst: f64 },
+    /// At most `limit` requests in any `window_ns` long window.
+    SlidingWindow { limit: usize, window_ns: i64 },
+}
+
+/// Which requests share a limit.
+pub enum LimitBy {
+    Global,
+    Key,
+    Kind,
+}
+
+/// A limit with the state of one partition of the requests.
+enum LimitState {
+    Tokens {
+        rate: f64,
+        burst: f64,
+        tokens: f64,
+        last_t: i64,
+    },
+    Window {
+        limit: usize,
+        window_ns: i64,
+        times: VecDeque<i64>,
+    },
+}
+
+impl LimitState {
+    fn new(limit: RateLimit, cur_t: i64) -> Self {
+        match limit {
+            RateLimit::TokenBucket { rate, burst } => LimitState::Tokens {
+                rate,
+                burst,
+                tokens: burst,
+                last_t: cur_t,
+            },
+            RateLimit::SlidingWindow { limit, window_ns } => LimitState::Window {
+                limit,
+                window_ns,
+                times: VecDeque::new(),
+            },
+        }
+    }
+
+    /// Takes a request at the time, false when it is over the limit.
+    fn take(&mut self, cur_t: i64) -> bool {
+        match self {
+            LimitState::Tokens {
+                rate,
+                burst,
+                tokens,
+                last_t,
+            } => {
+                let refill = (cur_t - *last_t) as f64 * *rate / 1_000_000_000.0;
+                *tokens = (*tokens + refill).min(*burst);
+                *last_t = cur_t;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+            LimitState::Window {
+                limit,
+                window_ns,
+                times,
+            } => {
+                while times.front().is_some_and(|t| *t + *window_ns <= cur_t) {
+                    times.pop_front();
+                }
+                if times.len() < *limit {
+                    times.push_back(cur_t);
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// RateLimiter passes requests within the limit to the sink and rejects the
+/// rest, back to the system waiting for them or else to the rejection sink.
+/// Has no queue of its own.
+pub struct RateLimiter {
+    limit: RateLimit,
+    by: LimitBy,
+    sink: SystemRef,
+    rejection_sink: SystemRef,
+    states: HashMap<Option<u64>, LimitState>,
+    accepted: Counter,
+    rejected: Counter,
+    sr: Option<SystemRef>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, by: LimitBy, sink: SystemRef, rejection_sink: SystemRef) -> Self {
+        RateLimiter {
+            limit,
+            by,
+            sink,
+            rejection_sink,
+            states: HashMap::new(),
+            accepted: Counter::new(),
+            rejected: Counter::new(),
+            sr: None,
+        }
+    }
+
+    fn accept(&mut self, request: &Request, cur_t: i64) -> bool {
+        let partition = match self.by {
+            LimitBy::Global => None,
+            LimitBy::Key => request.key,
+            LimitBy::Kind => Some(request.kind as u64),
+        };
+        let limit = self.limit;
+        self.states
+            .entry(partition)
+            .or_insert_with(|| LimitState::new(limit, cur_t))
+            .take(cur_t)
+    }
+}
+
+impl Sink for RateLimiter {
+    fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        if self.accept(&request, scheduler.get_cur_t()) {
+            self.accepted.inc();
+            world.with_system(self.sink, |system, world| {
+                system.next(request, world, scheduler);
+            });
+        } else {
+            self.rejected.inc();
+            request.status = Status::Rejected;
+            // the system waiting for the request may still be on the call stack
+            let sink = request.respond_to(self.rejection_sink);
+            scheduler.deliver_at(scheduler.get_cur_t(), sink, request);
+        }
+    }
+}
+
+impl StatEmitter for RateLimiter {
+    fn stats(&self) -> String {
+        format!(
+            "accepted {} rejected {}",
+            self.accepted.stats(),
+            self.rejected.stats()
+        )
+    }
+}
+
+impl WorldMember for RateLimiter {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.accepted.name = Some(name.clone() + "_accepted");
+        self.rejected.name = Some(name + "_rejected");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::System;
+    use crate::testing::{count, end_sink, run, send_at};
+
+    fn limiter(world: &mut World, limit: RateLimit) -> (SystemRef, SystemRef, SystemRef) {
+        let sink = end_sink(world, "sink");
+        let rejections = end_sink(world, "rejections");
+        let limiter = RateLimiter::new(limit, LimitBy::Global, sink, rejections);
+        let limiter = world.add(System::RateLimiter(limiter), "limiter".to_string());
+        (limiter, sink, rejections)
+    }
+
+    #[test]
+    fn sliding_window_rejects_over_limit() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let limit = RateLimit::SlidingWindow {
+            limit: 2,
+            window_ns: 1_000,
+        };
+        let (limiter, sink, rejections) = limiter(&mut world, limit);
+        for t in [0, 0, 0, 500, 1_000] {
+            send_at(&mut scheduler, t, limiter, 0, None);
+        }
+        run(&mut world, &mut scheduler, 1_000_000);
+        assert_eq!(count(&mut world, sink, Status::Ok), 3);
+        assert_eq!(count(&mut world, rejections, Status::Rejected), 2);
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let limit = RateLimit::TokenBucket {
+            rate: 1_000.0,
+            burst: 1.0,
+        };
+        let (limiter, sink, rejections) = limiter(&mut world, limit);
+        for t in [0, 500_000, 1_000_000] {
+            send_at(&mut scheduler, t, limiter, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, sink, Status::Ok), 2);
+        assert_eq!(count(&mut world, rejections, Status::Rejected), 1);
+    }
+
+    #[test]
+    fn rejections_go_back_to_the_waiting_system() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let limit = RateLimit::SlidingWindow {
+            limit: 0,
+            window_ns: 1_000,
+        };
+        let (limiter, _, rejections) = limiter(&mut world, limit);
+        let waiting = end_sink(&mut world, "waiting");
+        let mut request = scheduler.new_request();
+        request.reply_to.push(waiting);
+        scheduler.deliver_at(0, limiter, request);
+        run(&mut world, &mut scheduler, 1_000_000);
+        assert_eq!(count(&mut world, waiting, Status::Rejected), 1);
+        assert_eq!(count(&mut world, rejections, Status::Rejected), 0);
+    }
+}
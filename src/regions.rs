@@ -0,0 +1,334 @@
+use crate::objects::{Request, Scheduler, World};
+use crate::traits::{Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{tostring, Counter};
+
+use rand_distr::{Distribution, Poisson, WeightedIndex};
+
+use std::collections::HashMap;
+
+/// Where a system runs, see `World::place`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Location {
+    pub region: usize,
+    pub zone: usize,
+}
+
+/// Regions with the latency between each pair of them.
+/// Set on the world, a request sent to a system in another region or zone
+/// arrives after the latency of the hop.
+/// Its metrics are prefixed with the name, like those of systems.
+pub struct Topology {
+    regions: Vec<String>,
+    latency: Vec<Vec<Option<Poisson<f32>>>>,
+    zone_latency: Option<Poisson<f32>>,
+    cross_region: Vec<Vec<Counter>>,
+    cross_zone: Counter,
+}
+
+impl Topology {
+    pub fn new(name: &str, regions: &[&str]) -> Self {
+        let n = regions.len();
+        let mut cross_zone = Counter::new();
+        cross_zone.name = Some(format!("{}_cross_zone", name));
+        Topology {
+            regions: regions.iter().map(|r| r.to_string()).collect(),
+            latency: vec![vec![None; n]; n],
+            zone_latency: None,
+            cross_region: regions
+                .iter()
+                .map(|from| {
+                    regions
+                        .iter()
+                        .map(|to| {
+                            let mut counter = Counter::new();
+                            counter.name = Some(format!("{}_cross_region_{}_{}", name, from, to));
+                            counter
+                        })
+                        .collect()
+                })
+                .collect(),
+            cross_zone,
+        }
+    }
+
+    /// Latency in ns of a hop between the regions, both ways.
+    pub fn with_latency(mut self, a: &str, b: &str, latency: Poisson<f32>) -> Self {
+        let (a, b) = (self.region(a), self.region(b));
+        self.latency[a][b] = Some(latency);
+        self.latency[b][a] = Some(latency);
+        self
+    }
+
+    /// Latency in ns of a hop between zones of the same region.
+    pub fn with_zone_latency(mut self, latency: Poisson<f32>) -> Self {
+        self.zone_latency = Some(latency);
+        self
+    }
+
+    pub fn region(&self, name: &str) -> usize {
+        self.regions
+            .iter()
+            .position(|r| r == name)
+            .unwrap_or_else(|| panic!("no region {}", name))
+    }
+
+    pub fn region_name(&self, region: usize) -> &str {
+        &self.regions[region]
+    }
+
+    pub fn regions(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Requests sent from one region to the other so far.
+    pub fn cross_region(&self, from: usize, to: usize) -> i64 {
+        self.cross_region[from][to].value()
+    }
+
+    /// Moves the request to the location, returns the latency of the hop when
+    /// it leaves its zone.
+    pub(crate) fn hop(&mut self, request: &mut Request, to: Location) -> Option<i64> {
+        let from = request.location.replace(to)?;
+        let mut rng = rand::thread_rng();
+        if from.region != to.region {
+            let latency = self.latency[from.region][to.region]
+                .as_ref()
+                .unwrap_or_else(|| {
+                    panic!(
+                        "no latency between regions {} and {}",
+                        self.regions[from.region], self.regions[to.region]
+                    )
+                })
+                .sample(&mut rng) as i64;
+            self.cross_region[from.region][to.region].inc();
+            request.cross_region_ns += latency;
+            Some(latency)
+        } else if from.zone != to.zone {
+            self.cross_zone.inc();
+            self.zone_latency
+                .map(|latency| latency.sample(&mut rng) as i64)
+        } else {
+            None
+        }
+    }
+
+    pub fn stats(&self) -> String {
+        let mut hops = Vec::new();
+        for (from, row) in self.cross_region.iter().enumerate() {
+            for (to, counter) in row.iter().enumerate() {
+                if counter.value() > 0 {
+                    hops.push(format!(
+                        "{} -> {} {}",
+                        self.regions[from],
+                        self.regions[to],
+                        counter.stats()
+                    ));
+                }
+            }
+        }
+        format!(
+            "cross region [{}] cross zone {}",
+            hops.join(", "),
+            self.cross_zone.stats()
+        )
+    }
+}
+
+/// How a RegionRouter picks the target for a request.
+pub enum RoutingPolicy {
+    /// A target in the region of the request, any target when there is none.
+    LocalFirst,
+    /// Like local first, but when local targets have `max_in_flight` requests
+    /// outstanding the remote target with the fewest outstanding takes it.
+    /// When every target is that busy the one with the fewest outstanding does.
+    SpillOver { max_in_flight: usize },
+    /// `weights[region][i]` is the weight of target `i` for requests from
+    /// the region.
+    GeoWeighted(Vec<Vec<f64>>),
+}
+
+/// RegionRouter spreads requests over targets placed in regions, e.g. a load
+/// balancer per region, by the region the request comes from.
+/// Responses come back through the router and go to the sink.
+pub struct RegionRouter {
+    policy: RoutingPolicy,
+    targets: Vec<SystemRef>,
+    sink: SystemRef,
+    weights: Vec<WeightedIndex<f64>>,
+    round_robin: usize,
+    outstanding: Vec<usize>,
+    // request id to target index
+    in_flight: HashMap<u64, usize>,
+    local: Counter,
+    remote: Counter,
+    spilled: Counter,
+    sr: Option<SystemRef>,
+}
+
+impl RegionRouter {
+    pub fn new(policy: RoutingPolicy, targets: Vec<SystemRef>, sink: SystemRef) -> Self {
+        assert!(!targets.is_empty());
+        let weights = match &policy {
+            RoutingPolicy::GeoWeighted(weights) => weights
+                .iter()
+                .map(|row| {
+                    assert_eq!(row.len(), targets.len());
+                    WeightedIndex::new(row).unwrap()
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        RegionRouter {
+            policy,
+            outstanding: vec![0; targets.len()],
+            targets,
+            sink,
+            weights,
+            round_robin: 0,
+            in_flight: HashMap::new(),
+            local: Counter::new(),
+            remote: Counter::new(),
+            spilled: Counter::new(),
+            sr: None,
+        }
+    }
+
+    fn next_of(&mut self, candidates: &[usize]) -> usize {
+        self.round_robin = (self.round_robin + 1) % candidates.len();
+        candidates[self.round_robin]
+    }
+
+    fn pick(&mut self, region: Option<usize>, world: &World) -> usize {
+        let local: Vec<usize> = (0..self.targets.len())
+            .filter(|i| {
+                region.is_some_and(|region| {
+                    world
+                        .location(self.targets[*i])
+                        .is_some_and(|l| l.region == region)
+                })
+            })
+            .collect();
+        let all: Vec<usize> = (0..self.targets.len()).collect();
+        match &self.policy {
+            RoutingPolicy::LocalFirst if local.is_empty() => self.next_of(&all),
+            RoutingPolicy::LocalFirst => self.next_of(&local),
+            RoutingPolicy::SpillOver { max_in_flight } => {
+                let available: Vec<usize> = local
+                    .iter()
+                    .copied()
+                    .filter(|i| self.outstanding[*i] < *max_in_flight)
+                    .collect();
+                if !available.is_empty() {
+                    return self.next_of(&available);
+                }
+                let remote = all
+                    .iter()
+                    .copied()
+                    .filter(|i| !local.contains(i) && self.outstanding[*i] < *max_in_flight)
+                    .min_by_key(|i| self.outstanding[*i]);
+                // when everything is overloaded too
+                let target = remote.unwrap_or_else(|| {
+                    all.into_iter()
+                        .min_by_key(|i| self.outstanding[*i])
+                        .unwrap()
+                });
+                if !local.is_empty() && !local.contains(&target) {
+                    self.spilled.inc();
+                }
+                target
+            }
+            RoutingPolicy::GeoWeighted(_) => match region {
+                Some(region) => self.weights[region].sample(&mut rand::thread_rng()),
+                None => self.next_of(&all),
+            },
+        }
+    }
+}
+
+impl Sink for RegionRouter {
+    fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        if let Some(target) = self.in_flight.remove(&request.id) {
+            self.outstanding[target] -= 1;
+            let sink = request.respond_to(self.sink);
+            world.with_system(sink, |system, world| system.next(request, world, scheduler));
+            return;
+        }
+        let region = request.location.map(|l| l.region);
+        let target = self.pick(region, world);
+        if region.is_some() && world.location(self.targets[target]).map(|l| l.region) == region {
+            self.local.inc();
+        } else {
+            self.remote.inc();
+        }
+        self.outstanding[target] += 1;
+        self.in_flight.insert(request.id, target);
+        request.reply_to.push(self.getref().unwrap());
+        world.with_system(self.targets[target], |system, world| {
+            system.next(request, world, scheduler)
+        });
+    }
+}
+
+impl StatEmitter for RegionRouter {
+    fn stats(&self) -> String {
+        format!(
+            "local {} remote {} spilled {} outstanding {}",
+            self.local.stats(),
+            self.remote.stats(),
+            self.spilled.stats(),
+            tostring(self.in_flight.len())
+        )
+    }
+}
+
+impl WorldMember for RegionRouter {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.local.name = Some(name.clone() + "_local");
+        self.remote.name = Some(name.clone() + "_remote");
+        self.spilled.name = Some(name + "_spilled");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_prefixed_with_the_name() {
+        let topology = Topology::new("topology", &["eu", "us"]);
+        assert_eq!(
+            topology.cross_zone.name.as_deref(),
+            Some("topology_cross_zone")
+        );
+        assert_eq!(
+            topology.cross_region[0][1].name.as_deref(),
+            Some("topology_cross_region_eu_us")
+        );
+    }
+
+    #[test]
+    fn hops_count_where_requests_cross() {
+        let mut topology = Topology::new("topology", &["eu", "us"]).with_latency(
+            "eu",
+            "us",
+            Poisson::new(1_000.0).unwrap(),
+        );
+        let mut request = Request::new(1, 0);
+        let eu = Location { region: 0, zone: 0 };
+        let us = Location { region: 1, zone: 0 };
+        assert_eq!(topology.hop(&mut request, eu), None);
+        assert!(topology.hop(&mut request, us).is_some());
+        assert_eq!(topology.cross_region(0, 1), 1);
+        assert!(request.cross_region_ns > 0);
+        assert_eq!(
+            topology.hop(&mut request, Location { region: 1, zone: 1 }),
+            None
+        );
+        assert_eq!(topology.cross_zone.value(), 1);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::objects::{Request, Scheduler, Status, World};
+use crate::traits::{Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{Counter, DelayQueue, Gauge, Histogram};
+
+use rand_distr::{Distribution, Poisson};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+const STALE_WINDOW_NS: i64 = 1_000_000_000;
+
+struct Follower {
+    system: SystemRef,
+    // (key, version) applied at the time
+    pending: DelayQueue<(u64, u64)>,
+    // the follower applies the next write no sooner than this
+    free_t: i64,
+    versions: HashMap<u64, u64>,
+}
+
+impl Follower {
+    /// Applies the writes due by now, adds their keys to `applied`.
+    fn apply_due(&mut self, cur_t: i64, applied: &mut HashSet<u64>) {
+        while let Some((key, version)) = self.pending.pop_due(cur_t) {
+            let v = self.versions.entry(key).or_insert(0);
+            *v = (*v).max(version);
+            applied.insert(key);
+        }
+    }
+
+    fn version(&self, key: u64) -> u64 {
+        self.versions.get(&key).copied().unwrap_or(0)
+    }
+}
+
+/// LeaderFollowerStore sends writes to the leader and reads to the followers
+/// round robin. Once the leader acknowledges a write, each follower applies it
+/// after the replication lag, no faster than the replication limit.
+/// A read is stale when its follower has not applied the latest acknowledged
+/// write of the key, its staleness is how long ago the first missing write
+/// was acknowledged.
+pub struct LeaderFollowerStore {
+    leader: SystemRef,
+    followers: Vec<Follower>,
+    lag: Poisson<f32>,
+    // writes per second each follower applies
+    replication_limit: Option<f64>,
+    write_kind: usize,
+    sink: SystemRef,
+    round_robin: usize,
+    // request id to the follower reading, None for writes
+    in_flight: HashMap<u64, Option<usize>>,
+    latest: HashMap<u64, u64>,
+    // (version, acknowledged at) of writes some follower has not applied yet
+    unreplicated: HashMap<u64, VecDeque<(u64, i64)>>,
+    reads: Counter,
+    writes: Counter,
+    stale_reads: Counter,
+    staleness: Histogram,
+    stale_ratio: Gauge,
+    read_rate: Gauge,
+    backlog: Gauge,
+    window_start_t: i64,
+    window_reads: i64,
+    window_stale: i64,
+    sr: Option<SystemRef>,
+}
+
+impl LeaderFollowerStore {
+    /// `lag` is the replication delay in ns after the leader acknowledged a write.
+    pub fn new(
+        leader: SystemRef,
+        followers: Vec<SystemRef>,
+        lag: Poisson<f32>,
+        sink: SystemRef,
+    ) -> Self {
+        assert!(!followers.is_empty());
+        LeaderFollowerStore {
+            leader,
+            followers: followers
+                .into_iter()
+                .map(|system| Follower {
+                    system,
+                    pending: DelayQueue::new(),
+                    free_t: 0,
+                    versions: HashMap::new(),
+                })
+                .collect(),
+            lag,
+            replication_limit: None,
+            write_kind: 1,
+            sink,
+            round_robin: 0,
+            in_flight: HashMap::new(),
+            latest: HashMap::new(),
+            unreplicated: HashMap::new(),
+            reads: Counter::new(),
+            writes: Counter::new(),
+            stale_reads: Counter::new(),
+            staleness: Histogram::new(),
+            stale_ratio: Gauge::new(),
+            read_rate: Gauge::new(),
+            backlog: Gauge::new(),
+            window_start_t: 0,
+            window_reads: 0,
+            window_stale: 0,
+            sr: None,
+        }
+    }
+
+    /// Each follower applies at most `writes_per_second`, writes beyond that
+    /// queue up.
+    pub fn with_replication_limit(mut self, writes_per_second: f64) -> Self {
+        assert!(writes_per_second > 0.0);
+        self.replication_limit = Some(writes_per_second);
+        self
+    }
+
+    /// Requests of this kind are writes, the rest are reads. Defaults to 1.
+    pub fn with_write_kind(mut self, kind: usize) -> Self {
+        self.write_kind = kind;
+        self
+    }
+
+    /// Applies the writes due at the followers and forgets the ones every
+    /// follower applied.
+    fn apply_due(&mut self, cur_t: i64) {
+        let mut applied = HashSet::new();
+        for f in self.followers.iter_mut() {
+            f.apply_due(cur_t, &mut applied);
+        }
+        for key in applied {
+            let replicated = self.followers.iter().map(|f| f.version(key)).min().unwrap();
+            if let Some(writes) = self.unreplicated.get_mut(&key) {
+                while writes.front().is_some_and(|(v, _)| *v <= replicated) {
+                    writes.pop_front();
+                }
+                if writes.is_empty() {
+                    self.unreplicated.remove(&key);
+                }
+            }
+        }
+        let backlog: usize = self.followers.iter().map(|f| f.pending.len()).sum();
+        self.backlog.set(backlog as f64);
+    }
+
+    fn replicate(&mut self, key: u64, cur_t: i64) {
+        self.apply_due(cur_t);
+        let version = self.latest.entry(key).or_insert(0);
+        *version += 1;
+        let version = *version;
+        self.unreplicated
+            .entry(key)
+            .or_default()
+            .push_back((version, cur_t));
+        let mut rng = rand::thread_rng();
+        for follower in self.followers.iter_mut() {
+            let mut t = cur_t + self.lag.sample(&mut rng) as i64;
+            if let Some(limit) = self.replication_limit {
+                t = t.max(follower.free_t);
+                follower.free_t = t + (1_000_000_000.0 / limit) as i64;
+            }
+            follower.pending.push(t, (key, version));
+        }
+        let backlog: usize = self.followers.iter().map(|f| f.pending.len()).sum();
+        self.backlog.set(backlog as f64);
+    }
+
+    /// Checks the read the follower served against the latest write of the key.
+    fn check(&mut self, follower: usize, key: u64, cur_t: i64) {
+        self.apply_due(cur_t);
+        let applied = self.followers[follower].version(key);
+        let missing = self
+            .unreplicated
+            .get(&key)
+            .and_then(|writes| writes.iter().find(|(v, _)| *v > applied));
+        self.window_reads += 1;
+        if let Some((_, acknowledged_t)) = missing {
+            self.stale_reads.inc();
+            self.staleness.update(cur_t - acknowledged_t);
+            self.window_stale += 1;
+        }
+        if cur_t >= self.window_start_t + STALE_WINDOW_NS {
+            let window_s = (cur_t - self.window_start_t) as f64 / 1_000_000_000.0;
+            self.stale_ratio
+                .set(self.window_stale as f64 / self.window_reads as f64);
+            self.read_rate.set(self.window_reads as f64 / window_s);
+            self.window_start_t = cur_t;
+            self.window_reads = 0;
+            self.window_stale = 0;
+        }
+    }
+}
+
+impl Sink for LeaderFollowerStore {
+    fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        if let Some(follower) = self.in_flight.remove(&request.id) {
+            let key = request.key.unwrap_or(0);
+            match follower {
+                _ if request.status != Status::Ok => {}
+                Some(follower) => self.check(follower, key, cur_t),
+                None => self.replicate(key, cur_t),
+            }
+            let sink = request.respond_to(self.sink);
+            world.with_system(sink, |system, world| system.next(request, world, scheduler));
+            return;
+        }
+        let (system, follower) = if request.kind == self.write_kind {
+            self.writes.inc();
+            (self.leader, None)
+        } else {
+            self.reads.inc();
+            self.round_robin = (self.round_robin + 1) % self.followers.len();
+            (
+                self.followers[self.round_robin].system,
+                Some(self.round_robin),
+            )
+        };
+        self.in_flight.insert(request.id, follower);
+        request.reply_to.push(self.getref().unwrap());
+        world.with_system(system, |system, world| {
+            system.next(request, world, scheduler)
+        });
+    }
+}
+
+impl StatEmitter for LeaderFollowerStore {
+    fn stats(&self) -> String {
+        format!(
+            "reads {} writes {} stale reads {} staleness {} stale ratio {}",
+            self.reads.stats(),
+            self.writes.stats(),
+            self.stale_reads.stats(),
+            self.staleness.stats(),
+            self.stale_ratio.stats()
+        )
+    }
+}
+
+impl WorldMember for LeaderFollowerStore {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        self.reads.name = Some(name.clone() + "_reads");
+        self.writes.name = Some(name.clone() + "_writes");
+        self.stale_reads.name = Some(name.clone() + "_stale_reads");
+        self.staleness.name = Some(name.clone() + "_staleness");
+        self.stale_ratio.name = Some(name.clone() + "_stale_ratio");
+        self.read_rate.name = Some(name.clone() + "_read_rate");
+        self.backlog.name = Some(name + "_replication_backlog");
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::{Server, System};
+    use crate::testing::{count, end_sink, run, send_at};
+
+    /// A store with a leader and two followers, replicating after about 100us.
+    fn store(world: &mut World) -> (SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let mut servers = (0..3).map(|i| {
+            let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+            world.add(System::Server(server), format!("server{}", i))
+        });
+        let leader = servers.next().unwrap();
+        let followers = servers.collect();
+        let store = LeaderFollowerStore::new(
+            leader,
+            followers,
+            Poisson::new(100_000.0).unwrap(),
+            end_sink,
+        );
+        let store = world.add(System::LeaderFollowerStore(store), "store".to_string());
+        (store, end_sink)
+    }
+
+    fn with_store<R, F: FnOnce(&LeaderFollowerStore) -> R>(
+        world: &mut World,
+        store: SystemRef,
+        f: F,
+    ) -> R {
+        world.with_system(store, |system, _world| match system {
+            System::LeaderFollowerStore(store) => f(store),
+            _ => panic!("not a leader follower store"),
+        })
+    }
+
+    #[test]
+    fn reads_before_replication_are_stale() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (store, end_sink) = store(&mut world);
+        send_at(&mut scheduler, 1, store, 1, Some(7));
+        send_at(&mut scheduler, 20_000, store, 0, Some(7));
+        send_at(&mut scheduler, 1_000_000, store, 0, Some(7));
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 3);
+        with_store(&mut world, store, |store| {
+            assert_eq!(store.stale_reads.value(), 1);
+            assert!(store.unreplicated.is_empty());
+        });
+    }
+
+    #[test]
+    fn writes_forget_what_is_replicated() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (store, _) = store(&mut world);
+        for i in 0..100 {
+            send_at(&mut scheduler, i * 1_000_000 + 1, store, 1, Some(i as u64));
+        }
+        run(&mut world, &mut scheduler, 1_000_000_000);
+        with_store(&mut world, store, |store| {
+            // only the last write may still be replicating
+            assert!(store.unreplicated.len() <= 1);
+            assert!(store.backlog.get() <= 2.0);
+        });
+    }
+}
@@ -0,0 +1,593 @@
+use crate::objects::{Request, Scheduler, World};
+use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
+use crate::utils::{tostring, Counter, DelayQueue, Gauge, Histogram};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+const IMBALANCE_WINDOW_NS: i64 = 1_000_000_000;
+
+/// How keys map to shards.
+pub enum Partitioning {
+    /// Hash of the key modulo the number of shards.
+    Hash,
+    /// Keys 1..=max_key split into equal ranges, one per shard.
+    Range { max_key: u64 },
+    /// Each shard owns `vnodes` points on a hash ring, a key belongs to the
+    /// shard of the first point after its hash.
+    ConsistentHash { vnodes: usize },
+}
+
+fn hash(x: u64) -> u64 {
+    // splitmix64
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Shards and, for consistent hashing, their ring.
+struct Layout {
+    shards: Vec<SystemRef>,
+    ring: Vec<(u64, SystemRef)>,
+}
+
+impl Layout {
+    fn new(partitioning: &Partitioning, shards: Vec<SystemRef>) -> Self {
+        assert!(!shards.is_empty());
+        let mut ring = Vec::new();
+        if let Partitioning::ConsistentHash { vnodes } = partitioning {
+            for shard in &shards {
+                for vnode in 0..*vnodes {
+                    ring.push((hash(((shard.index() as u64) << 32) | vnode as u64), *shard));
+                }
+            }
+            ring.sort();
+        }
+        Layout { shards, ring }
+    }
+
+    fn owner(&self, partitioning: &Partitioning, key: u64) -> SystemRef {
+        let n = self.shards.len() as u64;
+        match partitioning {
+            Partitioning::Hash => self.shards[(hash(key) % n) as usize],
+            Partitioning::Range { max_key } => {
+                let index = (key.saturating_sub(1) * n / max_key).min(n - 1);
+                self.shards[index as usize]
+            }
+            Partitioning::ConsistentHash { .. } => {
+                let h = hash(key);
+                let i = self.ring.partition_point(|(point, _)| *point < h);
+                self.ring[i % self.ring.len()].1
+            }
+        }
+    }
+}
+
+struct Shard {
+    requests: Counter,
+    latency: Histogram,
+    window_requests: i64,
+    window_latency_ns: i64,
+}
+
+impl Shard {
+    fn new() -> Self {
+        Shard {
+            requests: Counter::new(),
+            latency: Histogram::new(),
+            window_requests: 0,
+            window_latency_ns: 0,
+        }
+    }
+
+    fn set_name(&mut self, service: &str, shard: SystemRef) {
+        self.requests.name = Some(format!("{}_shard{}_requests", service, shard));
+        self.latency.name = Some(format!("{}_shard{}_latency", service, shard));
+    }
+}
+
+/// ShardedService routes requests to shard servers by their key.
+/// Resharding changes the shards, keys which belong to another shard after
+/// it are copied there one by one through the data movement queue and are
+/// served by their old shard until then.
+/// Requests without a key are spread over the shards by their id, whatever
+/// the partitioning.
+/// Schedule the service at the start of the simulation when resharding.
+pub struct ShardedService {
+    partitioning: Partitioning,
+    layout: Layout,
+    sink: SystemRef,
+    reshards: DelayQueue<Vec<SystemRef>>,
+    // keys requested so far, the data of the service
+    keys: HashSet<u64>,
+    movement: VecDeque<u64>,
+    // keys waiting for or being moved to the shard they are on now
+    unmoved: HashMap<u64, SystemRef>,
+    movement_parallelism: usize,
+    // id of a copy request to its key and the shard it is copied to
+    moving: HashMap<u64, (u64, SystemRef)>,
+    // request id to the shard and the time it was sent
+    in_flight: HashMap<u64, (SystemRef, i64)>,
+    shards: HashMap<SystemRef, Shard>,
+    window_start_t: i64,
+    moved: Counter,
+    movement_queue: Gauge,
+    load_imbalance: Gauge,
+    latency_imbalance: Gauge,
+    name: String,
+    sr: Option<SystemRef>,
+}
+
+impl ShardedService {
+    pub fn new(partitioning: Partitioning, shards: Vec<SystemRef>, sink: SystemRef) -> Self {
+        if let Partitioning::Range { max_key } = partitioning {
+            assert!(max_key > 0, "range partitioning needs keys 1..=max_key");
+        }
+        let layout = Layout::new(&partitioning, shards);
+        ShardedService {
+            shards: layout
+                .shards
+                .iter()
+                .map(|shard| (*shard, Shard::new()))
+                .collect(),
+            partitioning,
+            layout,
+            sink,
+            reshards: DelayQueue::new(),
+            keys: HashSet::new(),
+            movement: VecDeque::new(),
+            unmoved: HashMap::new(),
+            movement_parallelism: 1,
+            moving: HashMap::new(),
+            in_flight: HashMap::new(),
+            window_start_t: 0,
+            moved: Counter::new(),
+            movement_queue: Gauge::new(),
+            load_imbalance: Gauge::new(),
+            latency_imbalance: Gauge::new(),
+            name: String::new(),
+            sr: None,
+        }
+    }
+
+    /// Changes the shards to `shards` at simulation time `t`.
+    pub fn with_reshard_at(mut self, t: i64, shards: Vec<SystemRef>) -> Self {
+        self.reshards.push(t, shards);
+        self
+    }
+
+    /// Keys copied to their new shard at the same time, defaults to 1.
+    pub fn with_movement_parallelism(mut self, parallelism: usize) -> Self {
+        assert!(parallelism > 0);
+        self.movement_parallelism = parallelism;
+        self
+    }
+
+    /// The shard the data of the key is on.
+    fn owner(&self, key: u64) -> SystemRef {
+        match self.unmoved.get(&key) {
+            Some(shard) => *shard,
+            None => self.layout.owner(&self.partitioning, key),
+        }
+    }
+
+    fn reshard(&mut self, shards: Vec<SystemRef>, scheduler: &mut Scheduler) {
+        let layout = Layout::new(&self.partitioning, shards);
+        let mut moved = 0;
+        for key in &self.keys {
+            let from = match self.unmoved.get(key) {
+                Some(shard) => *shard,
+                None => self.layout.owner(&self.partitioning, *key),
+            };
+            if from == layout.owner(&self.partitioning, *key) {
+                // a key waiting to move may stay where it is now
+                self.unmoved.remove(key);
+            } else if self.unmoved.insert(*key, from).is_none() {
+                self.movement.push_back(*key);
+                moved += 1;
+            }
+        }
+        self.layout = layout;
+        for shard in &self.layout.shards {
+            if !self.shards.contains_key(shard) {
+                let mut stats = Shard::new();
+                stats.set_name(&self.name, *shard);
+                self.shards.insert(*shard, stats);
+            }
+        }
+        scheduler.annotate(
+            &self.name,
+            format!(
+                "resharding to {} shards, {} of {} keys move",
+                self.layout.shards.len(),
+                moved,
+                self.keys.len()
+            ),
+        );
+        self.report_movement();
+    }
+
+    /// Sends copy requests for queued keys up to the movement parallelism.
+    fn move_keys(&mut self, scheduler: &mut Scheduler) {
+        while self.moving.len() < self.movement_parallelism {
+            let key = match self.movement.pop_front() {
+                Some(key) => key,
+                None => break,
+            };
+            if !self.unmoved.contains_key(&key) {
+                continue;
+            }
+            let mut copy = scheduler.new_request();
+            copy.key = Some(key);
+            copy.reply_to.push(self.getref().unwrap());
+            let shard = self.layout.owner(&self.partitioning, key);
+            self.moving.insert(copy.id, (key, shard));
+            // the shard may be the one whose response is being handled
+            scheduler.deliver_at(scheduler.get_cur_t(), shard, copy);
+        }
+        self.report_movement();
+    }
+
+    fn report_movement(&mut self) {
+        self.movement_queue
+            .set((self.movement.len() + self.moving.len()) as f64);
+    }
+
+    fn record(&mut self, shard: SystemRef, latency: i64, cur_t: i64) {
+        if let Some(stats) = self.shards.get_mut(&shard) {
+            stats.requests.inc();
+            stats.latency.update(latency);
+            stats.window_requests += 1;
+            stats.window_latency_ns += latency;
+        }
+        if cur_t < self.window_start_t + IMBALANCE_WINDOW_NS {
+            return;
+        }
+        let current: Vec<&Shard> = self
+            .layout
+            .shards
+            .iter()
+            .map(|shard| &self.shards[shard])
+            .collect();
+        let loads: Vec<f64> = current.iter().map(|s| s.window_requests as f64).collect();
+        let latencies: Vec<f64> = current
+            .iter()
+            .filter(|s| s.window_requests > 0)
+            .map(|s| s.window_latency_ns as f64 / s.window_requests as f64)
+            .collect();
+        self.load_imbalance.set(max_over_mean(&loads));
+        self.latency_imbalance.set(max_over_mean(&latencies));
+        for stats in self.shards.values_mut() {
+            stats.window_requests = 0;
+            stats.window_latency_ns = 0;
+        }
+        self.window_start_t = cur_t;
+    }
+}
+
+fn max_over_mean(values: &[f64]) -> f64 {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    if mean > 0.0 {
+        values.iter().cloned().fold(0.0, f64::max) / mean
+    } else {
+        0.0
+    }
+}
+
+impl Sink for ShardedService {
+    fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        if let Some((key, shard)) = self.moving.remove(&request.id) {
+            self.moved.inc();
+            if shard == self.layout.owner(&self.partitioning, key) {
+                self.unmoved.remove(&key);
+            } else if self.unmoved.insert(key, shard).is_some() {
+                // resharded again while the key was copied
+                self.movement.push_back(key);
+            }
+            self.move_keys(scheduler);
+            return;
+        }
+        if let Some((shard, sent_t)) = self.in_flight.remove(&request.id) {
+            self.record(shard, cur_t - sent_t, cur_t);
+            let sink = request.respond_to(self.sink);
+            world.with_system(sink, |system, world| system.next(request, world, scheduler));
+            return;
+        }
+        let shard = match request.key {
+            Some(key) => {
+                self.keys.insert(key);
+                self.owner(key)
+            }
+            None => {
+                let n = self.layout.shards.len() as u64;
+                self.layout.shards[(hash(request.id) % n) as usize]
+            }
+        };
+        self.in_flight.insert(request.id, (shard, cur_t));
+        request.reply_to.push(self.getref().unwrap());
+        world.with_system(shard, |system, world| {
+            system.next(request, world, scheduler)
+        });
+    }
+}
+
+impl Emmitter for ShardedService {
+    fn tick(&mut self, _world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        while let Some(shards) = self.reshards.pop_due(scheduler.get_cur_t()) {
+            self.reshard(shards, scheduler);
+            self.move_keys(scheduler);
+        }
+        self.reshards.peek_t()
+    }
+}
+
+impl StatEmitter for ShardedService {
+    fn stats(&self) -> String {
+        let shards: Vec<String> = self
+            .layout
+            .shards
+            .iter()
+            .map(|shard| {
+                let stats = &self.shards[shard];
+                format!(
+                    "{} requests {} latency {}",
+                    shard,
+                    stats.requests.stats(),
+                    stats.latency.stats()
+                )
+            })
+            .collect();
+        format!(
+            "shards [{}] load imbalance {} latency imbalance {} moved {} movement queue {}",
+            shards.join(", "),
+            self.load_imbalance.stats(),
+            self.latency_imbalance.stats(),
+            self.moved.stats(),
+            tostring(self.movement.len() + self.moving.len())
+        )
+    }
+}
+
+impl HasQueue for ShardedService {
+    fn queue_size(&self) -> i64 {
+        (self.movement.len() + self.moving.len()) as i64
+    }
+}
+
+impl WorldMember for ShardedService {
+    fn add(&mut self, system_ref: SystemRef, name: String) {
+        for (shard, stats) in self.shards.iter_mut() {
+            stats.set_name(&name, *shard);
+        }
+        self.moved.name = Some(name.clone() + "_moved");
+        self.movement_queue.name = Some(name.clone() + "_movement_queue");
+        self.load_imbalance.name = Some(name.clone() + "_load_imbalance");
+        self.latency_imbalance.name = Some(name.clone() + "_latency_imbalance");
+        self.name = name;
+        self.sr = Some(system_ref)
+    }
+
+    fn getref(&self) -> Option<SystemRef> {
+        self.sr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::{Server, System};
+    use crate::testing::{end_sink, run, send_at};
+
+    use rand_distr::Poisson;
+
+    fn shards(world: &mut World, n: usize) -> Vec<SystemRef> {
+        (0..n)
+            .map(|i| end_sink(world, &format!("shard{}", i)))
+            .collect()
+    }
+
+    #[test]
+    fn range_partitioning_splits_keys_evenly() {
+        let mut world = World::new();
+        let shards = shards(&mut world, 4);
+        let partitioning = Partitioning::Range { max_key: 100 };
+        let layout = Layout::new(&partitioning, shards.clone());
+        let owners: Vec<SystemRef> = [1, 25, 26, 100, 150]
+            .iter()
+            .map(|key| layout.owner(&partitioning, *key))
+            .collect();
+        assert_eq!(
+            owners,
+            vec![shards[0], shards[0], shards[1], shards[3], shards[3]]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "range partitioning needs keys")]
+    fn range_partitioning_needs_keys() {
+        let mut world = World::new();
+        let sink = end_sink(&mut world, "endsink");
+        let shards = shards(&mut world, 2);
+        ShardedService::new(Partitioning::Range { max_key: 0 }, shards, sink);
+    }
+
+    /// Share of keys which belong to another shard after adding a fifth one.
+    fn moved_share(partitioning: Partitioning) -> f64 {
+        let mut world = World::new();
+        let shards = shards(&mut world, 5);
+        let before = Layout::new(&partitioning, shards[..4].to_vec());
+        let after = Layout::new(&partitioning, shards);
+        let moved = (1..=10_000)
+            .filter(|key| before.owner(&partitioning, *key) != after.owner(&partitioning, *key))
+            .count();
+        moved as f64 / 10_000.0
+    }
+
+    #[test]
+    fn consistent_hashing_moves_fewer_keys() {
+        let hash = moved_share(Partitioning::Hash);
+        let consistent = moved_share(Partitioning::ConsistentHash { vnodes: 100 });
+        assert!(hash > 0.6, "hash moved {}", hash);
+        assert!(consistent < 0.3, "consistent hashing moved {}", consistent);
+    }
+
+    /// Shard servers taking the service times, behind a sharded service.
+    fn sharded_service(
+        world: &mut World,
+        partitioning: Partitioning,
+        service_ns: &[f32],
+        configure: impl FnOnce(ShardedService, &[SystemRef]) -> ShardedService,
+    ) -> (SystemRef, Vec<SystemRef>) {
+        let end_sink = end_sink(world, "endsink");
+        let shards: Vec<SystemRef> = service_ns
+            .iter()
+            .enumerate()
+            .map(|(i, service_ns)| {
+                let server = Server::new(Poisson::new(*service_ns).unwrap(), end_sink);
+                world.add(System::Server(server), format!("shard{}", i))
+            })
+            .collect();
+        let n = shards.len();
+        let service = ShardedService::new(partitioning, shards[..n - 1].to_vec(), end_sink);
+        let service = configure(service, &shards);
+        let service = world.add(System::ShardedService(service), "service".to_string());
+        (service, shards)
+    }
+
+    fn with_service<R>(
+        world: &mut World,
+        service: SystemRef,
+        f: impl FnOnce(&ShardedService) -> R,
+    ) -> R {
+        world.with_system(service, |system, _world| match system {
+            System::ShardedService(service) => f(service),
+            _ => unreachable!(),
+        })
+    }
+
+    #[test]
+    fn routes_requests_to_the_shard_of_their_key() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        // the last shard is only there for resharding
+        let partitioning = Partitioning::Range { max_key: 100 };
+        let (service, shards) =
+            sharded_service(&mut world, partitioning, &[1_000.0; 5], |service, _| {
+                service
+            });
+        for (i, key) in [1, 26, 51, 76].into_iter().enumerate() {
+            send_at(&mut scheduler, i as i64 * 10_000, service, 0, Some(key));
+        }
+        for i in 0..40 {
+            send_at(&mut scheduler, 100_000 + i * 10_000, service, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        with_service(&mut world, service, |service| {
+            let requests: Vec<i64> = shards[..4]
+                .iter()
+                .map(|shard| service.shards[shard].requests.value())
+                .collect();
+            assert_eq!(requests.iter().sum::<i64>(), 44);
+            // keyless requests do not all end up on the last range
+            assert!(requests.iter().all(|n| *n > 1), "{:?}", requests);
+            assert_eq!(service.keys.len(), 4);
+        });
+    }
+
+    /// Keys 0..100 on four shards resharded onto five at 1ms, checks the
+    /// service at `up_to_ns`.
+    fn reshard<R>(
+        parallelism: usize,
+        up_to_ns: i64,
+        check: impl FnOnce(&ShardedService, &[SystemRef]) -> R,
+    ) -> R {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let partitioning = Partitioning::ConsistentHash { vnodes: 100 };
+        let (service, shards) = sharded_service(
+            &mut world,
+            partitioning,
+            &[10_000.0; 5],
+            |service, shards| {
+                service
+                    .with_reshard_at(1_000_000, shards.to_vec())
+                    .with_movement_parallelism(parallelism)
+            },
+        );
+        scheduler.schedule(&mut world, service);
+        for key in 0..100 {
+            send_at(&mut scheduler, key as i64, service, 0, Some(key));
+        }
+        run(&mut world, &mut scheduler, up_to_ns);
+        with_service(&mut world, service, |service| check(service, &shards))
+    }
+
+    #[test]
+    fn resharding_moves_keys_through_the_queue() {
+        let moving = |parallelism| {
+            reshard(parallelism, 1_000_001, |service, shards| {
+                // keys waiting to move are still served by their old shard
+                for key in &service.movement {
+                    assert_eq!(service.owner(*key), service.unmoved[key]);
+                    assert_eq!(service.layout.owner(&service.partitioning, *key), shards[4]);
+                }
+                (service.moving.len(), service.unmoved.len())
+            })
+        };
+        let (moving_one, unmoved) = moving(1);
+        assert_eq!(moving_one, 1);
+        assert!(unmoved > 4, "{} keys move", unmoved);
+        let (moving_four, _) = moving(4);
+        assert_eq!(moving_four, 4);
+        reshard(4, 100_000_000, |service, _| {
+            assert!(service.unmoved.is_empty());
+            assert!(service.movement.is_empty() && service.moving.is_empty());
+            assert_eq!(service.moved.value(), unmoved as i64);
+            assert_eq!(service.movement_queue.get(), 0.0);
+        });
+    }
+
+    #[test]
+    fn reports_load_and_latency_imbalance() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        // the second shard takes 100 times longer
+        let partitioning = Partitioning::Range { max_key: 100 };
+        let (service, _) = sharded_service(
+            &mut world,
+            partitioning,
+            &[1_000.0, 100_000.0, 1_000.0],
+            |service, _| service,
+        );
+        for i in 0..30 {
+            send_at(&mut scheduler, i * 10_000, service, 0, Some(1));
+        }
+        for i in 0..10 {
+            send_at(
+                &mut scheduler,
+                1_000_000 + i * 1_000_000,
+                service,
+                0,
+                Some(100),
+            );
+        }
+        // closes the window
+        send_at(&mut scheduler, IMBALANCE_WINDOW_NS, service, 0, Some(1));
+        run(&mut world, &mut scheduler, IMBALANCE_WINDOW_NS + 1_000_000);
+        with_service(&mut world, service, |service| {
+            let load_imbalance = service.load_imbalance.get();
+            assert!(
+                (load_imbalance - 31.0 / 20.5).abs() < 1e-9,
+                "{}",
+                load_imbalance
+            );
+            let latency_imbalance = service.latency_imbalance.get();
+            assert!(
+                (1.9..2.0).contains(&latency_imbalance),
+                "{}",
+                latency_imbalance
+            );
+        });
+    }
+}
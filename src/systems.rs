@@ -1,13 +1,37 @@
-use crate::objects::{Scheduler, World};
+use crate::autoscaler::Autoscaler;
+use crate::batcher::Batcher;
+use crate::broker::Broker;
+use crate::cache::Cache;
+use crate::callgraph::CallGraphService;
+use crate::circuitbreaker::CircuitBreaker;
+use crate::composite::Composite;
+use crate::database::Database;
+use crate::deployment::Deployment;
+use crate::failures::FailureInjector;
+use crate::failures::Fault;
+use crate::link::Link;
+use crate::objects::{KeySpace, Request, Scheduler, Status, World};
+use crate::quorum::QuorumStore;
+use crate::ratelimiter::RateLimiter;
+use crate::regions::RegionRouter;
+use crate::replication::LeaderFollowerStore;
+use crate::sharding::ShardedService;
 use crate::traits::{Emmitter, HasQueue, Sink, StatEmitter, SystemRef, WorldMember};
-use crate::utils::{tostring, Counter, Meter};
+use crate::utils::{tostring, Counter, DelayQueue, Gauge, Histogram, LateIds, Meter};
 
+use rand::Rng;
 use rand_distr::Distribution;
 use rand_distr::Poisson;
+use rand_distr::WeightedIndex;
+
+use std::collections::HashMap;
 
 pub struct ArrivalSource {
     distribution: Poisson<f32>,
     sink: SystemRef,
+    key_space: Option<KeySpace>,
+    kinds: Option<WeightedIndex<u32>>,
+    size: u64,
     meter: Meter,
     sr: Option<SystemRef>,
 }
@@ -17,24 +41,73 @@ impl ArrivalSource {
         ArrivalSource {
             distribution,
             sink,
+            key_space: None,
+            kinds: None,
+            size: 0,
             meter: Meter::new(),
             sr: None,
         }
     }
+
+    /// Requests will carry keys sampled from the key space.
+    pub fn with_key_space(mut self, key_space: KeySpace) -> Self {
+        self.key_space = Some(key_space);
+        self
+    }
+
+    /// Requests will be `bytes` in size.
+    pub fn with_size(mut self, bytes: u64) -> Self {
+        self.size = bytes;
+        self
+    }
+
+    /// Requests will be of kind `i` with probability proportional to `weights[i]`.
+    pub fn with_kinds(mut self, weights: Vec<u32>) -> Self {
+        self.kinds = Some(WeightedIndex::new(weights).unwrap());
+        self
+    }
 }
 
+/// EndSink counts requests leaving the system, with counts and latency
+/// since creation broken down by outcome.
 pub struct EndSink {
     ticks: Counter,
+    // indexed by `Status::index`
+    outcomes: Vec<(Counter, Histogram)>,
+    // latency added by hops between regions, of requests which crossed
+    cross_region: Histogram,
+    cross_region_share: Gauge,
+    latency_ns: i64,
+    cross_region_ns: i64,
     sr: Option<SystemRef>,
 }
 
+impl Default for EndSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EndSink {
     pub fn new() -> Self {
         EndSink {
             ticks: Counter::new(),
+            outcomes: Status::ALL
+                .iter()
+                .map(|_| (Counter::new(), Histogram::new()))
+                .collect(),
+            cross_region: Histogram::new(),
+            cross_region_share: Gauge::new(),
+            latency_ns: 0,
+            cross_region_ns: 0,
             sr: None,
         }
     }
+
+    /// Requests which left the system with the status.
+    pub fn count(&self, status: Status) -> i64 {
+        self.outcomes[status.index()].0.value()
+    }
 }
 
 impl StatEmitter for ArrivalSource {
@@ -54,14 +127,21 @@ impl WorldMember for ArrivalSource {
     }
 }
 
-impl<'a> Emmitter for ArrivalSource {
+impl Emmitter for ArrivalSource {
     fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
         let diff = (self.distribution.sample(&mut rand::thread_rng())) as i64;
         let next_time = scheduler.get_cur_t() + diff;
         self.meter.inc(diff);
 
+        let mut request = scheduler.new_request();
+        request.key = self.key_space.as_ref().map(|key_space| key_space.sample());
+        request.size = self.size;
+        request.location = world.location(self.sr.unwrap());
+        if let Some(kinds) = &self.kinds {
+            request.kind = kinds.sample(&mut rand::thread_rng());
+        }
         world.with_system(self.sink, |system, world| {
-            system.next(world, scheduler);
+            system.next(request, world, scheduler);
         });
         Some(next_time as i64)
     }
@@ -69,13 +149,39 @@ impl<'a> Emmitter for ArrivalSource {
 
 impl StatEmitter for EndSink {
     fn stats(&self) -> String {
-        format!("processed {}", self.ticks.stats())
+        let outcomes: Vec<String> = Status::ALL
+            .iter()
+            .zip(&self.outcomes)
+            .map(|(status, (counter, latency))| {
+                format!(
+                    "{} {} latency {}",
+                    status.name(),
+                    counter.stats(),
+                    latency.stats()
+                )
+            })
+            .collect();
+        let mut stats = format!("processed {} {}", self.ticks.stats(), outcomes.join(" "));
+        if self.cross_region_ns > 0 {
+            stats += &format!(
+                " cross region latency {} share {}",
+                self.cross_region.stats(),
+                self.cross_region_share.stats()
+            );
+        }
+        stats
     }
 }
 
 impl WorldMember for EndSink {
     fn add(&mut self, system_ref: SystemRef, name: String) {
-        self.ticks.name = Some(name + "_ticks");
+        self.ticks.name = Some(name.clone() + "_ticks");
+        for (status, (counter, latency)) in Status::ALL.iter().zip(self.outcomes.iter_mut()) {
+            counter.name = Some(format!("{}_{}", name, status.name()));
+            latency.name = Some(format!("{}_latency_{}", name, status.name()));
+        }
+        self.cross_region.name = Some(name.clone() + "_cross_region_latency");
+        self.cross_region_share.name = Some(name + "_cross_region_share");
         self.sr = Some(system_ref)
     }
 
@@ -85,35 +191,285 @@ impl WorldMember for EndSink {
 }
 
 impl Sink for EndSink {
-    fn next(&mut self, _world: &mut World, _scheduler: &mut Scheduler) {
-        self.ticks.inc()
+    fn next(&mut self, request: Request, _world: &mut World, scheduler: &mut Scheduler) {
+        self.ticks.inc();
+        let (counter, latency) = &mut self.outcomes[request.status.index()];
+        counter.inc();
+        latency.update(scheduler.get_cur_t() - request.created_t);
+        self.latency_ns += scheduler.get_cur_t() - request.created_t;
+        if request.cross_region_ns > 0 {
+            self.cross_region.update(request.cross_region_ns);
+            self.cross_region_ns += request.cross_region_ns;
+        }
+        if self.cross_region_ns > 0 {
+            self.cross_region_share
+                .set(self.cross_region_ns as f64 / self.latency_ns as f64);
+        }
+    }
+}
+
+/// Active health checks probe every sink each interval.
+pub struct HealthCheck {
+    pub interval_ns: i64,
+    /// Consecutive failed checks to eject a sink.
+    pub unhealthy_threshold: usize,
+    /// Consecutive passed checks to admit an ejected sink back.
+    pub healthy_threshold: usize,
+}
+
+/// Passive outlier detection watches responses coming back from sinks.
+pub struct OutlierDetection {
+    /// Consecutive errors, or responses slower than `max_latency_ns`, to eject a sink.
+    pub consecutive_errors: usize,
+    pub max_latency_ns: Option<i64>,
+    /// How long an outlier stays out of the rotation.
+    pub ejection_ns: i64,
+    /// Requests without a response for this long count as errors and are
+    /// answered with `Status::Timeout`. Their responses are dropped whenever
+    /// they come back.
+    pub timeout_ns: i64,
+}
+
+#[derive(Default)]
+struct SinkHealth {
+    failed_checks: usize,
+    passed_checks: usize,
+    consecutive_errors: usize,
+    // ejected by health checks
+    unhealthy: bool,
+    // ejected by outlier detection
+    ejected_until_t: Option<i64>,
+}
+
+impl SinkHealth {
+    fn ejected(&self) -> bool {
+        self.unhealthy || self.ejected_until_t.is_some()
     }
 }
 
 /// Loadbalancer distributes incoming requests across a series of sinks.
 /// Currenly it does not have a queue of its own.
+///
+/// With health checks or outlier detection, ejected sinks are skipped. If every
+/// sink is ejected requests go to all of them, as there is nowhere else to go.
+/// Outlier detection needs to see responses, so they come back through the load
+/// balancer and go on to the response sink.
 pub struct LoadBalancer {
     sinks: Vec<SystemRef>,
     sr: Option<SystemRef>,
     counter: Counter,
     cur: usize,
+    health_check: Option<HealthCheck>,
+    outlier_detection: Option<(OutlierDetection, SystemRef)>,
+    health: HashMap<SystemRef, SinkHealth>,
+    in_flight: HashMap<u64, InFlight>,
+    timeouts: DelayQueue<u64>,
+    // timed out requests whose responses are dropped
+    late: LateIds,
+    next_check_t: i64,
+    sent_to_dead: Counter,
+    ejections: Counter,
+    timed_out: Counter,
+    name: String,
+}
+
+/// A request sent to a sink with outlier detection on.
+struct InFlight {
+    sink: SystemRef,
+    sent_t: i64,
+    // answered with a timeout when the sink does not respond
+    request: Request,
 }
 
 impl LoadBalancer {
     pub fn new(sinks: Vec<SystemRef>) -> Self {
-        assert!(sinks.len() > 0);
+        assert!(!sinks.is_empty());
         LoadBalancer {
             sinks,
             sr: None,
             counter: Counter::new(),
             cur: 0,
+            health_check: None,
+            outlier_detection: None,
+            health: HashMap::new(),
+            in_flight: HashMap::new(),
+            timeouts: DelayQueue::new(),
+            late: LateIds::new(),
+            next_check_t: 0,
+            sent_to_dead: Counter::new(),
+            ejections: Counter::new(),
+            timed_out: Counter::new(),
+            name: String::new(),
+        }
+    }
+
+    /// Schedule the load balancer for the checks to run.
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> Self {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    /// Responses go through the load balancer to `sink`. Schedule the load
+    /// balancer for requests to time out.
+    pub fn with_outlier_detection(
+        mut self,
+        outlier_detection: OutlierDetection,
+        sink: SystemRef,
+    ) -> Self {
+        self.outlier_detection = Some((outlier_detection, sink));
+        self
+    }
+
+    pub fn sinks(&self) -> &[SystemRef] {
+        &self.sinks
+    }
+
+    /// Puts a sink into the rotation.
+    pub fn add_sink(&mut self, sink: SystemRef) {
+        self.sinks.push(sink);
+    }
+
+    /// Takes a sink out of the rotation, the last sink can not be removed.
+    pub fn remove_sink(&mut self, sink: SystemRef) -> bool {
+        match self.sinks.iter().position(|s| *s == sink) {
+            Some(i) if self.sinks.len() > 1 => {
+                self.sinks.remove(i);
+                self.health.remove(&sink);
+                if self.cur > i {
+                    self.cur -= 1;
+                }
+                self.cur %= self.sinks.len();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_ejected(&self, sink: SystemRef) -> bool {
+        self.health.get(&sink).is_some_and(|h| h.ejected())
+    }
+
+    fn set_ejected(&mut self, sink: SystemRef, ejected: bool, scheduler: &mut Scheduler) {
+        if ejected {
+            self.ejections.inc();
+        }
+        let text = if ejected { "ejected" } else { "admitted" };
+        scheduler.annotate(&self.name, format!("{} system {}", text, sink));
+    }
+
+    /// Next sink in round robin order which is not ejected.
+    fn pick(&mut self, scheduler: &mut Scheduler) -> SystemRef {
+        let cur_t = scheduler.get_cur_t();
+        let mut readmitted = vec![];
+        for (sink, health) in self.health.iter_mut() {
+            if health.ejected_until_t.is_some_and(|t| t <= cur_t) {
+                health.ejected_until_t = None;
+                health.consecutive_errors = 0;
+                readmitted.push(*sink);
+            }
+        }
+        for sink in readmitted {
+            self.set_ejected(sink, false, scheduler);
+        }
+        let n = self.sinks.len();
+        let mut picked = self.sinks[self.cur % n];
+        for i in 0..n {
+            let sink = self.sinks[(self.cur + i) % n];
+            if !self.is_ejected(sink) {
+                picked = sink;
+                self.cur = (self.cur + i) % n;
+                break;
+            }
+        }
+        self.cur = (self.cur + 1) % n;
+        picked
+    }
+
+    fn check(&mut self, world: &mut World, scheduler: &mut Scheduler) {
+        let health_check = self.health_check.as_ref().unwrap();
+        let (unhealthy_threshold, healthy_threshold) = (
+            health_check.unhealthy_threshold,
+            health_check.healthy_threshold,
+        );
+        for sink in self.sinks.clone() {
+            let up = world.with_system(sink, |system, _world| match system {
+                System::Server(server) => server.is_up(),
+                _ => true,
+            });
+            let health = self.health.entry(sink).or_default();
+            let was_unhealthy = health.unhealthy;
+            if up {
+                health.failed_checks = 0;
+                health.passed_checks += 1;
+                if health.passed_checks >= healthy_threshold {
+                    health.unhealthy = false;
+                }
+            } else {
+                health.passed_checks = 0;
+                health.failed_checks += 1;
+                if health.failed_checks >= unhealthy_threshold {
+                    health.unhealthy = true;
+                }
+            }
+            let unhealthy = health.unhealthy;
+            if unhealthy != was_unhealthy {
+                self.set_ejected(sink, unhealthy, scheduler);
+            }
+        }
+    }
+
+    fn on_response(&mut self, sink: SystemRef, failed: bool, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let (outlier_detection, _) = self.outlier_detection.as_ref().unwrap();
+        let (consecutive_errors, ejection_ns) = (
+            outlier_detection.consecutive_errors,
+            outlier_detection.ejection_ns,
+        );
+        let health = match self.health.get_mut(&sink) {
+            Some(health) => health,
+            // removed from the load balancer meanwhile
+            None => return,
+        };
+        if !failed {
+            health.consecutive_errors = 0;
+            return;
+        }
+        health.consecutive_errors += 1;
+        if health.consecutive_errors >= consecutive_errors && health.ejected_until_t.is_none() {
+            health.ejected_until_t = Some(cur_t + ejection_ns);
+            self.set_ejected(sink, true, scheduler);
+        }
+    }
+
+    /// Answers requests without a response by now with a timeout.
+    fn expire(&mut self, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        while let Some(id) = self.timeouts.pop_due(cur_t) {
+            let in_flight = match self.in_flight.remove(&id) {
+                Some(in_flight) => in_flight,
+                None => continue,
+            };
+            self.timed_out.inc();
+            self.on_response(in_flight.sink, true, scheduler);
+            let (_, response_sink) = self.outlier_detection.as_ref().unwrap();
+            self.late.insert(id);
+            let mut request = in_flight.request;
+            request.status = Status::Timeout;
+            let response_sink = request.respond_to(*response_sink);
+            world.with_system(response_sink, |system, world| {
+                system.next(request, world, scheduler);
+            });
         }
     }
 }
 
 impl WorldMember for LoadBalancer {
     fn add(&mut self, system_ref: SystemRef, name: String) {
-        self.counter.name = Some(name + "_counter");
+        self.counter.name = Some(name.clone() + "_counter");
+        self.sent_to_dead.name = Some(name.clone() + "_sent_to_dead");
+        self.ejections.name = Some(name.clone() + "_ejections");
+        self.timed_out.name = Some(name.clone() + "_timed_out");
+        self.name = name;
         self.sr = Some(system_ref)
     }
 
@@ -123,30 +479,347 @@ impl WorldMember for LoadBalancer {
 }
 
 impl Sink for LoadBalancer {
-    fn next(&mut self, world: &mut World, scheduler: &mut Scheduler) {
-        let next_sink_ref = self.sinks[self.cur];
+    fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        if let Some(in_flight) = self.in_flight.remove(&request.id) {
+            let (outlier_detection, response_sink) = self.outlier_detection.as_ref().unwrap();
+            let slow = outlier_detection
+                .max_latency_ns
+                .is_some_and(|max_latency_ns| cur_t - in_flight.sent_t > max_latency_ns);
+            let response_sink = request.respond_to(*response_sink);
+            self.on_response(
+                in_flight.sink,
+                request.status != Status::Ok || slow,
+                scheduler,
+            );
+            world.with_system(response_sink, |system, world| {
+                system.next(request, world, scheduler);
+            });
+            return;
+        }
+        if self.late.remove(request.id) {
+            // response after the request timed out
+            return;
+        }
+        let next_sink_ref = self.pick(scheduler);
+        if let Some((outlier_detection, _)) = &self.outlier_detection {
+            let timeout_t = cur_t + outlier_detection.timeout_ns;
+            self.health.entry(next_sink_ref).or_default();
+            self.in_flight.insert(
+                request.id,
+                InFlight {
+                    sink: next_sink_ref,
+                    sent_t: cur_t,
+                    request: request.clone(),
+                },
+            );
+            self.timeouts.push(timeout_t, request.id);
+            scheduler.schedule_at(timeout_t, self.sr.unwrap());
+            request.reply_to.push(self.sr.unwrap());
+        }
+        let sent_to_dead = &mut self.sent_to_dead;
         world.with_system(next_sink_ref, |system, world| {
-            system.next(world, scheduler);
+            if let System::Server(server) = system {
+                if !server.is_up() {
+                    sent_to_dead.inc();
+                }
+            }
+            system.next(request, world, scheduler);
         });
-        self.cur += 1;
-        self.cur %= self.sinks.len();
         self.counter.inc();
     }
 }
 
+impl Emmitter for LoadBalancer {
+    fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
+        let cur_t = scheduler.get_cur_t();
+        if self.outlier_detection.is_some() {
+            self.expire(world, scheduler);
+        }
+        let interval_ns = self.health_check.as_ref()?.interval_ns;
+        if cur_t < self.next_check_t {
+            // woken for a timeout
+            return None;
+        }
+        self.check(world, scheduler);
+        self.next_check_t = cur_t + interval_ns;
+        Some(self.next_check_t)
+    }
+}
+
 impl StatEmitter for LoadBalancer {
     fn stats(&self) -> String {
-        format!("lb incoming {}", self.counter.stats())
+        format!(
+            "lb incoming {} sent to dead {} ejections {} timed out {}",
+            self.counter.stats(),
+            self.sent_to_dead.stats(),
+            self.ejections.stats(),
+            self.timed_out.stats()
+        )
+    }
+}
+
+use std::collections::{BinaryHeap, VecDeque};
+
+struct Queued {
+    arrival_t: i64,
+    request: Request,
+}
+
+struct InService {
+    // completion time
+    t: i64,
+    service_ns: i64,
+    queued: Queued,
+}
+
+impl InService {
+    /// Service the request got by `t`.
+    fn served_ns(&self, t: i64) -> i64 {
+        (t - (self.t - self.service_ns)).clamp(0, self.service_ns)
+    }
+}
+
+/// Request served by a processor sharing server.
+struct Shared {
+    // work per request done by the server when this one is complete
+    finish_ns: f64,
+    queued: Queued,
+}
+impl PartialEq for Shared {
+    fn eq(&self, o: &Self) -> bool {
+        self.finish_ns == o.finish_ns
+    }
+}
+impl Eq for Shared {}
+impl PartialOrd for Shared {
+    fn partial_cmp(&self, o: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(o))
+    }
+}
+impl Ord for Shared {
+    fn cmp(&self, o: &Self) -> std::cmp::Ordering {
+        self.finish_ns.total_cmp(&o.finish_ns).reverse()
+    }
+}
+
+/// Requests served at once by a processor sharing server. They all progress
+/// at the same speed, so the work each one got since the server was last
+/// idle tells when they complete.
+struct Sharing {
+    cores: usize,
+    requests: BinaryHeap<Shared>,
+    // load model factor for the requests in service now
+    load_factor: f64,
+    // work each request in service got since the server was last idle, in ns
+    // at full speed, back to 0 when idle to keep the precision of f64
+    work_ns: f64,
+    // work is accounted up to this time
+    updated_t: i64,
+}
+
+impl Sharing {
+    /// Speed of each request, a core each until there are more requests,
+    /// slowed down by the load model.
+    fn rate(&self) -> f64 {
+        (self.cores as f64 / self.requests.len() as f64).min(1.0) / self.load_factor
+    }
+
+    /// Updates the load model factor after requests arrived or completed,
+    /// the work so far must be done at the previous speed.
+    fn set_load(&mut self, load_model: Option<&LoadModel>) {
+        self.load_factor = match load_model {
+            Some(load_model) if !self.requests.is_empty() => {
+                load_model.factor(self.requests.len(), 0)
+            }
+            _ => 1.0,
+        };
+    }
+
+    /// Busy share of the cores since the last update up to `t`, in ns.
+    fn busy_ns(&self, t: i64) -> i64 {
+        let elapsed = (t - self.updated_t) as f64;
+        (elapsed * self.requests.len().min(self.cores) as f64 / self.cores as f64) as i64
+    }
+
+    /// Does the work since the last update, returns the busy share of the
+    /// cores for that time in ns.
+    fn advance(&mut self, t: i64) -> i64 {
+        let busy_ns = self.busy_ns(t);
+        if !self.requests.is_empty() {
+            self.work_ns += (t - self.updated_t) as f64 * self.rate();
+        }
+        self.updated_t = t;
+        busy_ns
+    }
+
+    fn push(&mut self, service_ns: i64, queued: Queued) {
+        self.requests.push(Shared {
+            finish_ns: self.work_ns + service_ns as f64,
+            queued,
+        });
+    }
+
+    /// Takes a request done by now.
+    fn pop_done(&mut self) -> Option<Queued> {
+        // rounding of the completion time to whole ns
+        if self.requests.peek()?.finish_ns - self.work_ns >= 1e-3 {
+            return None;
+        }
+        let shared = self.requests.pop().unwrap();
+        if self.requests.is_empty() {
+            self.work_ns = 0.0;
+        }
+        Some(shared.queued)
+    }
+
+    /// Takes the requests which arrived before `t`, e.g. timed out.
+    fn take_arrived_before(&mut self, t: i64) -> Vec<Queued> {
+        let (taken, kept): (Vec<Shared>, Vec<Shared>) = std::mem::take(&mut self.requests)
+            .into_iter()
+            .partition(|shared| shared.queued.arrival_t < t);
+        self.requests = kept.into();
+        if self.requests.is_empty() {
+            self.work_ns = 0.0;
+        }
+        taken.into_iter().map(|shared| shared.queued).collect()
+    }
+
+    /// Takes every request, e.g. when the server crashes.
+    fn drain(&mut self) -> Vec<Queued> {
+        self.work_ns = 0.0;
+        self.load_factor = 1.0;
+        self.requests.drain().map(|shared| shared.queued).collect()
+    }
+
+    /// When the next request completes at the current speed.
+    fn next_t(&self) -> Option<i64> {
+        let remaining_ns = self.requests.peek()?.finish_ns - self.work_ns;
+        Some(self.updated_t + (remaining_ns.max(0.0) / self.rate()).ceil() as i64)
+    }
+
+    /// When the first request to arrive times out.
+    fn next_timeout_t(&self, timeout_ns: i64) -> Option<i64> {
+        let arrival_t = self.requests.iter().map(|s| s.queued.arrival_t).min()?;
+        Some(arrival_t + timeout_ns + 1)
     }
 }
 
-use std::collections::VecDeque;
+/// Totals of the work a server has done, observers take differences
+/// between two snapshots.
+#[derive(Clone, Copy, Default)]
+pub struct ServerLoad {
+    pub busy_ns: i64,
+    pub completed: i64,
+    /// Sum of time from arrival to completion of completed requests.
+    pub latency_ns: i64,
+    pub queue: i64,
+}
+
+/// How much slower a server is right after it starts or restarts, service
+/// time is multiplied by a factor decaying linearly to 1.
+#[derive(Clone, Copy)]
+pub enum WarmUp {
+    /// `factor` for the first request, 1 from the `requests`-th on.
+    Requests { factor: f64, requests: u64 },
+    /// `factor` at the start, 1 after `duration_ns`.
+    Time { factor: f64, duration_ns: i64 },
+}
+
+/// Scales service time by the load of the server, see `Server::with_load_model`.
+pub enum LoadModel {
+    /// Universal scalability law, with `n` requests in service, service time
+    /// is multiplied by `1 + contention * (n - 1) + coherency * n * (n - 1)`.
+    /// Queued requests do not count, so it needs a processor sharing server,
+    /// a fifo server serves one request at a time.
+    Usl { contention: f64, coherency: f64 },
+    /// Multiplier from the requests in service and the requests queued.
+    Function(Box<dyn Fn(usize, usize) -> f64>),
+}
+
+impl LoadModel {
+    pub fn function<F: Fn(usize, usize) -> f64 + 'static>(f: F) -> Self {
+        LoadModel::Function(Box::new(f))
+    }
+
+    pub fn factor(&self, in_service: usize, queued: usize) -> f64 {
+        match self {
+            LoadModel::Usl {
+                contention,
+                coherency,
+            } => {
+                let n = in_service as f64;
+                1.0 + contention * (n - 1.0) + coherency * n * (n - 1.0)
+            }
+            LoadModel::Function(f) => f(in_service, queued),
+        }
+    }
+}
+
+/// How a server or a database fails requests.
+#[derive(Default)]
+pub struct ErrorModel {
+    /// Probability a served request fails.
+    pub probability: f64,
+    /// Added to the probability for every request waiting in the queue, or
+    /// still sharing a processor sharing server.
+    pub per_queued: f64,
+    /// Requests which waited longer than this time out instead of being served.
+    /// A processor sharing server times out requests in service for longer.
+    pub timeout_ns: Option<i64>,
+    /// Requests arriving to a queue this long are rejected.
+    pub max_queue: Option<usize>,
+}
+
+impl ErrorModel {
+    /// Whether a request served with `queued` requests waiting fails.
+    pub fn fails(&self, queued: usize) -> bool {
+        let p = self.probability + self.per_queued * queued as f64;
+        rand::thread_rng().gen_bool(p.clamp(0.0, 1.0))
+    }
+
+    /// Whether a request which waited since `arrival_t` times out.
+    pub fn timed_out(&self, arrival_t: i64, cur_t: i64) -> bool {
+        self.timeout_ns
+            .is_some_and(|timeout_ns| cur_t - arrival_t > timeout_ns)
+    }
+
+    /// Whether a request arriving to the queue is rejected.
+    pub fn rejects(&self, queued: usize) -> bool {
+        self.max_queue.is_some_and(|max_queue| queued >= max_queue)
+    }
+}
+
+/// Server serves one request at a time in fifo order.
+/// Service time is sampled when a request starts being served.
+/// With processor sharing it serves all its requests at once instead, see
+/// `with_processor_sharing`.
 pub struct Server {
     distribution: Poisson<f32>,
     sink: SystemRef,
-    queue: VecDeque<i64>,
+    queue: VecDeque<Queued>,
+    in_service: Option<InService>,
+    sharing: Option<Sharing>,
+    crashed: Option<Fault>,
+    partitioned: bool,
+    slowdown: f64,
+    warm_up: Option<WarmUp>,
+    // warm-up since the last start, a restart may bring its own
+    warming: Option<WarmUp>,
+    load_model: Option<LoadModel>,
+    started_t: i64,
+    // requests started since the server started
+    started: u64,
+    error_model: Option<ErrorModel>,
+    batch_exponent: f64,
+    busy_ns: i64,
+    completed: i64,
+    latency_ns: i64,
     meter: Meter,
+    latency: Histogram,
     counter: Counter,
+    lost: Counter,
+    errors: Counter,
     sr: Option<SystemRef>,
 }
 
@@ -156,28 +829,347 @@ impl Server {
             distribution,
             sink,
             queue: VecDeque::new(),
+            in_service: None,
+            sharing: None,
+            crashed: None,
+            partitioned: false,
+            slowdown: 1.0,
+            warm_up: None,
+            warming: None,
+            load_model: None,
+            started_t: 0,
+            started: 0,
+            error_model: None,
+            batch_exponent: 0.0,
+            busy_ns: 0,
+            completed: 0,
+            latency_ns: 0,
             meter: Meter::new(),
+            latency: Histogram::new(),
             counter: Counter::new(),
+            lost: Counter::new(),
+            errors: Counter::new(),
             sr: None,
         }
     }
-}
 
-impl Sink for Server {
-    fn next(&mut self, _world: &mut World, scheduler: &mut Scheduler) {
-        let next_time = (self.distribution.sample(&mut rand::thread_rng())) as i64;
-        if self.queue.is_empty() {
-            let nt = scheduler.get_cur_t() + next_time;
-            self.queue.push_back(nt);
+    /// The server warms up from the start of the simulation, when it is
+    /// started and after each restart.
+    pub fn with_warm_up(mut self, warm_up: WarmUp) -> Self {
+        self.warm_up = Some(warm_up);
+        self.warming = Some(warm_up);
+        self
+    }
 
-            scheduler.schedule_at(nt, self.getref().unwrap());
+    /// Service time depends on the requests in the server. A fifo server
+    /// applies the factor when a request starts being served, the one
+    /// starting counts as in service. A processor sharing server slows down
+    /// every request by the factor for the requests in service, updated as
+    /// they arrive and complete.
+    pub fn with_load_model(mut self, load_model: LoadModel) -> Self {
+        self.load_model = Some(load_model);
+        self
+    }
+
+    /// Serves every request at once on the cores, each request gets a core
+    /// until there are more requests than cores, then they share them
+    /// equally. Service time is sampled when a request arrives, it is
+    /// the time it takes on a core of its own. With `n` requests each one
+    /// runs at `min(cores / n, 1)` divided by the load model factor for `n`.
+    /// Nothing is queued, the max queue of the error model limits the
+    /// requests in the server and its timeout how long they stay.
+    pub fn with_processor_sharing(mut self, cores: usize) -> Self {
+        assert!(cores > 0);
+        self.sharing = Some(Sharing {
+            cores,
+            requests: BinaryHeap::new(),
+            load_factor: 1.0,
+            work_ns: 0.0,
+            updated_t: 0,
+        });
+        self
+    }
+
+    /// Marks the server as started now, e.g. when it is added while the
+    /// simulation runs, its warm-up starts again.
+    pub fn start(&mut self, t: i64) {
+        self.started_t = t;
+        self.started = 0;
+        self.warming = self.warm_up;
+    }
+
+    /// Service time multiplier of the warm-up at the time, 1 once warm.
+    pub fn warm_up_factor(&self, t: i64) -> f64 {
+        let (factor, progress) = match self.warming {
+            None => return 1.0,
+            Some(WarmUp::Requests { factor, requests }) => {
+                (factor, self.started as f64 / requests.max(1) as f64)
+            }
+            Some(WarmUp::Time {
+                factor,
+                duration_ns,
+            }) => (
+                factor,
+                (t - self.started_t) as f64 / duration_ns.max(1) as f64,
+            ),
+        };
+        factor + (1.0 - factor) * progress.clamp(0.0, 1.0)
+    }
+
+    pub fn with_error_model(mut self, error_model: ErrorModel) -> Self {
+        self.error_model = Some(error_model);
+        self
+    }
+
+    /// Service time of a batch is multiplied by `batch_size ^ exponent`,
+    /// 0 serves a batch as fast as a single request, 1 scales linearly.
+    pub fn with_batch_scaling(mut self, exponent: f64) -> Self {
+        self.batch_exponent = exponent;
+        self
+    }
+
+    /// Totals up to `t`, counting the service requests in service got so far.
+    pub fn load(&self, t: i64) -> ServerLoad {
+        let serving_ns = match (&self.in_service, &self.sharing) {
+            (Some(in_service), _) => in_service.served_ns(t),
+            (None, Some(sharing)) => sharing.busy_ns(t),
+            (None, None) => 0,
+        };
+        ServerLoad {
+            busy_ns: self.busy_ns + serving_ns,
+            completed: self.completed,
+            latency_ns: self.latency_ns,
+            queue: self.queue_size(),
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.crashed.is_none() && !self.partitioned
+    }
+
+    /// Samples the service time of a request starting now, scaled by the
+    /// load model factor.
+    fn service_ns(&mut self, request: &Request, cur_t: i64, load_factor: f64) -> i64 {
+        let mut factor = self.slowdown * self.warm_up_factor(cur_t) * load_factor;
+        self.started += 1;
+        factor *= (request.batch_size as f64).powf(self.batch_exponent);
+        let sampled = self.distribution.sample(&mut rand::thread_rng()) as f64;
+        let service_ns = (sampled * factor) as i64;
+        self.meter.inc(service_ns);
+        service_ns
+    }
+
+    /// Starts serving the request alongside the others, moves the next completion.
+    fn share(&mut self, queued: Queued, scheduler: &mut Scheduler) {
+        let cur_t = scheduler.get_cur_t();
+        let service_ns = self.service_ns(&queued.request, cur_t, 1.0);
+        let sharing = self.sharing.as_mut().unwrap();
+        self.busy_ns += sharing.advance(cur_t);
+        sharing.push(service_ns, queued);
+        sharing.set_load(self.load_model.as_ref());
+        self.reschedule_shared(scheduler);
+    }
+
+    /// Completes the requests done by now and times out the ones in the
+    /// server for too long, moves the next completion.
+    fn complete_shared(&mut self, scheduler: &mut Scheduler) -> Vec<Queued> {
+        let cur_t = scheduler.get_cur_t();
+        let timeout_ns = self.timeout_ns();
+        let sharing = self.sharing.as_mut().unwrap();
+        self.busy_ns += sharing.advance(cur_t);
+        let mut done = Vec::new();
+        while let Some(queued) = sharing.pop_done() {
+            done.push(queued);
+        }
+        let timed_out = timeout_ns.map_or(Vec::new(), |timeout_ns| {
+            sharing.take_arrived_before(cur_t - timeout_ns)
+        });
+        sharing.set_load(self.load_model.as_ref());
+        self.reschedule_shared(scheduler);
+        for queued in timed_out {
+            self.answer(queued.request, Status::Timeout, scheduler);
+        }
+        done
+    }
+
+    /// Wakes up at the next completion or time out of a shared request.
+    fn reschedule_shared(&self, scheduler: &mut Scheduler) {
+        let sharing = self.sharing.as_ref().unwrap();
+        let timeout_t = self
+            .timeout_ns()
+            .and_then(|timeout_ns| sharing.next_timeout_t(timeout_ns));
+        let next_t = match (sharing.next_t(), timeout_t) {
+            (Some(t), Some(timeout_t)) => Some(t.min(timeout_t)),
+            (t, None) => t,
+            (None, timeout_t) => timeout_t,
+        };
+        if let Some(t) = next_t {
+            scheduler.reschedule(t, self.getref().unwrap());
+        }
+    }
+
+    fn timeout_ns(&self) -> Option<i64> {
+        self.error_model.as_ref().and_then(|m| m.timeout_ns)
+    }
+
+    /// Records the served request and responds.
+    fn respond(
+        &mut self,
+        queued: Queued,
+        fails: bool,
+        world: &mut World,
+        scheduler: &mut Scheduler,
+    ) {
+        let latency = scheduler.get_cur_t() - queued.arrival_t;
+        self.completed += 1;
+        self.latency_ns += latency;
+        self.latency.update(latency);
+        let mut request = queued.request;
+        if fails {
+            self.errors.inc();
+            request.status = Status::Error;
+        }
+        if self.partitioned {
+            self.lose(request, false, scheduler);
         } else {
-            let top = self.queue.back();
-            let nt = top.unwrap() + next_time;
-            self.queue.push_back(nt);
+            let sink = request.respond_to(self.sink);
+            world.with_system(sink, |system, world| system.next(request, world, scheduler));
+        }
+    }
+
+    /// Starts serving the next queued request, returns when it completes.
+    fn start_next(&mut self, scheduler: &mut Scheduler) -> Option<i64> {
+        let cur_t = scheduler.get_cur_t();
+        let queued = loop {
+            let queued = self.queue.pop_front()?;
+            let timed_out = self
+                .error_model
+                .as_ref()
+                .is_some_and(|m| m.timed_out(queued.arrival_t, cur_t));
+            if !timed_out {
+                break queued;
+            }
+            self.answer(queued.request, Status::Timeout, scheduler);
+        };
+        let load_factor = self
+            .load_model
+            .as_ref()
+            .map_or(1.0, |m| m.factor(1, self.queue.len()));
+        let service_ns = self.service_ns(&queued.request, cur_t, load_factor);
+        let t = cur_t + service_ns;
+        self.in_service = Some(InService {
+            t,
+            service_ns,
+            queued,
+        });
+        Some(t)
+    }
+
+    /// Responds with the status without serving the request.
+    fn answer(&mut self, mut request: Request, status: Status, scheduler: &mut Scheduler) {
+        self.errors.inc();
+        request.status = status;
+        let sink = request.respond_to(self.sink);
+        scheduler.deliver_at(scheduler.get_cur_t(), sink, request);
+    }
+
+    /// Drops the request, or fails it back to the sender when `fail` is set.
+    fn lose(&mut self, request: Request, fail: bool, scheduler: &mut Scheduler) {
+        self.lost.inc();
+        if fail {
+            self.answer(request, Status::Error, scheduler);
         }
-        self.meter.inc(next_time);
+    }
+
+    /// Whether the request served now fails, with the requests still waiting
+    /// or sharing the server counting as queued.
+    fn fails(&self) -> bool {
+        let queued = match &self.sharing {
+            Some(sharing) => sharing.requests.len(),
+            None => self.queue.len(),
+        };
+        self.error_model
+            .as_ref()
+            .is_some_and(|model| model.fails(queued))
+    }
+
+    pub fn inject(&mut self, fault: Fault, scheduler: &mut Scheduler) {
+        match fault {
+            Fault::Crash { fail } => {
+                let cur_t = scheduler.get_cur_t();
+                self.crashed = Some(fault);
+                let in_service = self.in_service.take().map(|s| {
+                    self.busy_ns += s.served_ns(cur_t);
+                    s.queued
+                });
+                let shared = match self.sharing.as_mut() {
+                    Some(sharing) => {
+                        self.busy_ns += sharing.advance(cur_t);
+                        let shared = sharing.drain();
+                        scheduler.cancel(self.getref().unwrap());
+                        shared
+                    }
+                    None => Vec::new(),
+                };
+                let queued: Vec<Queued> = in_service
+                    .into_iter()
+                    .chain(shared)
+                    .chain(self.queue.drain(..))
+                    .collect();
+                for queued in queued {
+                    self.lose(queued.request, fail, scheduler);
+                }
+            }
+            Fault::Brownout(factor) => self.slowdown = factor,
+            Fault::Partition => self.partitioned = true,
+            Fault::Heal => self.partitioned = false,
+            Fault::Restart { cold_ns, factor } => {
+                self.crashed = None;
+                self.slowdown = 1.0;
+                self.start(scheduler.get_cur_t());
+                if cold_ns > 0 {
+                    self.warming = Some(WarmUp::Time {
+                        factor,
+                        duration_ns: cold_ns,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Sink for Server {
+    fn next(&mut self, request: Request, _world: &mut World, scheduler: &mut Scheduler) {
         self.counter.inc();
+        if let Some(Fault::Crash { fail }) = self.crashed {
+            self.lose(request, fail, scheduler);
+            return;
+        }
+        if self.partitioned {
+            self.lose(request, false, scheduler);
+            return;
+        }
+        let queued = match &self.sharing {
+            Some(sharing) => sharing.requests.len(),
+            None => self.queue.len(),
+        };
+        if self.error_model.as_ref().is_some_and(|m| m.rejects(queued)) {
+            self.answer(request, Status::Rejected, scheduler);
+            return;
+        }
+        let queued = Queued {
+            arrival_t: scheduler.get_cur_t(),
+            request,
+        };
+        if self.sharing.is_some() {
+            self.share(queued, scheduler);
+            return;
+        }
+        self.queue.push_back(queued);
+        if self.in_service.is_none() {
+            let nt = self.start_next(scheduler).unwrap();
+            scheduler.schedule_at(nt, self.getref().unwrap());
+        }
     }
 }
 
@@ -186,7 +1178,7 @@ impl StatEmitter for Server {
         format!(
             "meter {} queue {} counter {}",
             self.meter.stats(),
-            tostring(self.queue.len()),
+            tostring(self.queue_size()),
             self.counter.stats()
         )
     }
@@ -194,13 +1186,21 @@ impl StatEmitter for Server {
 
 impl HasQueue for Server {
     fn queue_size(&self) -> i64 {
-        self.queue.len() as i64
+        let shared = self.sharing.as_ref().map_or(0, |s| s.requests.len());
+        (self.queue.len() + self.in_service.iter().len() + shared) as i64
     }
 }
 
 impl WorldMember for Server {
     fn add(&mut self, system_ref: SystemRef, name: String) {
-        self.meter.name = Some(name + "_meter");
+        assert!(
+            self.sharing.is_some() || !matches!(self.load_model, Some(LoadModel::Usl { .. })),
+            "the USL needs processor sharing, a fifo server serves one request at a time"
+        );
+        self.meter.name = Some(name.clone() + "_meter");
+        self.latency.name = Some(name.clone() + "_latency");
+        self.lost.name = Some(name.clone() + "_lost");
+        self.errors.name = Some(name + "_errors");
         self.sr = Some(system_ref);
     }
 
@@ -209,22 +1209,52 @@ impl WorldMember for Server {
     }
 }
 
-impl<'a> Emmitter for Server {
+impl Emmitter for Server {
     fn tick(&mut self, world: &mut World, scheduler: &mut Scheduler) -> Option<i64> {
-        let _ob = self.queue.front().cloned();
-        self.queue.pop_front();
-        world.with_system(self.sink, |system, world| system.next(world, scheduler));
-        let _nb = self.queue.front();
-        self.queue.front().cloned()
+        let cur_t = scheduler.get_cur_t();
+        if self.sharing.is_some() {
+            for queued in self.complete_shared(scheduler) {
+                let fails = self.fails();
+                self.respond(queued, fails, world, scheduler);
+            }
+            return None;
+        }
+        if self.in_service.as_ref().is_none_or(|s| s.t > cur_t) {
+            // scheduled before a crash, the request is gone
+            return None;
+        }
+        let done = self.in_service.take().unwrap();
+        self.busy_ns += done.service_ns;
+        let fails = self.fails();
+        let nt = self.start_next(scheduler);
+        self.respond(done.queued, fails, world, scheduler);
+        nt
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum System {
     Unset,
     EndSink(EndSink),
     Server(Server),
     ArrivalSource(ArrivalSource),
     LoadBalancer(LoadBalancer),
+    Cache(Cache),
+    Database(Database),
+    RateLimiter(RateLimiter),
+    CircuitBreaker(CircuitBreaker),
+    Autoscaler(Autoscaler),
+    FailureInjector(FailureInjector),
+    Batcher(Batcher),
+    Broker(Broker),
+    Link(Link),
+    RegionRouter(RegionRouter),
+    QuorumStore(QuorumStore),
+    LeaderFollowerStore(LeaderFollowerStore),
+    ShardedService(ShardedService),
+    CallGraphService(CallGraphService),
+    Composite(Composite),
+    Deployment(Deployment),
 }
 
 impl System {
@@ -234,17 +1264,56 @@ impl System {
             System::Server(server) => server.tick(world, scheduler),
             System::ArrivalSource(ars) => ars.tick(world, scheduler),
             System::Unset => unimplemented!(),
-            System::LoadBalancer(_lb) => unimplemented!(),
+            System::LoadBalancer(lb) => lb.tick(world, scheduler),
+            System::Cache(cache) => cache.tick(world, scheduler),
+            System::Database(database) => database.tick(world, scheduler),
+            System::RateLimiter(_) => unimplemented!(),
+            System::CircuitBreaker(breaker) => breaker.tick(world, scheduler),
+            System::Autoscaler(autoscaler) => autoscaler.tick(world, scheduler),
+            System::FailureInjector(injector) => injector.tick(world, scheduler),
+            System::Batcher(batcher) => batcher.tick(world, scheduler),
+            System::Broker(broker) => broker.tick(world, scheduler),
+            System::Link(_) => unimplemented!(),
+            System::RegionRouter(_) => unimplemented!(),
+            System::QuorumStore(store) => store.tick(world, scheduler),
+            System::LeaderFollowerStore(_) => unimplemented!(),
+            System::ShardedService(service) => service.tick(world, scheduler),
+            System::CallGraphService(service) => service.tick(world, scheduler),
+            System::Composite(_) => unimplemented!(),
+            System::Deployment(deployment) => deployment.tick(world, scheduler),
         }
     }
 
-    pub fn next(&mut self, world: &mut World, scheduler: &mut Scheduler) {
+    pub fn next(&mut self, mut request: Request, world: &mut World, scheduler: &mut Scheduler) {
+        if let Some(system_ref) = self.getref() {
+            // requests from another zone or region arrive after the hop latency
+            if let Some(latency) = world.hop(system_ref, &mut request) {
+                scheduler.deliver_at(scheduler.get_cur_t() + latency, system_ref, request);
+                return;
+            }
+        }
         match self {
-            System::EndSink(es) => es.next(world, scheduler),
-            System::Server(sr) => sr.next(world, scheduler),
+            System::EndSink(es) => es.next(request, world, scheduler),
+            System::Server(sr) => sr.next(request, world, scheduler),
             System::ArrivalSource(_ars) => unimplemented!(),
             System::Unset => unimplemented!(),
-            System::LoadBalancer(lb) => lb.next(world, scheduler),
+            System::LoadBalancer(lb) => lb.next(request, world, scheduler),
+            System::Cache(cache) => cache.next(request, world, scheduler),
+            System::Database(database) => database.next(request, world, scheduler),
+            System::RateLimiter(limiter) => limiter.next(request, world, scheduler),
+            System::CircuitBreaker(breaker) => breaker.next(request, world, scheduler),
+            System::Autoscaler(_) => unimplemented!(),
+            System::FailureInjector(_) => unimplemented!(),
+            System::Batcher(batcher) => batcher.next(request, world, scheduler),
+            System::Broker(broker) => broker.next(request, world, scheduler),
+            System::Link(link) => link.next(request, world, scheduler),
+            System::RegionRouter(router) => router.next(request, world, scheduler),
+            System::QuorumStore(store) => store.next(request, world, scheduler),
+            System::LeaderFollowerStore(store) => store.next(request, world, scheduler),
+            System::ShardedService(service) => service.next(request, world, scheduler),
+            System::CallGraphService(service) => service.next(request, world, scheduler),
+            System::Composite(composite) => composite.next(request, world, scheduler),
+            System::Deployment(_) => unimplemented!(),
         }
     }
 
@@ -255,6 +1324,49 @@ impl System {
             System::Server(s) => s.queue_size(),
             System::ArrivalSource(_) => 0,
             System::LoadBalancer(_) => 0,
+            System::Cache(cache) => cache.queue_size(),
+            System::Database(database) => database.queue_size(),
+            System::RateLimiter(_) => 0,
+            System::CircuitBreaker(_) => 0,
+            System::Autoscaler(_) => 0,
+            System::FailureInjector(_) => 0,
+            System::Batcher(batcher) => batcher.queue_size(),
+            System::Broker(broker) => broker.queue_size(),
+            System::Link(_) => 0,
+            System::RegionRouter(_) => 0,
+            System::QuorumStore(store) => store.queue_size(),
+            System::LeaderFollowerStore(_) => 0,
+            System::ShardedService(service) => service.queue_size(),
+            System::CallGraphService(service) => service.queue_size(),
+            System::Composite(_) => 0,
+            System::Deployment(_) => 0,
+        }
+    }
+
+    /// Name of the kind of system, `Unset` while the system is being called.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            System::Unset => "Unset",
+            System::EndSink(_) => "EndSink",
+            System::Server(_) => "Server",
+            System::ArrivalSource(_) => "ArrivalSource",
+            System::LoadBalancer(_) => "LoadBalancer",
+            System::Cache(_) => "Cache",
+            System::Database(_) => "Database",
+            System::RateLimiter(_) => "RateLimiter",
+            System::CircuitBreaker(_) => "CircuitBreaker",
+            System::Autoscaler(_) => "Autoscaler",
+            System::FailureInjector(_) => "FailureInjector",
+            System::Batcher(_) => "Batcher",
+            System::Broker(_) => "Broker",
+            System::Link(_) => "Link",
+            System::RegionRouter(_) => "RegionRouter",
+            System::QuorumStore(_) => "QuorumStore",
+            System::LeaderFollowerStore(_) => "LeaderFollowerStore",
+            System::ShardedService(_) => "ShardedService",
+            System::CallGraphService(_) => "CallGraphService",
+            System::Composite(_) => "Composite",
+            System::Deployment(_) => "Deployment",
         }
     }
 }
@@ -267,6 +1379,22 @@ impl StatEmitter for System {
             System::ArrivalSource(asr) => asr.stats(),
             System::Unset => unimplemented!(),
             System::LoadBalancer(lb) => lb.stats(),
+            System::Cache(cache) => cache.stats(),
+            System::Database(database) => database.stats(),
+            System::RateLimiter(limiter) => limiter.stats(),
+            System::CircuitBreaker(breaker) => breaker.stats(),
+            System::Autoscaler(autoscaler) => autoscaler.stats(),
+            System::FailureInjector(injector) => injector.stats(),
+            System::Batcher(batcher) => batcher.stats(),
+            System::Broker(broker) => broker.stats(),
+            System::Link(link) => link.stats(),
+            System::RegionRouter(router) => router.stats(),
+            System::QuorumStore(store) => store.stats(),
+            System::LeaderFollowerStore(store) => store.stats(),
+            System::ShardedService(service) => service.stats(),
+            System::CallGraphService(service) => service.stats(),
+            System::Composite(composite) => composite.stats(),
+            System::Deployment(deployment) => deployment.stats(),
         }
     }
 }
@@ -279,16 +1407,400 @@ impl WorldMember for System {
             System::ArrivalSource(arrival_source) => arrival_source.add(system_ref, name),
             System::Unset => unimplemented!(),
             System::LoadBalancer(lb) => lb.add(system_ref, name),
+            System::Cache(cache) => cache.add(system_ref, name),
+            System::Database(database) => database.add(system_ref, name),
+            System::RateLimiter(limiter) => limiter.add(system_ref, name),
+            System::CircuitBreaker(breaker) => breaker.add(system_ref, name),
+            System::Autoscaler(autoscaler) => autoscaler.add(system_ref, name),
+            System::FailureInjector(injector) => injector.add(system_ref, name),
+            System::Batcher(batcher) => batcher.add(system_ref, name),
+            System::Broker(broker) => broker.add(system_ref, name),
+            System::Link(link) => link.add(system_ref, name),
+            System::RegionRouter(router) => router.add(system_ref, name),
+            System::QuorumStore(store) => store.add(system_ref, name),
+            System::LeaderFollowerStore(store) => store.add(system_ref, name),
+            System::ShardedService(service) => service.add(system_ref, name),
+            System::CallGraphService(service) => service.add(system_ref, name),
+            System::Composite(composite) => composite.add(system_ref, name),
+            System::Deployment(deployment) => deployment.add(system_ref, name),
         }
     }
 
     fn getref(&self) -> Option<SystemRef> {
         match self {
             System::EndSink(es) => es.getref(),
-            System::Server(_) => todo!(),
+            System::Server(sv) => sv.getref(),
             System::ArrivalSource(ars) => ars.getref(),
             System::Unset => unimplemented!(),
             System::LoadBalancer(lb) => lb.getref(),
+            System::Cache(cache) => cache.getref(),
+            System::Database(database) => database.getref(),
+            System::RateLimiter(limiter) => limiter.getref(),
+            System::CircuitBreaker(breaker) => breaker.getref(),
+            System::Autoscaler(autoscaler) => autoscaler.getref(),
+            System::FailureInjector(injector) => injector.getref(),
+            System::Batcher(batcher) => batcher.getref(),
+            System::Broker(broker) => broker.getref(),
+            System::Link(link) => link.getref(),
+            System::RegionRouter(router) => router.getref(),
+            System::QuorumStore(store) => store.getref(),
+            System::LeaderFollowerStore(store) => store.getref(),
+            System::ShardedService(service) => service.getref(),
+            System::CallGraphService(service) => service.getref(),
+            System::Composite(composite) => composite.getref(),
+            System::Deployment(deployment) => deployment.getref(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{count, end_sink, run, send_at};
+
+    /// A load balancer over a server and a server dropping every request.
+    fn load_balancer(
+        world: &mut World,
+        scheduler: &mut Scheduler,
+        configure: impl FnOnce(LoadBalancer, SystemRef) -> LoadBalancer,
+    ) -> (SystemRef, SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let servers: Vec<SystemRef> = (0..2)
+            .map(|i| {
+                let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink);
+                world.add(System::Server(server), format!("server{}", i))
+            })
+            .collect();
+        world.with_system(servers[1], |system, _world| match system {
+            System::Server(server) => server.inject(Fault::Crash { fail: false }, scheduler),
+            _ => unreachable!(),
+        });
+        let lb = configure(LoadBalancer::new(servers.clone()), end_sink);
+        let lb = world.add(System::LoadBalancer(lb), "lb".to_string());
+        (lb, servers[1], end_sink)
+    }
+
+    fn with_lb<R>(world: &mut World, lb: SystemRef, f: impl FnOnce(&LoadBalancer) -> R) -> R {
+        world.with_system(lb, |system, _world| match system {
+            System::LoadBalancer(lb) => f(lb),
+            _ => unreachable!(),
+        })
+    }
+
+    #[test]
+    fn outlier_detection_times_out_dropped_requests() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (lb, dropping, end_sink) = load_balancer(&mut world, &mut scheduler, |lb, sink| {
+            let outlier_detection = OutlierDetection {
+                consecutive_errors: 2,
+                max_latency_ns: None,
+                ejection_ns: 100_000_000,
+                timeout_ns: 100_000,
+            };
+            lb.with_outlier_detection(outlier_detection, sink)
+        });
+        for i in 0..40 {
+            send_at(&mut scheduler, i * 10_000 + 1, lb, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        let ok = count(&mut world, end_sink, Status::Ok);
+        let timed_out = count(&mut world, end_sink, Status::Timeout);
+        assert_eq!(ok + timed_out, 40);
+        // every other request went to the dropping server until it was ejected
+        assert!((2..10).contains(&timed_out), "timed out {}", timed_out);
+        with_lb(&mut world, lb, |lb| {
+            assert!(lb.is_ejected(dropping));
+            assert!(lb.in_flight.is_empty());
+            // the dropping server never answers
+            assert_eq!(lb.late.len(), timed_out as usize);
+        });
+    }
+
+    #[test]
+    fn responses_long_after_the_timeout_are_dropped() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        // takes ten timeouts per request
+        let server = Server::new(Poisson::new(1_000_000.0).unwrap(), end_sink);
+        let server = world.add(System::Server(server), "server".to_string());
+        let outlier_detection = OutlierDetection {
+            consecutive_errors: 100,
+            max_latency_ns: None,
+            ejection_ns: 100_000_000,
+            timeout_ns: 100_000,
+        };
+        let lb =
+            LoadBalancer::new(vec![server]).with_outlier_detection(outlier_detection, end_sink);
+        let lb = world.add(System::LoadBalancer(lb), "lb".to_string());
+        for t in 0..3 {
+            send_at(&mut scheduler, t, lb, 0, None);
+        }
+        run(&mut world, &mut scheduler, 100_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Timeout), 3);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 0);
+        with_lb(&mut world, lb, |lb| {
+            assert_eq!(lb.counter.value(), 3);
+            assert!(lb.late.is_empty());
+        });
+    }
+
+    #[test]
+    fn health_check_ejects_a_crashed_server() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let (lb, crashed, end_sink) = load_balancer(&mut world, &mut scheduler, |lb, _| {
+            lb.with_health_check(HealthCheck {
+                interval_ns: 1_000_000,
+                unhealthy_threshold: 1,
+                healthy_threshold: 1,
+            })
+        });
+        scheduler.schedule(&mut world, lb);
+        for i in 0..10 {
+            send_at(&mut scheduler, i * 10_000 + 1, lb, 0, None);
+        }
+        run(&mut world, &mut scheduler, 500_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 10);
+        assert!(with_lb(&mut world, lb, |lb| lb.is_ejected(crashed)));
+    }
+
+    #[test]
+    fn server_error_model() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let error_model = ErrorModel {
+            probability: 1.0,
+            timeout_ns: Some(1_500_000),
+            max_queue: Some(2),
+            ..ErrorModel::default()
+        };
+        let server =
+            Server::new(Poisson::new(1_000_000.0).unwrap(), end_sink).with_error_model(error_model);
+        let server = world.add(System::Server(server), "server".to_string());
+        // fails, fails after 1ms, times out after 2ms, rejected
+        for _ in 0..4 {
+            send_at(&mut scheduler, 0, server, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Error), 2);
+        assert_eq!(count(&mut world, end_sink, Status::Timeout), 1);
+        assert_eq!(count(&mut world, end_sink, Status::Rejected), 1);
+    }
+
+    #[test]
+    fn usl_counts_requests_in_service() {
+        let usl = LoadModel::Usl {
+            contention: 0.1,
+            coherency: 0.01,
+        };
+        assert_eq!(usl.factor(1, 0), 1.0);
+        // waiting requests do not slow down the one served
+        assert_eq!(usl.factor(1, 10), 1.0);
+        let expected = 1.0 + 0.1 * 3.0 + 0.01 * 4.0 * 3.0;
+        assert!((usl.factor(4, 0) - expected).abs() < 1e-9);
+        let function = LoadModel::function(|in_service, queued| (in_service + queued) as f64);
+        assert_eq!(function.factor(1, 2), 3.0);
+    }
+
+    fn sharing(cores: usize) -> Sharing {
+        Sharing {
+            cores,
+            requests: BinaryHeap::new(),
+            load_factor: 1.0,
+            work_ns: 0.0,
+            updated_t: 0,
+        }
+    }
+
+    fn queued(id: u64) -> Queued {
+        Queued {
+            arrival_t: 0,
+            request: Request::new(id, 0),
+        }
+    }
+
+    #[test]
+    fn processor_sharing_completes_at_the_analytic_times() {
+        let mut sharing = sharing(2);
+        for (id, service_ns) in [100, 200, 300, 400].into_iter().enumerate() {
+            sharing.push(service_ns, queued(id as u64));
+        }
+        // four requests share two cores at half speed until the first one
+        // is done, three at two thirds, then the last two get a core each
+        let mut completions = Vec::new();
+        while let Some(t) = sharing.next_t() {
+            sharing.advance(t);
+            while let Some(queued) = sharing.pop_done() {
+                completions.push((queued.request.id, t));
+            }
+        }
+        let expected = [(0, 200), (1, 350), (2, 450), (3, 550)];
+        assert_eq!(completions.len(), expected.len());
+        for ((id, t), (expected_id, expected_t)) in completions.into_iter().zip(expected) {
+            assert_eq!(id, expected_id);
+            // completion times are rounded up to whole ns
+            assert!((t - expected_t).abs() <= 1, "{} completed at {}", id, t);
+        }
+        assert_eq!(sharing.work_ns, 0.0);
+    }
+
+    #[test]
+    fn arrival_moves_the_pending_completion() {
+        let mut sharing = sharing(1);
+        sharing.push(100, queued(0));
+        assert_eq!(sharing.next_t(), Some(100));
+        sharing.advance(50);
+        sharing.push(100, queued(1));
+        // the rest of the first request goes at half speed
+        assert_eq!(sharing.next_t(), Some(150));
+    }
+
+    /// Processor sharing server on `cores` taking about 1ms per request.
+    fn sharing_server(
+        world: &mut World,
+        cores: usize,
+        error_model: ErrorModel,
+    ) -> (SystemRef, SystemRef) {
+        let end_sink = end_sink(world, "endsink");
+        let server = Server::new(Poisson::new(1_000_000.0).unwrap(), end_sink)
+            .with_processor_sharing(cores)
+            .with_error_model(error_model);
+        let server = world.add(System::Server(server), "server".to_string());
+        (server, end_sink)
+    }
+
+    #[test]
+    fn requests_sharing_the_server_count_as_queued() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let error_model = ErrorModel {
+            per_queued: 1.0,
+            ..ErrorModel::default()
+        };
+        let (server, end_sink) = sharing_server(&mut world, 2, error_model);
+        // the first to complete shares the server with the other one
+        send_at(&mut scheduler, 0, server, 0, None);
+        send_at(&mut scheduler, 0, server, 0, None);
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Error), 1);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 1);
+    }
+
+    #[test]
+    fn shared_requests_time_out() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let error_model = ErrorModel {
+            timeout_ns: Some(1_500_000),
+            ..ErrorModel::default()
+        };
+        let (server, end_sink) = sharing_server(&mut world, 1, error_model);
+        // two at half speed need 2ms, alone it takes 1ms
+        send_at(&mut scheduler, 0, server, 0, None);
+        send_at(&mut scheduler, 0, server, 0, None);
+        send_at(&mut scheduler, 5_000_000, server, 0, None);
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Timeout), 2);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 1);
+        let load = world.with_system(server, |system, _world| match system {
+            System::Server(server) => server.load(10_000_000),
+            _ => unreachable!(),
+        });
+        assert_eq!(load.completed, 1);
+        assert_eq!(load.queue, 0);
+    }
+
+    /// Completions per request service time of `n` requests kept in service
+    /// on `n` cores, a new one arriving whenever one completes.
+    fn closed_loop_throughput(usl: &LoadModel, n: usize) -> f64 {
+        let service_ns = |i: u64| 500 + (i * 337 % 1_000) as i64;
+        let mut sharing = sharing(n);
+        let mut sent = 0;
+        for _ in 0..n {
+            sharing.push(service_ns(sent), queued(sent));
+            sent += 1;
+        }
+        sharing.set_load(Some(usl));
+        let mut completed = 0;
+        let mut t = 0;
+        while t < 10_000_000 {
+            t = sharing.next_t().unwrap();
+            sharing.advance(t);
+            while sharing.pop_done().is_some() {
+                completed += 1;
+                sharing.push(service_ns(sent), queued(sent));
+                sent += 1;
+            }
+            sharing.set_load(Some(usl));
+        }
+        // service times average 1000ns
+        completed as f64 * 1_000.0 / t as f64
+    }
+
+    #[test]
+    fn usl_shapes_the_throughput_of_processor_sharing() {
+        let (contention, coherency) = (0.1, 0.01);
+        let usl = LoadModel::Usl {
+            contention,
+            coherency,
+        };
+        for n in [1, 2, 4, 8, 16, 32] {
+            let x = n as f64;
+            let expected = x / (1.0 + contention * (x - 1.0) + coherency * x * (x - 1.0));
+            let throughput = closed_loop_throughput(&usl, n);
+            assert!(
+                (throughput - expected).abs() < 0.01 * expected,
+                "{} requests: {} instead of {}",
+                n,
+                throughput,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn completions_speed_up_the_shared_requests() {
+        let mut world = World::new();
+        let mut scheduler = Scheduler::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let usl = LoadModel::Usl {
+            contention: 1.0,
+            coherency: 0.0,
+        };
+        let server = Server::new(Poisson::new(1_000_000.0).unwrap(), end_sink)
+            .with_processor_sharing(2)
+            .with_load_model(usl);
+        let server = world.add(System::Server(server), "server".to_string());
+        // 1ms requests at 0, 0.5ms and 1ms, two share the cores at half
+        // speed and three at two ninths until the first completes at
+        // 2.125ms, the second at 3.125ms and the third at 3.375ms
+        for t in [0, 500_000, 1_000_000] {
+            send_at(&mut scheduler, t, server, 0, None);
+        }
+        run(&mut world, &mut scheduler, 10_000_000);
+        assert_eq!(count(&mut world, end_sink, Status::Ok), 3);
+        let load = world.with_system(server, |system, _world| match system {
+            System::Server(server) => server.load(10_000_000),
+            _ => unreachable!(),
+        });
+        // 5ms in total with the factor fixed at arrival
+        let latency_ms = load.latency_ns as f64 / 1_000_000.0;
+        assert!((latency_ms - 7.125).abs() < 0.05, "{}", latency_ms);
+    }
+
+    #[test]
+    #[should_panic(expected = "the USL needs processor sharing")]
+    fn usl_needs_processor_sharing() {
+        let mut world = World::new();
+        let end_sink = end_sink(&mut world, "endsink");
+        let usl = LoadModel::Usl {
+            contention: 0.1,
+            coherency: 0.0,
+        };
+        let server = Server::new(Poisson::new(1_000.0).unwrap(), end_sink).with_load_model(usl);
+        world.add(System::Server(server), "server".to_string());
+    }
+}
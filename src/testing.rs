@@ -0,0 +1,36 @@
+use crate::objects::{Scheduler, Status, World};
+use crate::systems::{EndSink, System};
+use crate::traits::SystemRef;
+
+/// Adds an EndSink to count what leaves the system.
+pub fn end_sink(world: &mut World, name: &str) -> SystemRef {
+    world.add(System::EndSink(EndSink::new()), name.to_string())
+}
+
+/// Requests which reached the EndSink with the status.
+pub fn count(world: &mut World, end_sink: SystemRef, status: Status) -> i64 {
+    world.with_system(end_sink, |system, _world| match system {
+        System::EndSink(end_sink) => end_sink.count(status),
+        _ => panic!("not an end sink"),
+    })
+}
+
+/// Sends a request of the kind and key to the system at time `t`.
+pub fn send_at(
+    scheduler: &mut Scheduler,
+    t: i64,
+    system: SystemRef,
+    kind: usize,
+    key: Option<u64>,
+) {
+    let mut request = scheduler.new_request();
+    request.created_t = t;
+    request.kind = kind;
+    request.key = key;
+    scheduler.deliver_at(t, system, request);
+}
+
+/// Runs the simulation until there is nothing left to do or `up_to_ns`.
+pub fn run(world: &mut World, scheduler: &mut Scheduler, up_to_ns: i64) {
+    while scheduler.execute_next(world, up_to_ns) {}
+}
@@ -1,5 +1,45 @@
-pub type SystemRef = usize;
+use std::fmt;
 
+/// Reference to a system in the world. A system added in the place of a
+/// removed one gets the next generation of the place, so references to the
+/// removed system are told apart from it, see `World::remove`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SystemRef {
+    index: usize,
+    generation: u32,
+}
+
+impl SystemRef {
+    pub(crate) fn new(index: usize, generation: u32) -> Self {
+        SystemRef { index, generation }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl fmt::Display for SystemRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.generation == 0 {
+            write!(f, "{}", self.index)
+        } else {
+            write!(f, "{}g{}", self.index, self.generation)
+        }
+    }
+}
+
+impl fmt::Debug for SystemRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+use crate::objects::Request;
 use crate::objects::Scheduler;
 use crate::objects::World;
 
@@ -13,7 +53,7 @@ pub trait Emmitter {
 }
 
 pub trait Sink {
-    fn next(&mut self, world: &mut World, scheduler: &mut Scheduler);
+    fn next(&mut self, request: Request, world: &mut World, scheduler: &mut Scheduler);
 }
 
 pub trait StatEmitter {
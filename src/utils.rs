@@ -4,10 +4,11 @@ pub fn tostring<T: ToFormattedString>(arg: T) -> String {
 }
 
 pub fn tostringfloat(arg: f64) -> String {
+    let arg = (arg * 1000.0).round() / 1000.0;
     let f = arg.floor();
     let rest = arg - f;
     let rst = format!("{:.3}", rest);
-    format!("{}{}", tostring(f as i64), rst[1..].to_string())
+    format!("{}{}", tostring(f as i64), &rst[1..])
 }
 
 use metriki_core::global::global_registry;
@@ -18,6 +19,12 @@ pub struct Meter {
     pub name: Option<String>,
 }
 
+impl Default for Meter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Meter {
     pub fn new() -> Self {
         Meter {
@@ -30,9 +37,50 @@ impl Meter {
     pub fn inc(&mut self, d: i64) {
         self.co += 1;
         self.sm += d;
-        self.name
-            .as_ref()
-            .map(|name| global_registry().meter(name.as_str()).mark());
+        if let Some(name) = self.name.as_ref() {
+            global_registry().meter(name.as_str()).mark();
+        }
+    }
+
+    pub fn stats(&self) -> String {
+        if self.co == 0 {
+            "0".to_string()
+        } else {
+            tostringfloat(self.sm as f64 / self.co as f64)
+        }
+    }
+}
+
+/// Histogram keeps the average like Meter but reports the distribution.
+pub struct Histogram {
+    sm: i64,
+    co: i64,
+    pub name: Option<String>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            sm: 0,
+            co: 0,
+            name: None,
+        }
+    }
+
+    pub fn update(&mut self, v: i64) {
+        self.co += 1;
+        self.sm += v;
+        if let Some(name) = self.name.as_ref() {
+            global_registry()
+                .histogram(name.as_str())
+                .update(v.max(0) as u64);
+        }
     }
 
     pub fn stats(&self) -> String {
@@ -49,6 +97,12 @@ pub struct Counter {
     pub name: Option<String>,
 }
 
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Counter {
     pub fn new() -> Self {
         Counter { co: 0, name: None }
@@ -56,12 +110,231 @@ impl Counter {
 
     pub fn inc(&mut self) {
         self.co += 1;
-        self.name
-            .as_ref()
-            .map(|name| global_registry().counter(name.as_str()).inc(1));
+        if let Some(name) = self.name.as_ref() {
+            global_registry().counter(name.as_str()).inc(1);
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.co
     }
 
     pub fn stats(&self) -> String {
         tostring(self.co)
     }
 }
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Gauge holds the last set value, it is registered in the metrics registry
+/// on the first set after the name is known.
+pub struct Gauge {
+    value: Arc<AtomicU64>,
+    registered: bool,
+    pub name: Option<String>,
+}
+
+impl Default for Gauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gauge {
+    pub fn new() -> Self {
+        Gauge {
+            value: Arc::new(AtomicU64::new(0f64.to_bits())),
+            registered: false,
+            name: None,
+        }
+    }
+
+    pub fn set(&mut self, v: f64) {
+        self.value.store(v.to_bits(), Ordering::Relaxed);
+        if !self.registered {
+            if let Some(name) = self.name.as_ref() {
+                let value = self.value.clone();
+                global_registry().gauge(
+                    name.as_str(),
+                    Box::new(move || f64::from_bits(value.load(Ordering::Relaxed))),
+                );
+                self.registered = true;
+            }
+        }
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.value.load(Ordering::Relaxed))
+    }
+
+    pub fn stats(&self) -> String {
+        tostringfloat(self.get())
+    }
+}
+
+struct Delayed<T> {
+    t: i64,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Delayed<T> {
+    fn eq(&self, o: &Self) -> bool {
+        self.t == o.t && self.seq == o.seq
+    }
+}
+impl<T> Eq for Delayed<T> {}
+impl<T> PartialOrd for Delayed<T> {
+    fn partial_cmp(&self, o: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(o))
+    }
+}
+impl<T> Ord for Delayed<T> {
+    fn cmp(&self, o: &Self) -> std::cmp::Ordering {
+        (self.t, self.seq).cmp(&(o.t, o.seq)).reverse()
+    }
+}
+
+use std::collections::BinaryHeap;
+
+/// DelayQueue holds items until their time comes, items due at the same time
+/// come out in the order they were pushed.
+pub struct DelayQueue<T> {
+    heap: BinaryHeap<Delayed<T>>,
+    seq: u64,
+}
+
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> DelayQueue<T> {
+    pub fn new() -> Self {
+        DelayQueue {
+            heap: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    pub fn push(&mut self, t: i64, item: T) {
+        self.seq += 1;
+        self.heap.push(Delayed {
+            t,
+            seq: self.seq,
+            item,
+        });
+    }
+
+    /// Pops the earliest item if it is due at `t` or earlier.
+    pub fn pop_due(&mut self, t: i64) -> Option<T> {
+        if self.heap.peek().is_some_and(|top| top.t <= t) {
+            self.heap.pop().map(|top| top.item)
+        } else {
+            None
+        }
+    }
+
+    pub fn peek_t(&self) -> Option<i64> {
+        self.heap.peek().map(|top| top.t)
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+use std::collections::{HashSet, VecDeque};
+
+/// Late responses are remembered for at most this many requests.
+const LATE_IDS: usize = 100_000;
+
+/// LateIds holds ids of requests given up on, so their responses are not
+/// taken for new requests when they come back. An id is kept until its
+/// response arrives, when too many responses never come the oldest ids are
+/// forgotten first.
+pub struct LateIds {
+    ids: HashSet<u64>,
+    // insertion order, may still hold ids already removed from the set
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl Default for LateIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LateIds {
+    pub fn new() -> Self {
+        Self::with_capacity(LATE_IDS)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        LateIds {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, id: u64) {
+        if !self.ids.insert(id) {
+            return;
+        }
+        self.order.push_back(id);
+        while self.ids.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.ids.remove(&oldest);
+        }
+        if self.order.len() > 2 * self.capacity {
+            let ids = &self.ids;
+            self.order.retain(|id| ids.contains(id));
+        }
+    }
+
+    /// Removes the id, true if it was late.
+    pub fn remove(&mut self, id: u64) -> bool {
+        self.ids.remove(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_ids_forget_the_oldest_beyond_capacity() {
+        let mut late = LateIds::with_capacity(3);
+        for id in 0..3 {
+            late.insert(id);
+        }
+        assert!(late.remove(1));
+        assert!(!late.remove(1));
+        for id in 3..1_000 {
+            late.insert(id);
+            assert!(late.len() <= 3);
+            assert!(late.order.len() <= 6);
+        }
+        assert!(!late.remove(0));
+        assert!(!late.remove(996));
+        assert!(late.remove(997));
+        assert!(late.remove(999));
+    }
+}